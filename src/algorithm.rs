@@ -1 +1,1039 @@
+use log::{debug, info, warn};
+use nalgebra::{
+    core::{DMatrix, DVector},
+    linalg::{SymmetricEigen, SVD},
+    Dynamic,
+};
+use rand::{distributions::WeightedIndex, prelude::*, rngs::StdRng};
+use rayon::prelude::*;
 
+use std::{fmt, ops::Sub, str::FromStr};
+
+use crate::data::SparseMatrix;
+
+/// A symmetric n x n matrix that we only ever multiply vectors with.
+pub trait SymmetricOperator {
+    fn dim(&self) -> usize;
+    /// $`y = A x`$
+    fn apply(&self, x: &DVector<f64>) -> DVector<f64>;
+}
+
+impl SymmetricOperator for SparseMatrix {
+    fn dim(&self) -> usize {
+        self.nrows
+    }
+    fn apply(&self, x: &DVector<f64>) -> DVector<f64> {
+        self.mul_vec(x)
+    }
+}
+
+impl SymmetricOperator for DMatrix<f64> {
+    fn dim(&self) -> usize {
+        self.nrows()
+    }
+    fn apply(&self, x: &DVector<f64>) -> DVector<f64> {
+        self * x
+    }
+}
+
+/// Which end of the spectrum we are interested in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Which {
+    #[allow(dead_code)]
+    Largest,
+    Smallest,
+}
+
+/// Thick restart Lanczos, finds `k` extreme eigenpairs of a symmetric
+/// operator without ever forming it densely.
+///
+/// A Krylov basis $`V`$ of at most `max_dim` vectors is grown with full
+/// reorthogonalization, the Ritz pairs of $`V^T A V`$ are computed,
+/// and if the wanted ones have not converged the basis is shrunk back to
+/// the best Ritz vectors plus the residual and grown again.
+#[derive(Debug, Clone)]
+pub struct Lanczos {
+    pub k: usize,
+    pub which: Which,
+    /// Largest size of the Krylov basis, $`2k + 20`$ by default.
+    pub max_dim: usize,
+    pub max_restarts: usize,
+    /// A Ritz pair is converged if its residual is below `tol` $`\cdot |\theta|`$.
+    pub tol: f64,
+}
+
+impl Lanczos {
+    pub fn new(k: usize, which: Which) -> Self {
+        Self {
+            k,
+            which,
+            max_dim: 2 * k + 20,
+            max_restarts: 200,
+            tol: 1e-8,
+        }
+    }
+
+    /// Eigen pairs ordered from the most wanted, i.e. largest first for
+    /// `Which::Largest`. Eigen vectors are the columns of `eigenvectors`.
+    pub fn solve(&self, op: &dyn SymmetricOperator) -> SymmetricEigen<f64, Dynamic> {
+        let n = op.dim();
+        let k = usize::min(self.k, n);
+        if n == 0 {
+            return SymmetricEigen {
+                eigenvalues: DVector::zeros(0),
+                eigenvectors: DMatrix::zeros(0, 0),
+            };
+        }
+        let max_dim = usize::min(usize::max(self.max_dim, k + 1), n);
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+
+        // Basis vectors are columns of `basis`, `h` is the projection V^T A V.
+        let mut basis = DMatrix::<f64>::zeros(n, max_dim);
+        let mut h = DMatrix::<f64>::zeros(max_dim, max_dim);
+        let mut len = 1;
+        let start = random_unit_vector(n, &mut seed);
+        basis.set_column(0, &start);
+
+        let mut restart = 0;
+        loop {
+            // Grow the basis, the residual of the last vector ends up in `w`.
+            let mut w;
+            loop {
+                let j = len - 1;
+                w = op.apply(&basis.column(j).into_owned());
+                // Gram-Schmidt twice is enough to keep the basis orthogonal.
+                for pass in 0..2 {
+                    for i in 0..len {
+                        let c = basis.column(i).dot(&w);
+                        w.axpy(-c, &basis.column(i), 1f64);
+                        if pass == 0 {
+                            h[(i, j)] = c;
+                        } else {
+                            h[(i, j)] += c;
+                        }
+                        h[(j, i)] = h[(i, j)];
+                    }
+                }
+                let beta = w.norm();
+                if len == max_dim {
+                    break;
+                }
+                if beta > 1e-12 {
+                    basis.set_column(len, &(w.clone() / beta));
+                    h[(len, j)] = beta;
+                    h[(j, len)] = beta;
+                } else {
+                    // Found an invariant subspace, carry on with any vector
+                    // orthogonal to it.
+                    let mut v = random_unit_vector(n, &mut seed);
+                    for _ in 0..2 {
+                        for i in 0..len {
+                            let c = basis.column(i).dot(&v);
+                            v.axpy(-c, &basis.column(i), 1f64);
+                        }
+                    }
+                    v.normalize_mut();
+                    basis.set_column(len, &v);
+                }
+                len += 1;
+            }
+            let beta = w.norm();
+
+            let ritz = SymmetricEigen::new(h.slice((0, 0), (len, len)).into_owned());
+            let order = self.order(&ritz.eigenvalues);
+            let residual = |i: usize| (beta * ritz.eigenvectors[(len - 1, i)]).abs();
+            let converged = order
+                .iter()
+                .take(k)
+                .filter(|&&i| residual(i) <= self.tol * f64::max(ritz.eigenvalues[i].abs(), 1f64))
+                .count();
+            debug!(
+                "Lanczos restart {}: {} of {} eigen pairs converged",
+                restart, converged, k
+            );
+
+            if converged == k || len == n || restart == self.max_restarts {
+                if converged < k && len < n {
+                    warn!(
+                        "Lanczos stopped after {} restarts with {} of {} eigen pairs converged",
+                        restart, converged, k
+                    );
+                }
+                let wanted: Vec<usize> = order.into_iter().take(k).collect();
+                let basis = basis.columns(0, len);
+                let eigenvectors =
+                    DMatrix::from_fn(len, k, |r, c| ritz.eigenvectors[(r, wanted[c])]);
+                return SymmetricEigen {
+                    eigenvalues: DVector::from_fn(k, |i, _| ritz.eigenvalues[wanted[i]]),
+                    eigenvectors: basis * eigenvectors,
+                };
+            }
+
+            // Thick restart: keep the best Ritz vectors, which are
+            // diagonal in the projection, and couple them to the residual.
+            let keep = usize::min(k + (max_dim - k) / 2, max_dim - 1);
+            let kept: Vec<usize> = order.into_iter().take(keep).collect();
+            let ritz_vectors = basis.columns(0, len)
+                * DMatrix::from_fn(len, keep, |r, c| ritz.eigenvectors[(r, kept[c])]);
+            basis.columns_mut(0, keep).copy_from(&ritz_vectors);
+            basis.set_column(keep, &(w / beta));
+            h.fill(0f64);
+            for (c, &i) in kept.iter().enumerate() {
+                h[(c, c)] = ritz.eigenvalues[i];
+            }
+            len = keep + 1;
+            restart += 1;
+        }
+    }
+
+    /// Indices of the eigen values, most wanted first.
+    fn order(&self, eigenvalues: &DVector<f64>) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..eigenvalues.len()).collect();
+        order.sort_by(|&a, &b| {
+            let cmp = eigenvalues[a].partial_cmp(&eigenvalues[b]).unwrap();
+            match self.which {
+                Which::Largest => cmp.reverse(),
+                Which::Smallest => cmp,
+            }
+        });
+        order
+    }
+}
+
+/// A cheap xorshift so that the solver is deterministic.
+/// Entries are uniform in $`[-0.5, 0.5)`$.
+fn random_vector(n: usize, seed: &mut u64) -> DVector<f64> {
+    DVector::from_fn(n, |_, _| {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        (*seed >> 11) as f64 / (1u64 << 53) as f64 - 0.5f64
+    })
+}
+
+fn random_unit_vector(n: usize, seed: &mut u64) -> DVector<f64> {
+    let mut v = random_vector(n, seed);
+    v.normalize_mut();
+    v
+}
+
+/// K-means clustering of the rows of a matrix, e.g. a spectral embedding
+/// or learned customer/movie factors.
+///
+/// Centroids are seeded by k-means++, refined by Lloyd iterations, and the
+/// whole thing is restarted `num_restarts` times (in parallel) keeping the
+/// run with the lowest inertia,
+/// ```math
+/// \sum_i ||x_i - \mu_{c(i)}||^2
+/// ```
+#[derive(Debug, Clone)]
+pub struct KMeans {
+    pub k: usize,
+    pub max_iter: usize,
+    pub num_restarts: usize,
+    /// Stop once no centroid moves more than `tol`.
+    pub tol: f64,
+    pub seed: u64,
+}
+
+/// Result of `KMeans::fit`.
+#[derive(Debug, Clone)]
+pub struct Clustering {
+    /// k x d, one centroid per row.
+    #[allow(dead_code)]
+    pub centroids: DMatrix<f64>,
+    /// Cluster of every point, empty if there is no point or `k` is 0.
+    pub labels: Vec<usize>,
+    pub inertia: f64,
+    pub num_iter: usize,
+}
+
+impl KMeans {
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            max_iter: 300,
+            num_restarts: 10,
+            tol: 1e-6,
+            seed: 271,
+        }
+    }
+
+    /// Cluster the rows of the n x d matrix `points`.
+    pub fn fit(&self, points: &DMatrix<f64>) -> Clustering {
+        let k = usize::min(self.k, points.nrows());
+        if k == 0 {
+            warn!("KMeans has no point to cluster or no cluster to make");
+            return Clustering {
+                centroids: DMatrix::zeros(0, points.ncols()),
+                labels: vec![],
+                inertia: 0f64,
+                num_iter: 0,
+            };
+        }
+        // Points as columns, so each one is contiguous.
+        let points = points.transpose();
+        let best = (0..self.num_restarts.max(1))
+            .into_par_iter()
+            .map(|restart| {
+                let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(restart as u64));
+                let clustering = self.lloyd(&points, self.init_plus_plus(&points, k, &mut rng));
+                debug!(
+                    "KMeans restart {}: inertia {} after {} iterations",
+                    restart, clustering.inertia, clustering.num_iter
+                );
+                clustering
+            })
+            .min_by(|a, b| a.inertia.partial_cmp(&b.inertia).unwrap())
+            .unwrap();
+        info!("KMeans best inertia: {}", best.inertia);
+        best
+    }
+
+    /// k-means++: every next centroid is a point drawn with probability
+    /// proportional to its squared distance to the closest centroid so far.
+    fn init_plus_plus(&self, points: &DMatrix<f64>, k: usize, rng: &mut StdRng) -> DMatrix<f64> {
+        let n = points.ncols();
+        let mut centroids = DMatrix::zeros(points.nrows(), k);
+        centroids.set_column(0, &points.column(rng.gen_range(0, n)));
+        let mut dist: Vec<f64> = (0..n)
+            .map(|i| (points.column(i) - centroids.column(0)).norm_squared())
+            .collect();
+        for c in 1..k {
+            let next = match WeightedIndex::new(&dist) {
+                Ok(weights) => weights.sample(rng),
+                // Every point sits on a centroid already.
+                Err(_) => rng.gen_range(0, n),
+            };
+            centroids.set_column(c, &points.column(next));
+            dist.iter_mut().enumerate().for_each(|(i, d)| {
+                *d = f64::min(*d, (points.column(i) - centroids.column(c)).norm_squared())
+            });
+        }
+        centroids
+    }
+
+    fn lloyd(&self, points: &DMatrix<f64>, mut centroids: DMatrix<f64>) -> Clustering {
+        let (d, n) = points.shape();
+        let k = centroids.ncols();
+        let mut num_iter = 0;
+        let (mut labels, mut dist) = assign(points, &centroids);
+        while num_iter < self.max_iter {
+            num_iter += 1;
+            let mut sums = DMatrix::<f64>::zeros(d, k);
+            let mut counts = vec![0usize; k];
+            for i in 0..n {
+                let mut sum = sums.column_mut(labels[i]);
+                sum += points.column(i);
+                counts[labels[i]] += 1;
+            }
+            let mut shift = 0f64;
+            for (c, &count) in counts.iter().enumerate() {
+                let centroid = if count > 0 {
+                    sums.column(c) / count as f64
+                } else {
+                    // An empty cluster takes over the point worst served.
+                    let far = (0..n)
+                        .max_by(|&a, &b| dist[a].partial_cmp(&dist[b]).unwrap())
+                        .unwrap();
+                    dist[far] = 0f64;
+                    points.column(far).into_owned()
+                };
+                shift = f64::max(shift, (&centroid - centroids.column(c)).norm());
+                centroids.set_column(c, &centroid);
+            }
+            let assigned = assign(points, &centroids);
+            labels = assigned.0;
+            dist = assigned.1;
+            if shift <= self.tol {
+                break;
+            }
+        }
+        Clustering {
+            centroids: centroids.transpose(),
+            labels,
+            inertia: dist.iter().sum(),
+            num_iter,
+        }
+    }
+}
+
+/// Closest centroid of every point and the squared distance to it.
+fn assign(points: &DMatrix<f64>, centroids: &DMatrix<f64>) -> (Vec<usize>, Vec<f64>) {
+    (0..points.ncols())
+        .into_par_iter()
+        .map(|i| {
+            let point = points.column(i);
+            (0..centroids.ncols())
+                .map(|c| (c, (point - centroids.column(c)).norm_squared()))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .unwrap()
+        })
+        .unzip()
+}
+
+/// Randomized truncated SVD of a sparse matrix,
+/// $`A \approx U \Sigma V^T`$ with only `rank` singular triplets.
+///
+/// A random range finder: $`Q`$ is an orthonormal basis of
+/// $`(A A^T)^q A \Omega`$ for a random $`\Omega`$ with
+/// `rank + oversampling` columns, then the small $`Q^T A`$ is decomposed
+/// densely. See Halko, Martinsson and Tropp, 2011. `power_iterations`
+/// ($`q`$) sharpens the result when singular values decay slowly, which
+/// is the case for ratings.
+#[derive(Debug, Clone)]
+pub struct RandomizedSvd {
+    pub rank: usize,
+    pub oversampling: usize,
+    pub power_iterations: usize,
+    pub seed: u64,
+}
+
+/// Result of `RandomizedSvd::decompose`, singular values are descending.
+#[derive(Debug, Clone)]
+pub struct TruncatedSvd {
+    /// n x rank
+    pub u: DMatrix<f64>,
+    pub singular_values: DVector<f64>,
+    /// m x rank
+    pub v: DMatrix<f64>,
+}
+
+impl TruncatedSvd {
+    /// $`(U \Sigma^{1/2}, V \Sigma^{1/2})`$, a good starting point for
+    /// any model that learns customer and movie factors.
+    #[allow(dead_code)]
+    pub fn balanced_factors(&self) -> (DMatrix<f64>, DMatrix<f64>) {
+        let sqrt = self.singular_values.map(f64::sqrt);
+        let mut u = self.u.clone();
+        let mut v = self.v.clone();
+        for (c, s) in sqrt.iter().enumerate() {
+            u.column_mut(c).scale_mut(*s);
+            v.column_mut(c).scale_mut(*s);
+        }
+        (u, v)
+    }
+}
+
+impl RandomizedSvd {
+    pub fn new(rank: usize) -> Self {
+        Self {
+            rank,
+            oversampling: 10,
+            power_iterations: 2,
+            seed: 271,
+        }
+    }
+
+    pub fn decompose(&self, a: &SparseMatrix) -> TruncatedSvd {
+        let (n, m) = a.shape();
+        let rank = usize::min(self.rank, usize::min(n, m));
+        let l = usize::min(rank + self.oversampling, usize::min(n, m));
+        let at = a.transpose();
+        let mut rng = StdRng::seed_from_u64(self.seed);
+
+        let omega = DMatrix::from_fn(m, l, |_, _| rng.gen_range(-1f64, 1f64));
+        let mut q = a.mul_dense(&omega).qr().q();
+        for i in 0..self.power_iterations {
+            // Orthonormalize in between, or everything collapses onto
+            // the top singular vector.
+            let z = at.mul_dense(&q).qr().q();
+            q = a.mul_dense(&z).qr().q();
+            debug!("Randomized SVD power iteration {} done", i + 1);
+        }
+
+        // B = Q^T A is l x m, decompose its transpose A^T Q instead.
+        let bt = at.mul_dense(&q);
+        let svd = SVD::new(bt, true, true);
+        let singular_values = svd.singular_values;
+        let (v, ut) = (svd.u.unwrap(), svd.v_t.unwrap());
+        let mut order: Vec<usize> = (0..singular_values.len()).collect();
+        order.sort_by(|&a, &b| singular_values[b].partial_cmp(&singular_values[a]).unwrap());
+        order.truncate(rank);
+        TruncatedSvd {
+            u: q * DMatrix::from_fn(l, rank, |r, c| ut[(order[c], r)]),
+            singular_values: DVector::from_fn(rank, |i, _| singular_values[order[i]]),
+            v: DMatrix::from_fn(m, rank, |r, c| v[(r, order[c])]),
+        }
+    }
+}
+
+/// How the distance between two clusters is derived from the distances
+/// between their members.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Linkage {
+    /// Closest pair of members.
+    Single,
+    /// Farthest pair of members.
+    Complete,
+    /// Average over all pairs of members (UPGMA).
+    Average,
+    /// Increase of the within cluster variance, meaningful only when the
+    /// distances are Euclidean.
+    Ward,
+}
+
+impl FromStr for Linkage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Linkage::Single),
+            "complete" => Ok(Linkage::Complete),
+            "average" => Ok(Linkage::Average),
+            "ward" => Ok(Linkage::Ward),
+            _ => Err(format!(
+                "Unknown linkage {}, try single, complete, average or ward",
+                s
+            )),
+        }
+    }
+}
+
+/// Bottom up hierarchical clustering.
+///
+/// Clusters are merged with the nearest neighbour chain algorithm, which
+/// takes $`O(n^2)`$ time and the n x n distances in memory, and
+/// distances are updated by the Lance-Williams formula of the `linkage`.
+#[derive(Debug, Clone)]
+pub struct Agglomerative {
+    pub linkage: Linkage,
+}
+
+/// One merge of a `Dendrogram`. Node `i < n` is the leaf `i`, node
+/// `n + t` is the cluster created by the `t`-th merge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merge {
+    pub left: usize,
+    pub right: usize,
+    pub distance: f64,
+    /// # of leaves under this merge.
+    pub size: usize,
+}
+
+impl fmt::Display for Merge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.left, self.right, self.distance, self.size
+        )
+    }
+}
+
+/// Result of `Agglomerative::fit`, the `n - 1` merges by increasing
+/// distance.
+#[derive(Debug, Clone)]
+pub struct Dendrogram {
+    pub num_leaves: usize,
+    pub merges: Vec<Merge>,
+}
+
+impl Agglomerative {
+    pub fn new(linkage: Linkage) -> Self {
+        Self { linkage }
+    }
+
+    /// Cluster n items given their symmetric n x n `distances`.
+    pub fn fit(&self, distances: &DMatrix<f64>) -> Dendrogram {
+        let n = distances.nrows();
+        assert!(distances.is_square(), "Distances must be n x n.");
+        // Ward's update is only exact on squared Euclidean distances.
+        let mut d = match self.linkage {
+            Linkage::Ward => distances.map(|x| x * x),
+            _ => distances.clone(),
+        };
+        let mut size = vec![1usize; n];
+        let mut active = vec![true; n];
+        let mut chain: Vec<usize> = Vec::with_capacity(n);
+        // (a, b, distance), the merged cluster lives on in slot b.
+        let mut merges: Vec<(usize, usize, f64)> = Vec::with_capacity(n.saturating_sub(1));
+        for _ in 1..n {
+            if chain.is_empty() {
+                chain.push(active.iter().position(|&a| a).unwrap());
+            }
+            let (a, b) = loop {
+                let a = chain[chain.len() - 1];
+                // Prefer the previous one on ties, or the chain may cycle.
+                let prev = if chain.len() > 1 {
+                    Some(chain[chain.len() - 2])
+                } else {
+                    None
+                };
+                let mut best = prev;
+                let mut best_dist = prev.map_or(f64::INFINITY, |p| d[(a, p)]);
+                for (c, _) in active.iter().enumerate().filter(|&(c, &on)| on && c != a) {
+                    if d[(a, c)] < best_dist || best.is_none() {
+                        best = Some(c);
+                        best_dist = d[(a, c)];
+                    }
+                }
+                let best = best.unwrap();
+                if Some(best) == prev {
+                    break (a, best);
+                }
+                chain.push(best);
+            };
+            chain.truncate(chain.len() - 2);
+            let dab = d[(a, b)];
+            let (na, nb) = (size[a] as f64, size[b] as f64);
+            for c in (0..n).filter(|&c| active[c] && c != a && c != b) {
+                let (dac, dbc) = (d[(a, c)], d[(b, c)]);
+                let nc = size[c] as f64;
+                let updated = match self.linkage {
+                    Linkage::Single => dac.min(dbc),
+                    Linkage::Complete => dac.max(dbc),
+                    Linkage::Average => (na * dac + nb * dbc) / (na + nb),
+                    Linkage::Ward => {
+                        ((na + nc) * dac + (nb + nc) * dbc - nc * dab) / (na + nb + nc)
+                    }
+                };
+                d[(b, c)] = updated;
+                d[(c, b)] = updated;
+            }
+            active[a] = false;
+            size[b] += size[a];
+            let dab = match self.linkage {
+                Linkage::Ward => dab.max(0f64).sqrt(),
+                _ => dab,
+            };
+            merges.push((a, b, dab));
+        }
+
+        // The chain finds merges out of order, sort them and name the
+        // clusters in that order.
+        merges.sort_by(|x, y| x.2.partial_cmp(&y.2).unwrap());
+        let mut union_find = UnionFind::new(n);
+        let mut node: Vec<usize> = (0..n).collect();
+        let merges = merges
+            .into_iter()
+            .enumerate()
+            .map(|(t, (a, b, distance))| {
+                let (ra, rb) = (union_find.find(a), union_find.find(b));
+                let (left, right) = (node[ra].min(node[rb]), node[ra].max(node[rb]));
+                let root = union_find.union(ra, rb);
+                node[root] = n + t;
+                Merge {
+                    left,
+                    right,
+                    distance,
+                    size: union_find.size[root],
+                }
+            })
+            .collect();
+        Dendrogram {
+            num_leaves: n,
+            merges,
+        }
+    }
+}
+
+impl Dendrogram {
+    /// Flat clustering with `num_clusters` clusters, labels are numbered
+    /// by their first leaf.
+    pub fn cut(&self, num_clusters: usize) -> Vec<usize> {
+        let num_clusters = num_clusters.max(1).min(self.num_leaves);
+        self.flat(self.num_leaves - num_clusters)
+    }
+
+    /// Flat clustering that only merges clusters closer than `distance`.
+    #[allow(dead_code)]
+    pub fn cut_at(&self, distance: f64) -> Vec<usize> {
+        self.flat(
+            self.merges
+                .iter()
+                .take_while(|m| m.distance <= distance)
+                .count(),
+        )
+    }
+
+    /// Labels after the first `num_merges` merges.
+    fn flat(&self, num_merges: usize) -> Vec<usize> {
+        let n = self.num_leaves;
+        let mut union_find = UnionFind::new(2 * n);
+        for (t, merge) in self.merges.iter().take(num_merges).enumerate() {
+            union_find.union(merge.left, n + t);
+            union_find.union(merge.right, n + t);
+        }
+        let mut labels = vec![usize::MAX; 2 * n];
+        let mut num_labels = 0;
+        (0..n)
+            .map(|i| {
+                let root = union_find.find(i);
+                if labels[root] == usize::MAX {
+                    labels[root] = num_labels;
+                    num_labels += 1;
+                }
+                labels[root]
+            })
+            .collect()
+    }
+}
+
+/// Disjoint sets with path halving and union by size.
+struct UnionFind {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        Self {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    /// Returns the root of the merged set.
+    fn union(&mut self, a: usize, b: usize) -> usize {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return a;
+        }
+        if self.size[a] < self.size[b] {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+        a
+    }
+}
+
+/// Project the rows of the n x d `points` onto their first `dims`
+/// principal components, the result is n x `dims`.
+pub fn pca(points: &DMatrix<f64>, dims: usize) -> DMatrix<f64> {
+    let (n, d) = points.shape();
+    let dims = usize::min(dims, d);
+    let mean = points.row_mean();
+    let mut centered = points.clone();
+    for mut row in centered.row_iter_mut() {
+        row -= &mean;
+    }
+    let covariance = centered.transpose() * &centered / usize::max(n, 2).sub(1) as f64;
+    let eigen = SymmetricEigen::new(covariance);
+    let mut order: Vec<usize> = (0..d).collect();
+    order.sort_by(|&a, &b| {
+        eigen.eigenvalues[b]
+            .partial_cmp(&eigen.eigenvalues[a])
+            .unwrap()
+    });
+    let components = DMatrix::from_fn(d, dims, |r, c| eigen.eigenvectors[(r, order[c])]);
+    centered * components
+}
+
+/// t-distributed stochastic neighbour embedding into 2-D, see van der
+/// Maaten and Hinton, 2008.
+///
+/// This is the exact $`O(n^2)`$ version, fine for a few thousand points.
+/// Neighbours in the input are Gaussian with a bandwidth per point picked
+/// to match `perplexity`, neighbours in the output are Student-t, and the
+/// KL divergence between both is minimized by gradient descent with
+/// momentum and early exaggeration.
+#[derive(Debug, Clone)]
+pub struct Tsne {
+    pub perplexity: f64,
+    pub num_iter: usize,
+    /// $`n / \alpha`$ with $`\alpha`$ the early exaggeration if not set,
+    /// a fixed step diverges on few points and stalls on many.
+    pub learning_rate: Option<f64>,
+    /// P is multiplied by this for the first `exaggeration_iter` iterations.
+    pub early_exaggeration: f64,
+    pub exaggeration_iter: usize,
+    pub seed: u64,
+}
+
+impl Default for Tsne {
+    fn default() -> Self {
+        Self {
+            perplexity: 30f64,
+            num_iter: 1000,
+            learning_rate: None,
+            early_exaggeration: 12f64,
+            exaggeration_iter: 250,
+            seed: 271,
+        }
+    }
+}
+
+impl Tsne {
+    /// Embed the rows of the n x d `points`, the result is n x 2.
+    pub fn fit(&self, points: &DMatrix<f64>) -> DMatrix<f64> {
+        let n = points.nrows();
+        if n < 2 {
+            return DMatrix::zeros(n, 2);
+        }
+        let p = self.affinities(points);
+        let learning_rate = self
+            .learning_rate
+            .unwrap_or_else(|| f64::max(n as f64 / self.early_exaggeration, 1f64));
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut y: Vec<[f64; 2]> = (0..n)
+            .map(|_| [rng.gen_range(-1e-4, 1e-4), rng.gen_range(-1e-4, 1e-4)])
+            .collect();
+        let mut velocity = vec![[0f64; 2]; n];
+        let mut gains = vec![[1f64; 2]; n];
+        for iter in 0..self.num_iter {
+            let (exaggeration, momentum) = if iter < self.exaggeration_iter {
+                (self.early_exaggeration, 0.5f64)
+            } else {
+                (1f64, 0.8f64)
+            };
+            // Unnormalized Student-t kernel, row i is (1 + |y_i - y_j|^2)^-1.
+            let kernel: Vec<Vec<f64>> = (0..n)
+                .into_par_iter()
+                .map(|i| {
+                    (0..n)
+                        .map(|j| {
+                            let (dx, dy) = (y[i][0] - y[j][0], y[i][1] - y[j][1]);
+                            if i == j {
+                                0f64
+                            } else {
+                                1f64 / (1f64 + dx * dx + dy * dy)
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+            let z: f64 = kernel.par_iter().map(|row| row.iter().sum::<f64>()).sum();
+            let gradient: Vec<[f64; 2]> = (0..n)
+                .into_par_iter()
+                .map(|i| {
+                    let mut g = [0f64; 2];
+                    for j in 0..n {
+                        let w = (exaggeration * p[(i, j)] - kernel[i][j] / z) * kernel[i][j];
+                        g[0] += 4f64 * w * (y[i][0] - y[j][0]);
+                        g[1] += 4f64 * w * (y[i][1] - y[j][1]);
+                    }
+                    g
+                })
+                .collect();
+            for i in 0..n {
+                for c in 0..2 {
+                    // Grow the step while the gradient keeps its direction.
+                    gains[i][c] = if (gradient[i][c] > 0f64) != (velocity[i][c] > 0f64) {
+                        gains[i][c] + 0.2f64
+                    } else {
+                        f64::max(gains[i][c] * 0.8f64, 0.01f64)
+                    };
+                    velocity[i][c] =
+                        momentum * velocity[i][c] - learning_rate * gains[i][c] * gradient[i][c];
+                    y[i][c] += velocity[i][c];
+                }
+            }
+            if (iter + 1) % 100 == 0 {
+                debug!("t-SNE iteration {} done", iter + 1);
+            }
+        }
+        DMatrix::from_fn(n, 2, |i, c| y[i][c])
+    }
+
+    /// Symmetric $`p_{ij}`$, every row's bandwidth is found by bisection.
+    fn affinities(&self, points: &DMatrix<f64>) -> DMatrix<f64> {
+        const MAX_STEPS: usize = 100;
+        const TOL: f64 = 1e-5;
+        let n = points.nrows();
+        let target = self.perplexity.min((n - 1) as f64).ln();
+        let rows: Vec<Vec<f64>> = (0..n)
+            .into_par_iter()
+            .map(|i| {
+                let distances: Vec<f64> = (0..n)
+                    .map(|j| (points.row(i) - points.row(j)).norm_squared())
+                    .collect();
+                let (mut beta, mut lo, mut hi) = (1f64, 0f64, f64::INFINITY);
+                let mut row = vec![0f64; n];
+                for _ in 0..MAX_STEPS {
+                    let min = (0..n)
+                        .filter(|&j| j != i)
+                        .map(|j| distances[j])
+                        .fold(f64::INFINITY, f64::min);
+                    // Shift by the nearest distance so that exp never underflows
+                    // to all zeros.
+                    (0..n).for_each(|j| {
+                        row[j] = if j == i {
+                            0f64
+                        } else {
+                            (-beta * (distances[j] - min)).exp()
+                        }
+                    });
+                    let sum: f64 = row.iter().sum();
+                    let entropy = beta
+                        * row
+                            .iter()
+                            .zip(&distances)
+                            .map(|(p, d)| p * (d - min))
+                            .sum::<f64>()
+                        / sum
+                        + sum.ln();
+                    row.iter_mut().for_each(|p| *p /= sum);
+                    if (entropy - target).abs() < TOL {
+                        break;
+                    }
+                    // Too much entropy means too wide a Gaussian.
+                    if entropy > target {
+                        lo = beta;
+                        beta = if hi.is_infinite() {
+                            beta * 2f64
+                        } else {
+                            (beta + hi) / 2f64
+                        };
+                    } else {
+                        hi = beta;
+                        beta = (beta + lo) / 2f64;
+                    }
+                }
+                row
+            })
+            .collect();
+        DMatrix::from_fn(n, n, |i, j| {
+            ((rows[i][j] + rows[j][i]) / (2f64 * n as f64)).max(1e-12)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_lanczos() {
+        let n = 60;
+        let mut seed = 42u64;
+        let a = DMatrix::from_iterator(n, n, random_vector(n * n, &mut seed).iter().copied());
+        let a = &a + a.transpose();
+        let dense = SymmetricEigen::new(a.clone());
+        let mut expected: Vec<f64> = dense.eigenvalues.iter().copied().collect();
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let mut lanczos = Lanczos::new(4, Which::Largest);
+        // Force a few restarts.
+        lanczos.max_dim = 12;
+        let largest = lanczos.solve(&a);
+        let smallest = Lanczos::new(4, Which::Smallest).solve(&SparseMatrix::from_dense(&a));
+        for i in 0..4 {
+            assert!((largest.eigenvalues[i] - expected[n - 1 - i]).abs() < 1e-6);
+            assert!((smallest.eigenvalues[i] - expected[i]).abs() < 1e-6);
+            for eigen in [&largest, &smallest].iter() {
+                let v = eigen.eigenvectors.column(i);
+                assert!((v.norm() - 1f64).abs() < 1e-6);
+                assert!((&a * v - v * eigen.eigenvalues[i]).norm() < 1e-5);
+            }
+        }
+        let empty = Lanczos::new(4, Which::Smallest).solve(&DMatrix::<f64>::zeros(0, 0));
+        assert!(empty.eigenvalues.is_empty() && empty.eigenvectors.shape() == (0, 0));
+    }
+
+    #[test]
+    fn test_kmeans() {
+        let mut seed = 7u64;
+        let centers = [(0f64, 0f64), (10f64, 0f64), (0f64, 10f64)];
+        let noise = random_vector(60, &mut seed);
+        let points = DMatrix::from_fn(30, 2, |i, j| {
+            let (x, y) = centers[i % 3];
+            (if j == 0 { x } else { y }) + noise[2 * i + j]
+        });
+        let clustering = KMeans::new(3).fit(&points);
+        for i in 0..30 {
+            assert!(clustering.labels[i] == clustering.labels[i % 3]);
+        }
+        assert!(clustering.labels[0] != clustering.labels[1]);
+        assert!(clustering.labels[1] != clustering.labels[2]);
+        assert!(clustering.labels[0] != clustering.labels[2]);
+        // Noise is uniform in [-0.5, 0.5), so no point is further than 1.
+        assert!(clustering.inertia < 30f64);
+        assert!(clustering.inertia == KMeans::new(3).fit(&points).inertia);
+        assert!(KMeans::new(0).fit(&points).labels.is_empty());
+        assert!(KMeans::new(3).fit(&DMatrix::zeros(0, 2)).labels.is_empty());
+    }
+
+    #[test]
+    fn test_randomized_svd() {
+        let mut seed = 3u64;
+        // A rank 3 matrix with a few entries dropped.
+        let x = DMatrix::from_iterator(40, 3, random_vector(120, &mut seed).iter().copied());
+        let y = DMatrix::from_iterator(3, 30, random_vector(90, &mut seed).iter().copied());
+        let mut a = x * y;
+        a[(0, 0)] = 0f64;
+        a[(5, 7)] = 0f64;
+        let expected = SVD::new(a.clone(), false, false).singular_values;
+        let mut expected: Vec<f64> = expected.iter().copied().collect();
+        expected.sort_by(|a, b| b.partial_cmp(a).unwrap());
+
+        let svd = RandomizedSvd::new(3).decompose(&SparseMatrix::from_dense(&a));
+        assert!(svd.u.shape() == (40, 3) && svd.v.shape() == (30, 3));
+        for (i, expected) in expected.iter().take(3).enumerate() {
+            assert!((svd.singular_values[i] - expected).abs() < 1e-6);
+            assert!((svd.u.column(i).norm() - 1f64).abs() < 1e-6);
+            assert!((svd.v.column(i).norm() - 1f64).abs() < 1e-6);
+        }
+        let (u, v) = svd.balanced_factors();
+        let residual = &a - u * v.transpose();
+        assert!(residual.norm() <= expected[3] * 1.5);
+    }
+
+    #[test]
+    fn test_agglomerative() {
+        let x = [0f64, 1f64, 5f64, 6f64, 20f64];
+        let distances = DMatrix::from_fn(5, 5, |i, j| (x[i] - x[j]).abs());
+        let heights = |linkage| -> Vec<f64> {
+            let dendrogram = Agglomerative::new(linkage).fit(&distances);
+            assert!(dendrogram.cut(3) == vec![0, 0, 1, 1, 2]);
+            assert!(dendrogram.cut(2) == vec![0, 0, 0, 0, 1]);
+            assert!(dendrogram.merges.last().unwrap().size == 5);
+            dendrogram.merges.iter().map(|m| m.distance).collect()
+        };
+        assert!(heights(Linkage::Single) == vec![1f64, 1f64, 4f64, 14f64]);
+        assert!(heights(Linkage::Complete) == vec![1f64, 1f64, 6f64, 20f64]);
+        assert!(heights(Linkage::Average) == vec![1f64, 1f64, 5f64, 17f64]);
+        heights(Linkage::Ward);
+
+        let dendrogram = Agglomerative::new(Linkage::Single).fit(&distances);
+        assert!(
+            dendrogram.merges[0]
+                == Merge {
+                    left: 0,
+                    right: 1,
+                    distance: 1f64,
+                    size: 2
+                }
+        );
+        assert!(dendrogram.merges[2].left == 5 && dendrogram.merges[2].right == 6);
+        assert!(dendrogram.cut_at(4f64) == vec![0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_pca_tsne() {
+        // Two blobs far apart along (1, 1, 1, 1).
+        let mut seed = 7u64;
+        let noise = random_vector(40 * 4, &mut seed);
+        let points = DMatrix::from_fn(40, 4, |i, j| {
+            (if i < 20 { 0f64 } else { 10f64 }) + 0.1f64 * noise[i * 4 + j]
+        });
+        let projected = pca(&points, 2);
+        assert!(projected.shape() == (40, 2));
+        // The first component separates the blobs, the second is noise.
+        let first = projected.column(0);
+        assert!((0..20).all(|i| first[i].signum() == first[0].signum()));
+        assert!((20..40).all(|i| first[i].signum() != first[0].signum()));
+        assert!(projected.column(1).amax() < 1f64);
+
+        let tsne = Tsne {
+            perplexity: 5f64,
+            num_iter: 300,
+            ..Tsne::default()
+        };
+        let embedded = tsne.fit(&points);
+        assert!(embedded.shape() == (40, 2));
+        let distance = |i: usize, j: usize| (embedded.row(i) - embedded.row(j)).norm();
+        let within = (1..20).map(|j| distance(0, j)).fold(0f64, f64::max);
+        let between = (20..40)
+            .map(|j| distance(0, j))
+            .fold(f64::INFINITY, f64::min);
+        assert!(within < between);
+    }
+}
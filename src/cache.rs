@@ -0,0 +1,417 @@
+//! A `RawData` is cached as one little endian file:
+//!
+//! | field            | type                             |
+//! |------------------|----------------------------------|
+//! | magic            | `b"RNFC"`                        |
+//! | version          | `u32`                            |
+//! | source checksum  | `u32`, see `source_checksum`     |
+//! | payload checksum | `u32`, CRC32 of the payload      |
+//! | payload length   | `u64`                            |
+//! | payload          | see below                        |
+//!
+//! The payload starts with the # of training transactions, test
+//! transactions, movies, customer ids, movie ids and cross validation
+//! transactions (`u64::MAX` if unspecified) as `u64`s, and the `f64` minimum, maximum and
+//! step (NaN if continuous) of the rating scale, followed by the columns
+//! of the training then the test transactions (`u32` movie ids, `u32`
+//! virtual customer ids, `f32` ratings, NaN if unknown, and `u16` days),
+//! the `u32` original
+//! id of every virtual customer and movie id, and the `u32` ids and `u16`
+//! years of the movies.
+//! Then come the titles and the `|` separated genres of the movies, each
+//! as one UTF-8 blob preceded by the `u32` offset of every string plus the
+//! end.
+//!
+//! Everything is decoded from a memory map, so the OS reads the file in
+//! large sequential chunks instead of us parsing text.
+use crc32fast::Hasher;
+use elapsed::measure_time;
+use log::info;
+use memmap2::Mmap;
+use std::{
+    convert::TryInto,
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::data::*;
+
+const MAGIC: &[u8; 4] = b"RNFC";
+
+/// Bumped whenever the layout changes, older caches are rebuilt.
+pub const FORMAT_VERSION: u32 = 5;
+
+const HEADER_LEN: usize = 4 + 4 + 4 + 4 + 8;
+
+/// CRC32 of the `format` the sources are read with, and of the name,
+/// length and modification time of every source file. Hashing the contents
+/// would cost as much as parsing them, while any edit, copy or download of
+/// the files changes one of these.
+pub fn source_checksum(format: &str, sources: &[PathBuf]) -> Result<u32, Box<dyn Error>> {
+    let mut hasher = Hasher::new();
+    hasher.update(format.as_bytes());
+    for source in sources {
+        let meta =
+            fs::metadata(source).map_err(|err| format!("Cannot read {:?}: {}", source, err))?;
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?;
+        hasher.update(source.to_string_lossy().as_bytes());
+        hasher.update(&meta.len().to_le_bytes());
+        hasher.update(&mtime.as_secs().to_le_bytes());
+        hasher.update(&mtime.subsec_nanos().to_le_bytes());
+    }
+    Ok(hasher.finalize())
+}
+
+/// A `Write` that keeps the CRC32 and length of what went through it.
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write `raw` to `path`, tagged with the `source_checksum` it was parsed
+/// from. The file is written aside and renamed, so a crash never leaves a
+/// truncated cache behind.
+pub fn save(path: &Path, raw: &RawData, source_checksum: u32) -> Result<(), Box<dyn Error>> {
+    info!("Writing data cache to {:?}", path);
+    let (elapsed, ret) = measure_time(|| -> Result<(), Box<dyn Error>> {
+        let tmp = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        file.write_all(&[0; HEADER_LEN])?;
+        let mut w = ChecksumWriter {
+            inner: file,
+            hasher: Hasher::new(),
+            len: 0,
+        };
+        for len in &[
+            raw.transactions.len(),
+            raw.test_data.len(),
+            raw.movies.len(),
+            raw.customer_ids.len(),
+            raw.movie_ids.len(),
+        ] {
+            w.write_all(&(*len as u64).to_le_bytes())?;
+        }
+        let num_cross_valid = raw.num_cross_valid.map_or(u64::MAX, |n| n as u64);
+        w.write_all(&num_cross_valid.to_le_bytes())?;
+        let scale = raw.scale;
+        for bound in &[scale.min, scale.max, scale.step.unwrap_or(f64::NAN)] {
+            w.write_all(&bound.to_le_bytes())?;
+        }
+        for transactions in &[&raw.transactions, &raw.test_data] {
+            write_column(&mut w, transactions.iter(), |t| to_u32(t.movie_id))?;
+            write_column(&mut w, transactions.iter(), |t| to_u32(t.customer_id))?;
+            write_column(&mut w, transactions.iter(), |t| {
+                Ok(t.rating.unwrap_or(f32::NAN).to_le_bytes())
+            })?;
+            write_column(&mut w, transactions.iter(), |t| Ok(t.date.to_le_bytes()))?;
+        }
+        for ids in &[&raw.customer_ids, &raw.movie_ids] {
+            write_column(&mut w, ids.originals().iter(), |&id| to_u32(id))?;
+        }
+        write_column(&mut w, raw.movies.iter(), |m| to_u32(m.movie_id))?;
+        write_column(&mut w, raw.movies.iter(), |m| {
+            Ok(m.year_produced.to_le_bytes())
+        })?;
+        let titles: Vec<&str> = raw.movies.iter().map(|m| m.title.as_str()).collect();
+        write_strings(&mut w, &titles)?;
+        let genres: Vec<String> = raw.movies.iter().map(|m| m.genres.join("|")).collect();
+        write_strings(&mut w, &genres)?;
+
+        let ChecksumWriter { inner, hasher, len } = w;
+        let mut file = inner.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        file.write_all(&source_checksum.to_le_bytes())?;
+        file.write_all(&hasher.finalize().to_le_bytes())?;
+        file.write_all(&len.to_le_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    });
+    info!("Writing data cache finished... elapsed: {}", elapsed);
+    ret
+}
+
+fn to_u32(value: usize) -> Result<[u8; 4], Box<dyn Error>> {
+    let value: u32 = value
+        .try_into()
+        .map_err(|_| format!("{} does not fit in the data cache", value))?;
+    Ok(value.to_le_bytes())
+}
+
+/// The `u32` offset of every string plus the end, then the strings.
+fn write_strings<S: AsRef<str>>(w: &mut impl Write, strings: &[S]) -> Result<(), Box<dyn Error>> {
+    let mut offset = 0;
+    write_column(w, strings.iter(), |s| {
+        let start = to_u32(offset);
+        offset += s.as_ref().len();
+        start
+    })?;
+    w.write_all(&to_u32(offset)?)?;
+    for s in strings {
+        w.write_all(s.as_ref().as_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_column<'a, T: 'a, B: AsRef<[u8]>>(
+    w: &mut impl Write,
+    items: impl Iterator<Item = &'a T>,
+    mut encode: impl FnMut(&T) -> Result<B, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    for item in items {
+        w.write_all(encode(item)?.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Read the cache at `path`. Fails if it is missing, of another version,
+/// corrupted, or was not built from sources with `source_checksum`.
+pub fn load(path: &Path, source_checksum: u32) -> Result<RawData, Box<dyn Error>> {
+    let file = File::open(path)?;
+    // SAFETY: the cache is only ever replaced by a rename, never modified
+    // in place, so the mapped pages do not change under us.
+    let mmap = unsafe { Mmap::map(&file)? };
+    if mmap.len() < HEADER_LEN || &mmap[0..4] != MAGIC {
+        return Err("Not a data cache".into());
+    }
+    let mut header = Columns {
+        bytes: &mmap[..HEADER_LEN],
+        pos: 4,
+    };
+    let version = header.u32s(1)?[0] as u32;
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Cache format version {} but {} expected",
+            version, FORMAT_VERSION
+        )
+        .into());
+    }
+    if header.u32s(1)?[0] as u32 != source_checksum {
+        return Err("Source files changed since the cache was written".into());
+    }
+    let payload_checksum = header.u32s(1)?[0] as u32;
+    let payload_len = header.u64s(1)?[0];
+    let payload = &mmap[HEADER_LEN..];
+    if payload.len() as u64 != payload_len || crc32fast::hash(payload) != payload_checksum {
+        return Err("Cache is corrupted".into());
+    }
+
+    info!("Loading cached data from {:?}", path);
+    let (elapsed, ret) = measure_time(|| -> Result<RawData, Box<dyn Error>> {
+        let mut columns = Columns {
+            bytes: payload,
+            pos: 0,
+        };
+        let lens = columns.u64s(6)?;
+        let (num_train, num_test, num_movies, num_customers, num_movie_ids) = (
+            lens[0] as usize,
+            lens[1] as usize,
+            lens[2] as usize,
+            lens[3] as usize,
+            lens[4] as usize,
+        );
+        let num_cross_valid = if lens[5] == u64::MAX {
+            None
+        } else {
+            Some(lens[5] as usize)
+        };
+        let bounds = columns.f64s(3)?;
+        let step = Some(bounds[2]).filter(|step| !step.is_nan());
+        let scale = RatingScale::new(bounds[0], bounds[1], step)?;
+        let transactions = columns.transactions(num_train)?;
+        let test_data = columns.transactions(num_test)?;
+        let customer_ids = IdMapper::from_originals(columns.u32s(num_customers)?);
+        let movie_ids = IdMapper::from_originals(columns.u32s(num_movie_ids)?);
+        let ids = columns.u32s(num_movies)?;
+        let years = columns.u16s(num_movies)?;
+        let titles = columns.strings(num_movies)?;
+        let genres = columns.strings(num_movies)?;
+        let movies = titles
+            .into_iter()
+            .zip(genres)
+            .enumerate()
+            .map(|(i, (title, genres))| Movie {
+                movie_id: ids[i],
+                year_produced: years[i] as u16,
+                title,
+                genres: if genres.is_empty() {
+                    vec![]
+                } else {
+                    genres.split('|').map(str::to_string).collect()
+                },
+            })
+            .collect();
+        Ok(RawData {
+            transactions,
+            test_data,
+            movies,
+            customer_ids,
+            movie_ids,
+            scale,
+            num_cross_valid,
+        })
+    });
+    info!("Loading cached data finished... elapsed: {}", elapsed);
+    ret
+}
+
+/// Cursor over the little endian columns of a mapped cache.
+struct Columns<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Columns<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or("Cache is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn u16s(&mut self, n: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        Ok(self
+            .take(n * 2)?
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)
+            .collect())
+    }
+    fn u32s(&mut self, n: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        Ok(self
+            .take(n * 4)?
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize)
+            .collect())
+    }
+    fn u64s(&mut self, n: usize) -> Result<Vec<u64>, Box<dyn Error>> {
+        Ok(self
+            .take(n * 8)?
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+    fn f64s(&mut self, n: usize) -> Result<Vec<f64>, Box<dyn Error>> {
+        Ok(self
+            .take(n * 8)?
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+    fn strings(&mut self, n: usize) -> Result<Vec<String>, Box<dyn Error>> {
+        let offsets = self.u32s(n + 1)?;
+        let bytes = self.take(offsets[n])?;
+        (0..n)
+            .map(|i| {
+                let s = bytes
+                    .get(offsets[i]..offsets[i + 1])
+                    .ok_or("Cache is corrupted")?;
+                Ok(String::from_utf8(s.to_vec())?)
+            })
+            .collect()
+    }
+    fn transactions(&mut self, n: usize) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let movie_ids = self.take(n * 4)?;
+        let customer_ids = self.take(n * 4)?;
+        let ratings = self.take(n * 4)?;
+        let dates = self.take(n * 2)?;
+        Ok(movie_ids
+            .chunks_exact(4)
+            .zip(customer_ids.chunks_exact(4))
+            .zip(ratings.chunks_exact(4))
+            .zip(dates.chunks_exact(2))
+            .map(|(((movie_id, customer_id), rating), date)| Transaction {
+                movie_id: u32::from_le_bytes(movie_id.try_into().unwrap()) as usize,
+                customer_id: u32::from_le_bytes(customer_id.try_into().unwrap()) as usize,
+                rating: Some(f32::from_le_bytes(rating.try_into().unwrap()))
+                    .filter(|r| !r.is_nan()),
+                date: u16::from_le_bytes([date[0], date[1]]),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_days() {
+        assert!(parse_day("1970-01-01").unwrap() == 0);
+        assert!(parse_day("2000-03-01").unwrap() == 11017);
+        for &date in &["1999-11-11", "2004-02-29", "2005-12-31", "2149-06-06"] {
+            assert!(format_day(parse_day(date).unwrap()) == date);
+        }
+        assert!(parse_day("2005-13-01").is_err());
+        assert!(parse_day("2005-02-29").is_err());
+        assert!(parse_day("1969-12-31").is_err());
+        assert!(parse_day("").is_err());
+    }
+
+    #[test]
+    fn test_save_load_cache() {
+        let transaction = |movie_id, customer_id, rating, date| Transaction {
+            movie_id,
+            customer_id,
+            rating,
+            date,
+        };
+        let raw = RawData {
+            transactions: vec![
+                transaction(0, 0, Some(4.5), 12000),
+                transaction(17769, 1, Some(0.5), 13000),
+            ],
+            test_data: vec![transaction(1, 2, None, 13148)],
+            movies: vec![
+                Movie {
+                    movie_id: 0,
+                    year_produced: 2003,
+                    title: "Dinosaur Planet".to_string(),
+                    genres: vec![],
+                },
+                Movie {
+                    movie_id: 1,
+                    year_produced: 0,
+                    title: "Amélie, \"Le Fabuleux\"".to_string(),
+                    genres: vec!["Comedy".to_string(), "Romance".to_string()],
+                },
+            ],
+            customer_ids: IdMapper::from_originals(vec![1488844, 822109, 2649429]),
+            movie_ids: IdMapper::from_originals((1..=17770).collect()),
+            scale: RatingScale::HALF_STARS,
+            num_cross_valid: Some(1),
+        };
+        let dir = std::env::temp_dir().join(format!("cache_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.cache");
+        save(&path, &raw, 42).unwrap();
+
+        let loaded = load(&path, 42).unwrap();
+        assert!(format!("{:?}", loaded) == format!("{:?}", raw));
+        // Changed sources.
+        assert!(load(&path, 43).is_err());
+        // Corrupted payload.
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(load(&path, 42).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
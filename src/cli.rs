@@ -0,0 +1,754 @@
+use elapsed::measure_time;
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+use crate::config;
+use crate::data::{Data, Transaction};
+use crate::io::{output::OutputFormat, FieldError, FromCsv, ParseMode, Rejects};
+use crate::models::cold_start::{ColdStart, FallbackChain, Priors};
+use crate::models::experiment::{self, Experiment, ModelConfig};
+use crate::models::persist::{self, Provenance};
+use crate::models::{rmse, search, Model, ModelHolder};
+use crate::plot;
+use crate::report;
+use crate::tracking::{self, Tracker};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "recommend-netflix", about = "Recommend movies from ratings.")]
+pub struct Opt {
+    /// Folder of the data files, `$DATA_PATH` or else `$RECOMMEND_HOME/data`.
+    #[structopt(long, parse(from_os_str))]
+    pub data: Option<PathBuf>,
+    /// Folder where predictions, plots and reports are written.
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    pub output: PathBuf,
+    /// Folder where trained models are saved and loaded from, see
+    /// `$MODEL_DIR`.
+    #[structopt(long, parse(from_os_str), env = config::MODEL_DIR)]
+    pub model_dir: Option<PathBuf>,
+    /// `.toml` or `.json` file of named model configurations, see
+    /// `$EXPERIMENT`.
+    #[structopt(long, parse(from_os_str), env = config::EXPERIMENT)]
+    pub experiment: Option<PathBuf>,
+    /// Folder where runs of `train`, `evaluate`, `predict` and `tune` are
+    /// recorded, see `$RUNS_DIR`.
+    #[structopt(long, parse(from_os_str), env = config::RUNS_DIR)]
+    pub runs: Option<PathBuf>,
+    /// Fallbacks predicting customers and movies without a training
+    /// rating, or `none`, see `$COLD_START`.
+    #[structopt(
+        long,
+        env = config::COLD_START,
+        default_value = "customer_bias,movie_mean,year_prior,global_mean"
+    )]
+    pub cold_start: FallbackChain,
+    /// Log level, e.g. `info` or `recommend_netflix=debug`. Defaults to
+    /// `$RUST_LOG`, or else everything.
+    #[structopt(long)]
+    pub log_level: Option<String>,
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Show the size of the dataset.
+    Stats,
+    /// Plot how many ratings every customer has, and the rating matrix.
+    Plot,
+    /// Cluster the most rated movies hierarchically.
+    Report,
+    /// Train models, and save them to the model directory if any.
+    Train {
+        /// Models or configurations of the experiment to train, by name,
+        /// glob, `tag:<tag>` or `!<pattern>` to leave some out. All of
+        /// them if none, see `$MODELS`.
+        #[structopt(long, env = config::MODELS, use_delimiter = true)]
+        model: Vec<String>,
+    },
+    /// Cross validation RMSE of models.
+    Evaluate {
+        /// Models or configurations of the experiment to evaluate, by name,
+        /// glob, `tag:<tag>` or `!<pattern>` to leave some out. All of
+        /// them if none, see `$MODELS`.
+        #[structopt(long, env = config::MODELS, use_delimiter = true)]
+        model: Vec<String>,
+    },
+    /// Predict the ratings of the test data.
+    Predict {
+        /// Models or configurations of the experiment to predict with, by name,
+        /// glob, `tag:<tag>` or `!<pattern>` to leave some out. All of
+        /// them if none, see `$MODELS`.
+        #[structopt(long, env = config::MODELS, use_delimiter = true)]
+        model: Vec<String>,
+        /// `plain`, `submission`, `csv` or `jsonl`.
+        #[structopt(long, env = config::OUTPUT_FORMAT, default_value = "plain")]
+        format: OutputFormat,
+    },
+    /// Movies a customer has not rated, best predicted first.
+    Recommend {
+        /// Original id of the customer.
+        #[structopt(long)]
+        customer: usize,
+        #[structopt(long)]
+        model: String,
+        /// # of movies.
+        #[structopt(long, default_value = "10")]
+        top: usize,
+    },
+    /// Fold new ratings into a trained model without retraining it, and
+    /// save it to the model directory if any.
+    Update {
+        #[structopt(long)]
+        model: String,
+        /// CSV of `movie_id,customer_id,rating,date` with a header line and
+        /// the ids of the data files. Customers missing from the data are
+        /// new ones.
+        #[structopt(long, parse(from_os_str))]
+        ratings: PathBuf,
+    },
+    /// Search hyperparameters as declared by the experiment file, and write
+    /// a leaderboard and the best configuration of every search.
+    Tune {
+        /// Searches to run, all of them if none.
+        #[structopt(long)]
+        search: Vec<String>,
+    },
+    /// Tabulate the runs recorded in `--runs`.
+    Compare {
+        /// Only this model or configuration.
+        #[structopt(long)]
+        model: Option<String>,
+    },
+    /// Registered models and their tags, and the configurations of the
+    /// experiment file if any.
+    ListModels,
+}
+
+/// `--data`, or else `$DATA_PATH`, or else `$RECOMMEND_HOME/data`.
+fn data_path(opt: &Opt) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(path) = &opt.data {
+        return Ok(path.clone());
+    }
+    match env::var(config::DATA_PATH) {
+        Ok(val) => Ok(PathBuf::from(val)),
+        Err(_) => {
+            warn!(
+                "${} not set, using ${}/data/ as default.",
+                config::DATA_PATH,
+                config::RECOMMEND_HOME
+            );
+            match env::var(config::RECOMMEND_HOME) {
+                Ok(val) => Ok(Path::new(&val).join("data")),
+                Err(_) => Err(format!(
+                    "Neither --data, ${} nor ${} is set.",
+                    config::DATA_PATH,
+                    config::RECOMMEND_HOME
+                )
+                .into()),
+            }
+        }
+    }
+}
+
+/// The configurations of the experiment file if any, followed by the
+/// registered models they do not shadow, with their default
+/// hyperparameters.
+fn available_configs(opt: &Opt) -> Result<Vec<ModelConfig>, Box<dyn Error>> {
+    let mut configs = match &opt.experiment {
+        Some(path) => Experiment::from_file(path)?.models,
+        None => vec![],
+    };
+    for name in ModelHolder::names() {
+        if !configs.iter().any(|config| config.name == name) {
+            configs.push(ModelConfig::default_for(name));
+        }
+    }
+    Ok(configs)
+}
+
+/// The configurations selected by `patterns`, see `experiment::select`.
+/// Every configuration of the experiment, or every registered model, if
+/// empty. All of them are checked, so that a typo fails before loading the
+/// data.
+fn model_configs(opt: &Opt, patterns: &[String]) -> Result<Vec<ModelConfig>, Box<dyn Error>> {
+    let available = available_configs(opt)?;
+    let defaults: Vec<ModelConfig> = match &opt.experiment {
+        Some(path) => Experiment::from_file(path)?.models,
+        None => available.clone(),
+    };
+    let configs = experiment::select(patterns, &available, &defaults)?;
+    for config in &configs {
+        ModelHolder::from_config(config)?;
+    }
+    Ok(configs)
+}
+
+/// The one configuration selected by `pattern`.
+fn model_config(opt: &Opt, pattern: &str) -> Result<ModelConfig, Box<dyn Error>> {
+    let mut configs = model_configs(opt, &[pattern.to_string()])?;
+    if configs.len() > 1 {
+        let names: Vec<&str> = configs.iter().map(|c| c.name.as_str()).collect();
+        return Err(format!("{:?} selects {}, pick one", pattern, names.join(", ")).into());
+    }
+    Ok(configs.remove(0))
+}
+
+/// Where the model of `config` is saved, if there is a model directory.
+fn model_path(opt: &Opt, config: &ModelConfig) -> Option<PathBuf> {
+    opt.model_dir
+        .as_ref()
+        .map(|dir| dir.join(format!("{}.model", config.name)))
+}
+
+/// Write the hyperparameters `model` actually ran with, defaults included,
+/// to `<name>.config.json` next to its results.
+fn dump_config(
+    opt: &Opt,
+    config: &ModelConfig,
+    model: &dyn Model,
+    tracker: &mut Tracker,
+) -> Result<(), Box<dyn Error>> {
+    let effective = ModelConfig {
+        params: model.config(),
+        ..config.clone()
+    };
+    let path = opt.output.join(format!("{}.config.json", config.name));
+    fs::write(&path, serde_json::to_string_pretty(&effective)?)?;
+    info!("Configuration of {} written to {:?}", config.name, path);
+    tracker.artifact(Some(config), &path)
+}
+
+/// Initialize and train a new model, save it, and plot its movie embedding
+/// if it has one. Cold customers and movies are left to `--cold-start`.
+fn train(
+    opt: &Opt,
+    config: &ModelConfig,
+    data: &Data,
+    tracker: &mut Tracker,
+) -> Result<(Box<dyn Model>, Provenance), Box<dyn Error>> {
+    let mut model = ModelHolder::from_config(config)?;
+    let (elapsed, _) = measure_time(|| {
+        model.init(data);
+    });
+    tracker.timing(config, "init", tracking::seconds(&elapsed));
+    let (elapsed, _) = measure_time(|| {
+        model.train();
+    });
+    tracker.timing(config, "train", tracking::seconds(&elapsed));
+    tracker.trained(config, model.as_ref());
+    let provenance = Provenance::new(data);
+    if let Some(path) = model_path(opt, config) {
+        if let Err(err) = persist::save_model(model.as_ref(), &provenance, &path) {
+            warn!("Cannot save {}: {}", config.name, err);
+        }
+    }
+    if let Some(embedding) = model.movie_embedding() {
+        let name = &config.name;
+        let path = opt.output.join(format!("{}_movies.png", name));
+        match plot::plot_movie_embedding(
+            data,
+            embedding,
+            &format!("{} movie embedding", name),
+            &path,
+        ) {
+            Ok(()) => tracker.artifact(Some(config), &path)?,
+            Err(err) => error!("Cannot plot movie embedding: {}", err),
+        }
+    }
+    Ok((
+        ColdStart::wrap(model, &opt.cold_start, data, &[]),
+        provenance,
+    ))
+}
+
+/// The saved model if it matches `data` and `config`, with the ratings it
+/// was updated with, otherwise a newly trained one.
+fn trained(
+    opt: &Opt,
+    config: &ModelConfig,
+    data: &Data,
+    tracker: &mut Tracker,
+) -> Result<(Box<dyn Model>, Provenance), Box<dyn Error>> {
+    let expected = ModelHolder::from_config(config)?;
+    let (elapsed, saved) = measure_time(|| {
+        model_path(opt, config)
+            .filter(|path| path.exists())
+            .and_then(
+                |path| match persist::load_model(&path, data.fingerprint()) {
+                    Ok((model, provenance))
+                        if model.get_name() == expected.get_name()
+                            && model.config() == expected.config() =>
+                    {
+                        Some((model, provenance))
+                    }
+                    Ok(_) => {
+                        warn!("{:?} has another configuration, retraining", path);
+                        None
+                    }
+                    Err(err) => {
+                        warn!("Cannot load saved model, retraining: {}", err);
+                        None
+                    }
+                },
+            )
+    });
+    match saved {
+        Some((model, provenance)) => {
+            tracker.timing(config, "load", tracking::seconds(&elapsed));
+            tracker.trained(config, model.as_ref());
+            let model = ColdStart::wrap(model, &opt.cold_start, data, &provenance.updates);
+            Ok((model, provenance))
+        }
+        None => train(opt, config, data, tracker),
+    }
+}
+
+/// Print the cross validation RMSE of `model`, whatever the log level.
+fn print_rmse(config: &ModelConfig, model: &dyn Model, data: &Data, tracker: &mut Tracker) {
+    let rmse = rmse(model, data.cross_valid());
+    println!("{} cross validation RMSE: {:.4}", config.name, rmse);
+    tracker.metric(config, "rmse", rmse);
+}
+
+fn stats(data: &Data) {
+    let metadata = data.metadata();
+    let (num_trans, num_tests) = metadata
+        .trans_freq
+        .iter()
+        .zip(metadata.tests_freq.iter())
+        .fold((0, 0), |(num_trans, num_tests), (&trans, &tests)| {
+            (num_trans + trans, num_tests + tests)
+        });
+    println!(
+        "# of customers: {}, # of movies: {}",
+        metadata.num_customers, metadata.num_movies
+    );
+    println!(
+        "# of transactions: {} ({} for training, {} for cross validation), # of tests: {}",
+        num_trans, metadata.num_train, metadata.num_cross_valid, num_tests
+    );
+    println!("Rating scale: {}", data.scale);
+}
+
+/// The ratings of `file`, in the layout of `train.csv`, with the virtual
+/// movie ids of `data` and the original customer ids, see
+/// `Provenance::fold_in`. Ratings of movies missing from `data` are left out.
+fn read_new_ratings(data: &Data, file: &Path) -> Result<Vec<Transaction>, Box<dyn Error>> {
+    let mut rejects = Rejects::new(config::from_env(config::PARSE_MODE, ParseMode::Strict));
+    let transactions =
+        Transaction::from_csv_where(file.to_path_buf(), &mut rejects, |t| match t.rating {
+            Some(_) => Ok(()),
+            None => Err(FieldError::Missing { column: 2 }),
+        })?;
+    rejects.log_summary();
+    let total = transactions.len();
+    let new: Vec<Transaction> = transactions
+        .into_iter()
+        .filter_map(|t| {
+            let movie_id = data.movie_ids.to_virtual(t.movie_id)?;
+            Some(Transaction { movie_id, ..t })
+        })
+        .collect();
+    info!(
+        "{} new ratings, {} of them of unknown movies left out",
+        total,
+        total - new.len()
+    );
+    Ok(new)
+}
+
+/// The `top` movies `customer` has not rated, in `data` or in `updates`, by
+/// decreasing prediction, or the most popular ones if `customer` has not
+/// rated any.
+fn recommend(
+    model: &dyn Model,
+    data: &Data,
+    updates: &[Transaction],
+    customer: usize,
+    top: usize,
+) -> Vec<(usize, f32)> {
+    let history: Vec<&Transaction> = data
+        .train()
+        .iter()
+        .chain(data.cross_valid())
+        .chain(updates)
+        .filter(|t| t.customer_id == customer)
+        .collect();
+    let num_movies = data.movie_ids.len();
+    let mut rated = vec![false; num_movies];
+    history.iter().for_each(|t| rated[t.movie_id] = true);
+    let date = history.iter().map(|t| t.date).max().unwrap_or(0);
+    if history.is_empty() {
+        info!("No ratings of the customer, recommending the most popular movies");
+        let popular: Vec<Transaction> = Priors::new(data, updates)
+            .popular_movies()
+            .into_iter()
+            .take(top)
+            .map(|movie_id| Transaction {
+                movie_id,
+                customer_id: customer,
+                rating: None,
+                date,
+            })
+            .collect();
+        let predictions = model.predict_all(&popular);
+        return popular
+            .iter()
+            .map(|t| t.movie_id)
+            .zip(predictions)
+            .collect();
+    }
+    let candidates: Vec<Transaction> = (0..num_movies)
+        .filter(|&j| !rated[j])
+        .map(|movie_id| Transaction {
+            movie_id,
+            customer_id: customer,
+            rating: None,
+            date,
+        })
+        .collect();
+    let mut predictions: Vec<(usize, f32)> = candidates
+        .iter()
+        .map(|t| t.movie_id)
+        .zip(model.predict_all(&candidates))
+        .collect();
+    predictions.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+    predictions.truncate(top);
+    predictions
+}
+
+/// Run the command of `opt`, and record it if it is tracked and there is
+/// a `--runs` folder.
+pub fn run(opt: &Opt) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&opt.output)?;
+    if let Some(dir) = &opt.model_dir {
+        fs::create_dir_all(dir)?;
+    }
+    let tracked = matches!(
+        opt.command,
+        Command::Train { .. }
+            | Command::Evaluate { .. }
+            | Command::Predict { .. }
+            | Command::Update { .. }
+            | Command::Tune { .. }
+    );
+    let mut tracker = match &opt.runs {
+        Some(dir) if tracked => Tracker::start(dir)?,
+        _ => Tracker::disabled(),
+    };
+    tracker.cold_start(&opt.cold_start);
+    let result = execute(opt, &mut tracker);
+    // The error of the command matters more than that of its record.
+    if let Err(err) = tracker.finish(&result) {
+        error!("Cannot record the run: {}", err);
+    }
+    result
+}
+
+fn execute(opt: &Opt, tracker: &mut Tracker) -> Result<(), Box<dyn Error>> {
+    let load_data = |tracker: &mut Tracker| -> Result<Data, Box<dyn Error>> {
+        let path = data_path(opt)?;
+        let data = Data::new(path.clone())?;
+        tracker.data(&path, &data);
+        Ok(data)
+    };
+    match &opt.command {
+        Command::ListModels => {
+            for config in available_configs(opt)? {
+                let holder = ModelHolder::find(&config.model);
+                let tags = holder.map_or(&[][..], |h| h.get_tags()).join(", ");
+                if config.name == config.model {
+                    println!("{:<24}[{}]", config.name, tags);
+                } else {
+                    println!("{:<24}{} [{}]", config.name, config.model, tags);
+                }
+            }
+        }
+        Command::Compare { model } => {
+            let dir = opt
+                .runs
+                .as_ref()
+                .ok_or("No runs to compare without --runs")?;
+            let runs = tracking::load_runs(dir)?;
+            let rows = tracking::compare(&runs, model.as_deref());
+            print!("{}", tracking::format_table(&rows));
+        }
+        Command::Stats => stats(&load_data(tracker)?),
+        Command::Plot => {
+            let data = load_data(tracker)?;
+            plot::plot_data_freq(data.metadata(), &opt.output)?;
+            plot::plot_initial_matrix(&data, &opt.output)?;
+            info!("Initial matrix plotted.");
+        }
+        Command::Report => report::movie_clusters(&load_data(tracker)?, &opt.output)?,
+        Command::Train { model } => {
+            let configs = model_configs(opt, model)?;
+            let data = load_data(tracker)?;
+            for config in &configs {
+                let (model, _) = train(opt, config, &data, tracker)?;
+                print_rmse(config, model.as_ref(), &data, tracker);
+                dump_config(opt, config, model.as_ref(), tracker)?;
+            }
+        }
+        Command::Evaluate { model } => {
+            let configs = model_configs(opt, model)?;
+            let data = load_data(tracker)?;
+            for config in &configs {
+                let (model, _) = trained(opt, config, &data, tracker)?;
+                print_rmse(config, model.as_ref(), &data, tracker);
+                dump_config(opt, config, model.as_ref(), tracker)?;
+            }
+        }
+        Command::Predict { model, format } => {
+            let configs = model_configs(opt, model)?;
+            let data = load_data(tracker)?;
+            for config in &configs {
+                let (model, _) = trained(opt, config, &data, tracker)?;
+                let (elapsed, predictions) = measure_time(|| model.predict_all(&data.test_data));
+                tracker.timing(config, "predict", tracking::seconds(&elapsed));
+                dump_config(opt, config, model.as_ref(), tracker)?;
+                let path = format.write_file(
+                    &opt.output,
+                    &config.name,
+                    &data,
+                    &data.test_data,
+                    &predictions,
+                )?;
+                tracker.artifact(Some(config), &path)?;
+            }
+        }
+        Command::Update { model, ratings } => {
+            let config = model_config(opt, model)?;
+            let data = load_data(tracker)?;
+            let (mut model, mut provenance) = trained(opt, &config, &data, tracker)?;
+            let known = provenance.new_customers.len();
+            let new = provenance.fold_in(&data, read_new_ratings(&data, ratings)?);
+            info!("{} new customers", provenance.new_customers.len() - known);
+            let before = rmse(model.as_ref(), &new);
+            let (elapsed, updated) = measure_time(|| model.update(&new));
+            updated?;
+            tracker.timing(&config, "update", tracking::seconds(&elapsed));
+            info!(
+                "{} RMSE on the new ratings: {:.4} before the update, {:.4} after, elapsed: {}",
+                config.name,
+                before,
+                rmse(model.as_ref(), &new),
+                elapsed
+            );
+            print_rmse(&config, model.as_ref(), &data, tracker);
+            match model_path(opt, &config) {
+                Some(path) => persist::save_model(model.as_ref(), &provenance, &path)?,
+                None => warn!("Without --model-dir the update is not kept"),
+            }
+        }
+        Command::Tune { search } => {
+            let path = opt
+                .experiment
+                .as_ref()
+                .ok_or("Searches are declared in the experiment file, see --experiment")?;
+            let mut searches = Experiment::from_file(path)?.searches;
+            if !search.is_empty() {
+                if let Some(name) = search
+                    .iter()
+                    .find(|&name| !searches.iter().any(|s| &s.name == name))
+                {
+                    return Err(format!("No search {:?} in {:?}", name, path).into());
+                }
+                searches.retain(|s| search.contains(&s.name));
+            }
+            for s in &searches {
+                s.candidates()?;
+            }
+            let data = load_data(tracker)?;
+            for s in &searches {
+                let trials = search::run(s, &data, &opt.cold_start)?;
+                for trial in &trials {
+                    tracker.timing(&trial.config, "train", trial.seconds);
+                    tracker.metric(&trial.config, "rmse", trial.rmse);
+                }
+                for path in search::write_results(s, &trials, &opt.output)? {
+                    tracker.artifact(None, &path)?;
+                }
+            }
+        }
+        Command::Recommend {
+            customer,
+            model,
+            top,
+        } => {
+            let config = model_config(opt, model)?;
+            let data = load_data(tracker)?;
+            let (model, provenance) = trained(opt, &config, &data, tracker)?;
+            let mut customers = provenance.customer_ids(&data);
+            if customers.to_virtual(*customer).is_none() {
+                warn!(
+                    "No customer {} in the data, taking them as a new one",
+                    customer
+                );
+            }
+            let virtual_id = customers.get_or_insert(*customer);
+            let titles: HashMap<usize, _> = data.movies.iter().map(|m| (m.movie_id, m)).collect();
+            for (rank, (movie_id, prediction)) in
+                recommend(model.as_ref(), &data, &provenance.updates, virtual_id, *top)
+                    .into_iter()
+                    .enumerate()
+            {
+                let title = titles.get(&movie_id).map_or_else(
+                    || "<unknown movie>".to_string(),
+                    |movie| format!("{} ({})", movie.title, movie.year_produced),
+                );
+                println!(
+                    "{}. {} {}: {}",
+                    rank + 1,
+                    data.movie_ids.to_original(movie_id),
+                    title,
+                    prediction
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::test::two_tastes_raw;
+
+    #[test]
+    fn test_parse() {
+        let opt = Opt::from_iter(&[
+            "recommend-netflix",
+            "--data",
+            "netflix",
+            "recommend",
+            "--customer",
+            "6",
+            "--model",
+            "PureSvd",
+        ]);
+        assert!(opt.data == Some(PathBuf::from("netflix")));
+        match opt.command {
+            Command::Recommend {
+                customer,
+                model,
+                top,
+            } => assert!(customer == 6 && model == "PureSvd" && top == 10),
+            command => panic!("{:?}", command),
+        }
+        let opt = Opt::from_iter(&[
+            "recommend-netflix",
+            "update",
+            "--model",
+            "PureSvd",
+            "--ratings",
+            "new.csv",
+        ]);
+        assert!(
+            matches!(opt.command, Command::Update { ratings, .. } if ratings == Path::new("new.csv"))
+        );
+        let parse = |args: &[&str]| Opt::from_iter_safe(args).map(|_| ());
+        assert!(parse(&["recommend-netflix", "recommend", "--model", "PureSvd"]).is_err());
+        assert!(parse(&[
+            "recommend-netflix",
+            "recommend",
+            "--customer",
+            "x",
+            "--model",
+            "PureSvd"
+        ])
+        .is_err());
+        assert!(parse(&["recommend-netflix", "update", "--model", "PureSvd"]).is_err());
+        assert!(parse(&["recommend-netflix", "fit"]).is_err());
+    }
+
+    #[test]
+    fn test_model_configs() {
+        let opt = Opt::from_iter(&["recommend-netflix", "list-models"]);
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let configs = model_configs(&opt, &names(&["PureSvd", "Mixture"])).unwrap();
+        assert!(
+            configs.iter().map(|c| c.name.as_str()).collect::<Vec<_>>()
+                == vec!["PureSvd", "Mixture"]
+        );
+        assert!(
+            model_configs(&opt, &[]).unwrap().len()
+                == inventory::iter::<ModelHolder>.into_iter().count()
+        );
+        assert!(model_configs(&opt, &names(&["PureSvd", "NoSuchModel"])).is_err());
+    }
+
+    #[test]
+    fn test_model_config() {
+        let opt = Opt::from_iter(&["recommend-netflix", "list-models"]);
+        assert!(model_config(&opt, "PureSvd").unwrap().name == "PureSvd");
+        assert!(model_config(&opt, "Pure*").unwrap().name == "PureSvd");
+        // `update` and `recommend` take a single model.
+        assert!(model_config(&opt, "*").is_err());
+        assert!(model_config(&opt, "NoSuchModel").is_err());
+    }
+
+    #[test]
+    fn test_recommend() {
+        let mut raw = two_tastes_raw(8, 12);
+        // Customer 0 likes the even movies and has not rated 2, 3 and 4.
+        raw.transactions
+            .retain(|t| t.customer_id != 0 || t.movie_id < 2 || t.movie_id > 4);
+        let data = Data::from_raw(raw);
+        let mut model = ModelHolder::find("ItemKnn").unwrap().get_model();
+        model.init(&data).train();
+        let movies = |recommended: Vec<(usize, f32)>| -> Vec<usize> {
+            recommended
+                .into_iter()
+                .map(|(movie_id, _)| movie_id)
+                .collect()
+        };
+        assert!(movies(recommend(model.as_ref(), &data, &[], 0, 10)) == vec![2, 4, 3]);
+        assert!(movies(recommend(model.as_ref(), &data, &[], 0, 1)) == vec![2]);
+        assert!(recommend(model.as_ref(), &data, &[], 1, 10).is_empty());
+        // A new customer gets popular movies.
+        assert!(recommend(model.as_ref(), &data, &[], 8, 3).len() == 3);
+        // Folded in ratings count as rated.
+        let update = Transaction {
+            movie_id: 2,
+            customer_id: 0,
+            rating: Some(5f32),
+            date: 12000,
+        };
+        assert!(movies(recommend(model.as_ref(), &data, &[update], 0, 10)) == vec![4, 3]);
+    }
+
+    #[test]
+    fn test_read_new_ratings() {
+        let data = Data::from_raw(two_tastes_raw(8, 6));
+        let file = std::env::temp_dir().join(format!("new_ratings_{}.csv", std::process::id()));
+        fs::write(
+            &file,
+            "movie,customer,rating,date\n\
+             1,100,5,2005-09-06\n\
+             9,100,5,2005-09-06\n\
+             2,200,1,2005-09-06\n",
+        )
+        .unwrap();
+        let new = read_new_ratings(&data, &file).unwrap();
+        // Unknown movies are left out, customers keep their original ids.
+        assert!(new.iter().map(|t| t.customer_id).collect::<Vec<_>>() == vec![100, 200]);
+        fs::write(&file, "movie,customer,rating,date\n1,100,,2005-09-06\n").unwrap();
+        assert!(read_new_ratings(&data, &file).is_err());
+        fs::remove_file(&file).unwrap();
+        assert!(read_new_ratings(&data, &file).is_err());
+    }
+}
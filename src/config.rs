@@ -1,11 +1,162 @@
+use log::warn;
+use std::{
+    env,
+    fmt::{Debug, Display},
+    str::FromStr,
+};
+
 /// The path to the project
 pub const RECOMMEND_HOME: &str = "RECOMMEND_HOME";
 
 /// The path to the folder who holds all data files.
 pub const DATA_PATH: &str = "DATA_PATH";
 
+/// Name of the similarity measure used by models that compare items,
+/// e.g. `pearson`, `jaccard` or `shrunk_adjusted_cosine`.
+pub const SIMILARITY: &str = "SIMILARITY";
+
+/// Affinity graph of spectral clustering, `knn:<k>`, `epsilon:<epsilon>`
+/// or `gaussian:<sigma>`.
+pub const SPECTRAL_GRAPH: &str = "SPECTRAL_GRAPH";
+
+/// Laplacian of spectral clustering, `unnormalized`, `symmetric` or `random_walk`.
+pub const SPECTRAL_LAPLACIAN: &str = "SPECTRAL_LAPLACIAN";
+
+/// Linkage of the hierarchical clustering of movies, `single`, `complete`,
+/// `average` or `ward`.
+pub const HIERARCHICAL_LINKAGE: &str = "HIERARCHICAL_LINKAGE";
+
+/// # of flat clusters cut from the dendrogram of movies.
+pub const HIERARCHICAL_CLUSTERS: &str = "HIERARCHICAL_CLUSTERS";
+
+/// Only the most rated movies are clustered hierarchically, as it needs
+/// all pairwise distances.
+pub const HIERARCHICAL_MOVIES: &str = "HIERARCHICAL_MOVIES";
+
+/// How movie embeddings are brought down to 2-D for plotting, `pca` or `tsne`.
+pub const EMBEDDING_PROJECTION: &str = "EMBEDDING_PROJECTION";
+
+/// Only the most rated movies are put on embedding plots.
+pub const EMBEDDING_MOVIES: &str = "EMBEDDING_MOVIES";
+
+/// Folder where trained models are saved as `<model-name>.model`, and
+/// loaded from instead of retraining if they match the dataset.
+pub const MODEL_DIR: &str = "MODEL_DIR";
+
+/// `.toml` or `.json` file of named model configurations, whose names
+/// can be used wherever a model name is expected, see
+/// `models::experiment`.
+pub const EXPERIMENT: &str = "EXPERIMENT";
+
+/// Folder where every run of `train`, `evaluate`, `predict` and `tune` is
+/// recorded in a directory of its own, see `tracking`. Runs are not
+/// recorded if unset.
+pub const RUNS_DIR: &str = "RUNS_DIR";
+
+/// Comma separated models or configurations run by `train`, `evaluate`
+/// and `predict` when no `--model` is given, by name, glob such as `*Knn`,
+/// `tag:<tag>`, or `!<pattern>` to leave some out, e.g. `!tag:slow`.
+pub const MODELS: &str = "MODELS";
+
+/// Layout of the files in `DATA_PATH`, `netflix_csv`, `netflix_prize`,
+/// `movielens` or `delimited`. Guessed from the files if unset.
+pub const DATA_FORMAT: &str = "DATA_FORMAT";
+
+/// What to do with rows of the data files that cannot be parsed, fail
+/// with `strict` or skip them with `lenient`.
+pub const PARSE_MODE: &str = "PARSE_MODE";
+
+/// Rating file of the `delimited` data format, relative to `DATA_PATH`.
+pub const DELIMITED_RATINGS: &str = "DELIMITED_RATINGS";
+
+/// Optional test file of the `delimited` data format, whose rating column
+/// is ignored.
+pub const DELIMITED_TEST: &str = "DELIMITED_TEST";
+
+/// Field delimiter of the `delimited` data format, one character or `tab`.
+pub const DELIMITED_SEPARATOR: &str = "DELIMITED_SEPARATOR";
+
+/// Whether files of the `delimited` data format start with a header line.
+pub const DELIMITED_HEADER: &str = "DELIMITED_HEADER";
+
+/// Comma separated meaning of each column of the `delimited` data format,
+/// `customer`, `movie`, `rating`, `date` or `_` to skip one. Dates are
+/// `YYYY-MM-DD` or unix timestamps.
+pub const DELIMITED_COLUMNS: &str = "DELIMITED_COLUMNS";
+
+/// Ratings of the `delimited` data format, `<min>:<max>` if continuous or
+/// `<min>:<max>:<step>`, e.g. `0.5:5:0.5` for half stars.
+pub const DELIMITED_SCALE: &str = "DELIMITED_SCALE";
+
+/// How predictions are written to `<model-name>.<extension>`, `plain`
+/// (one per line), `submission` (the Netflix Prize layout), `csv` or
+/// `jsonl`, the last two with the original movie and customer ids.
+pub const OUTPUT_FORMAT: &str = "OUTPUT_FORMAT";
+
+/// # of transactions per chunk when models predict in parallel, progress
+/// is logged as chunks complete.
+pub const PREDICT_CHUNK: &str = "PREDICT_CHUNK";
+
+/// Comma separated fallbacks predicting customers and movies without a
+/// training rating, `customer_bias`, `movie_mean`, `year_prior` or
+/// `global_mean`, or `none` to leave them to the models, see
+/// `models::cold_start`.
+pub const COLD_START: &str = "COLD_START";
+
 /// Training data;
 pub const TRAINING_DATA: &str = "train.csv";
 
+/// Test data, whose ratings are to be predicted.
+pub const TEST_DATA: &str = "test.csv";
+
+/// Movie ids, years and titles.
+pub const MOVIE_TITLES: &str = "movie_titles.csv";
+
+/// Binary cache of the dataset, written next to its files.
+pub const DATA_CACHE: &str = "data.cache";
+
 /// Rust log
 pub const RUST_LOG: &str = "RUST_LOG";
+
+/// Parse the environment variable `key`, `default` if it is not set or
+/// cannot be parsed.
+pub fn from_env<T>(key: &str, default: T) -> T
+where
+    T: FromStr + Debug,
+    T::Err: Display,
+{
+    match env::var(key).map(|val| val.parse::<T>()) {
+        Ok(Ok(val)) => val,
+        Ok(Err(err)) => {
+            warn!("${} is invalid: {}, using {:?}", key, err, default);
+            default
+        }
+        Err(_) => default,
+    }
+}
+
+/// (De)serialize a field through its `Display` and `FromStr`, so that it
+/// reads like the environment variable, e.g. `knn:50` rather than
+/// `{"Knn": 50}`. Use with `#[serde(with = "config::by_str")]`.
+pub mod by_str {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::{fmt::Display, str::FromStr};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
@@ -1,36 +1,297 @@
 use log::{info, warn};
-use nalgebra::core::DMatrix;
+use nalgebra::core::{DMatrix, DVector};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
     error::Error,
-    fmt::Debug,
+    fmt::{self, Debug, Display},
     path::PathBuf,
+    str::FromStr,
 };
 
+use crate::cache;
 use crate::config;
-use crate::io::FromCsv;
+use crate::io;
+
+/// A known rating, on the `RatingScale` of the dataset.
+pub type Rating = f32;
+
+/// Days since 1970-01-01, which lasts until 2149.
+pub type Day = u16;
+
+/// Parse a `YYYY-MM-DD` date into a `Day`.
+pub fn parse_day(date: &str) -> Result<Day, Box<dyn Error>> {
+    let mut fields = date.trim().splitn(3, '-');
+    let mut next = || -> Result<i64, Box<dyn Error>> {
+        Ok(fields
+            .next()
+            .ok_or_else(|| format!("Invalid date: {:?}", date))?
+            .parse()?)
+    };
+    let (y, m, d) = (next()?, next()?, next()?);
+    let leap = y % 4 == 0 && (y % 100 != 0 || y % 400 == 0);
+    let days_in_month = match m {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&m) || !(1..=days_in_month).contains(&d) {
+        return Err(format!("Invalid date: {:?}", date).into());
+    }
+    // Howard Hinnant's days_from_civil, years start in March so that the
+    // leap day is the last one.
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    if days < 0 || days > Day::MAX as i64 {
+        return Err(format!("Date out of range: {:?}", date).into());
+    }
+    Ok(days as Day)
+}
+
+/// Format a `Day` as `YYYY-MM-DD`, the inverse of `parse_day`.
+pub fn format_day(day: Day) -> String {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
 
-pub type Rating = u8;
 /// `Transaction` is a customer's behavior.config
 ///
 /// `Transcction` consists of the `movie_id` he bought,
 /// `customer_id` to tell us who he is, his `rating`
-/// (between 1 and 5 inclusive), and `date`
+/// (on the `RatingScale` of the dataset), and `date`
+/// as a `Day`.
 ///
-/// If 'rating' is 0 then this `Transaction` is in test set.
-#[derive(Debug, Clone)]
+/// If `rating` is `None` then this `Transaction` is in test set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     pub movie_id: usize,
     pub customer_id: usize,
-    pub rating: Rating,
-    pub date: String,
+    pub rating: Option<Rating>,
+    pub date: Day,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Movie {
     pub movie_id: usize,
     pub year_produced: u16,
     pub title: String,
+    /// Empty if the dataset has none, like Netflix.
+    pub genres: Vec<String>,
+}
+
+/// Two way mapping between the ids of the data files and the dense virtual
+/// ids counting from 0 that everything else uses.
+#[derive(Clone, Default)]
+pub struct IdMapper {
+    originals: Vec<usize>,
+    virtual_ids: HashMap<usize, usize>,
+}
+
+impl IdMapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Virtual id `i` stands for `originals[i]`, which must be distinct.
+    pub fn from_originals(originals: Vec<usize>) -> Self {
+        let virtual_ids = originals
+            .iter()
+            .enumerate()
+            .map(|(i, &original)| (original, i))
+            .collect();
+        Self {
+            originals,
+            virtual_ids,
+        }
+    }
+
+    /// The virtual id of `original`, the next one if it is new.
+    pub fn get_or_insert(&mut self, original: usize) -> usize {
+        let originals = &mut self.originals;
+        *self.virtual_ids.entry(original).or_insert_with(|| {
+            originals.push(original);
+            originals.len() - 1
+        })
+    }
+
+    pub fn to_virtual(&self, original: usize) -> Option<usize> {
+        self.virtual_ids.get(&original).copied()
+    }
+
+    /// Panics if `virtual_id` was never given out.
+    pub fn to_original(&self, virtual_id: usize) -> usize {
+        self.originals[virtual_id]
+    }
+
+    /// Original id of every virtual id.
+    pub fn originals(&self) -> &[usize] {
+        &self.originals
+    }
+
+    pub fn len(&self) -> usize {
+        self.originals.len()
+    }
+}
+
+impl Debug for IdMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("IdMapper").field(&self.originals).finish()
+    }
+}
+
+impl PartialEq for IdMapper {
+    fn eq(&self, other: &Self) -> bool {
+        self.originals == other.originals
+    }
+}
+
+/// # of levels continuous rating scales are binned into, where discrete
+/// ratings are needed.
+const CONTINUOUS_LEVELS: usize = 10;
+
+/// Ratings of a dataset range from `min` to `max` inclusive, by `step` if
+/// they are discrete. `min` must be positive, as a 0 is a missing entry of
+/// a `SparseMatrix`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RatingScale {
+    pub min: f64,
+    pub max: f64,
+    pub step: Option<f64>,
+}
+
+impl Default for RatingScale {
+    fn default() -> Self {
+        Self::NETFLIX
+    }
+}
+
+impl RatingScale {
+    /// 1 to 5 stars.
+    pub const NETFLIX: Self = Self {
+        min: 1f64,
+        max: 5f64,
+        step: Some(1f64),
+    };
+    /// 0.5 to 5 stars by halves.
+    pub const HALF_STARS: Self = Self {
+        min: 0.5,
+        max: 5f64,
+        step: Some(0.5),
+    };
+
+    pub fn new(min: f64, max: f64, step: Option<f64>) -> Result<Self, Box<dyn Error>> {
+        let scale = Self { min, max, step };
+        if !(min < max && min.is_finite() && max.is_finite()) {
+            return Err(format!("Rating scale {} is not min < max", scale).into());
+        }
+        if let Some(step) = step {
+            // `max` has to be a rating of the scale.
+            let steps = (max - min) / step;
+            if !(step > 0f64 && step <= max - min && (steps - steps.round()).abs() < 1e-6) {
+                return Err(format!("Rating scale {} has an invalid step", scale).into());
+            }
+        }
+        Ok(scale)
+    }
+
+    /// Whether `value` is a rating of this scale.
+    pub fn contains(self, value: f64) -> bool {
+        (self.min..=self.max).contains(&value)
+            && self.step.is_none_or(|step| {
+                let steps = (value - self.min) / step;
+                (steps - steps.round()).abs() < 1e-6
+            })
+    }
+
+    /// Parse a rating, which has to be on this scale.
+    pub fn parse(self, s: &str) -> Result<Rating, Box<dyn Error>> {
+        let value: f64 = s.trim().parse()?;
+        if !self.contains(value) {
+            return Err(format!("{} is not a rating of {}", value, self).into());
+        }
+        Ok(value as Rating)
+    }
+
+    /// The closest rating to a real valued prediction.
+    pub fn clamp(self, value: f64) -> Rating {
+        let value = if value.is_nan() {
+            self.middle()
+        } else {
+            value.clamp(self.min, self.max)
+        };
+        match self.step {
+            Some(step) => {
+                (self.min + ((value - self.min) / step).round() * step).min(self.max) as Rating
+            }
+            None => value as Rating,
+        }
+    }
+
+    /// Half way between `min` and `max`.
+    pub fn middle(self) -> f64 {
+        (self.min + self.max) / 2f64
+    }
+
+    /// # of distinct ratings, `CONTINUOUS_LEVELS` for continuous scales.
+    pub fn num_levels(self) -> usize {
+        ((self.max - self.min) / self.grid()).round() as usize + 1
+    }
+
+    /// From 0 for `min` to `num_levels() - 1` for `max`.
+    pub fn level(self, value: f64) -> usize {
+        let level = ((value.clamp(self.min, self.max) - self.min) / self.grid()).round();
+        (level as usize).min(self.num_levels() - 1)
+    }
+
+    /// The rating of a `level`.
+    pub fn level_value(self, level: usize) -> f64 {
+        (self.min + level as f64 * self.grid()).min(self.max)
+    }
+
+    fn grid(self) -> f64 {
+        self.step
+            .unwrap_or((self.max - self.min) / (CONTINUOUS_LEVELS - 1) as f64)
+    }
+}
+
+impl Display for RatingScale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.step {
+            Some(step) => write!(f, "{}:{}:{}", self.min, self.max, step),
+            None => write!(f, "{}:{}", self.min, self.max),
+        }
+    }
+}
+
+impl FromStr for RatingScale {
+    type Err = Box<dyn Error>;
+    /// `<min>:<max>` for continuous ratings, `<min>:<max>:<step>` for
+    /// discrete ones, e.g. `0.5:5:0.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bounds = s
+            .split(':')
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()?;
+        match bounds[..] {
+            [min, max] => Self::new(min, max, None),
+            [min, max, step] => Self::new(min, max, Some(step)),
+            _ => Err(format!("Rating scale {:?} is not <min>:<max>[:<step>]", s).into()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -44,74 +305,208 @@ pub struct MetaData {
 }
 
 /// `Data` holds all `Transaction`s, `Movie`s and test set,
-/// which is also in the form a `Transaction`.
+/// which is also in the form a `Transaction`. What `fingerprint` hashes
+/// can only be read, so it always describes the data.
 pub struct Data {
-    pub metadata: MetaData,
-    pub train: Vec<Transaction>,
-    pub cross_valid: Vec<Transaction>,
+    metadata: MetaData,
+    pub scale: RatingScale,
+    train: Vec<Transaction>,
+    cross_valid: Vec<Transaction>,
     pub movies: Vec<Movie>,
     pub test_data: Vec<Transaction>,
+    /// Original ids of the customers and movies, for output.
+    pub customer_ids: IdMapper,
+    pub movie_ids: IdMapper,
+    /// See `fingerprint`, hashed once on loading.
+    fingerprint: u64,
+}
+
+/// The dataset as it is on disk, before the cross validation split.
+/// Customer and movie ids are already virtual, see `customer_ids` and
+/// `movie_ids`.
+#[derive(Debug, Clone)]
+pub struct RawData {
+    pub transactions: Vec<Transaction>,
+    pub test_data: Vec<Transaction>,
+    pub movies: Vec<Movie>,
+    pub customer_ids: IdMapper,
+    pub movie_ids: IdMapper,
+    pub scale: RatingScale,
+    /// # of `transactions` at the end held out for cross validation, if
+    /// the dataset comes with its own split. Otherwise it is 20%.
+    pub num_cross_valid: Option<usize>,
+}
+
+impl RawData {
+    /// Give customers dense virtual ids, in order of their first
+    /// appearance. Movie ids are already virtual, `movie_ids` maps them
+    /// back.
+    pub fn new(
+        mut transactions: Vec<Transaction>,
+        mut test_data: Vec<Transaction>,
+        movies: Vec<Movie>,
+        movie_ids: IdMapper,
+        scale: RatingScale,
+        num_cross_valid: Option<usize>,
+    ) -> Self {
+        let mut customer_ids = IdMapper::new();
+        transactions.iter_mut().for_each(|t| {
+            t.customer_id = customer_ids.get_or_insert(t.customer_id);
+        });
+        let num_known = customer_ids.len();
+        test_data.iter_mut().for_each(|t| {
+            t.customer_id = customer_ids.get_or_insert(t.customer_id);
+        });
+        if customer_ids.len() > num_known {
+            warn!(
+                "{} customers of the test set are not in the training set, see ${}",
+                customer_ids.len() - num_known,
+                config::COLD_START
+            );
+        }
+        Self {
+            transactions,
+            test_data,
+            movies,
+            customer_ids,
+            movie_ids,
+            scale,
+            num_cross_valid,
+        }
+    }
 }
 
 impl Data {
+    /// Load the dataset from the binary cache in `path` if it is up to
+    /// date with the source files, otherwise parse them with the
+    /// `DataFormat` of `path` and rewrite the cache.
     pub fn new<P>(path: P) -> Result<Self, Box<dyn Error>>
     where
         P: Into<PathBuf> + Clone + Debug,
     {
         info!("Loading data from: {:?}", path);
-        let mut transactions =
-            Transaction::from_csv(path.clone().into().join(config::TRAINING_DATA))?;
-        let movies = Movie::from_csv(path.clone().into().join("movie_titles.csv"))?;
-        let mut test_data = Transaction::from_csv(path.clone().into().join("test.csv"))?;
-        let mut virtual_id_map = HashMap::new();
-        let mut virtual_id = 0;
-        let mut trans_freq = Vec::new();
-        transactions.iter_mut().for_each(|t| {
-            let idx = *virtual_id_map.entry(t.customer_id).or_insert_with(|| {
-                virtual_id += 1;
-                trans_freq.push(0);
-                virtual_id - 1
-            });
-            t.customer_id = idx;
-            trans_freq[idx as usize] += 1;
-        });
-        let mut tests_freq = vec![0; virtual_id as usize];
-        test_data.iter_mut().for_each(|t| {
-            let idx = *virtual_id_map.entry(t.customer_id).or_insert_with(|| {
-                warn!(
-                    "How come a customer(id: {}) is in testing set but not in training set? \
-                      Setting its virtial id to {}.",
-                    t.customer_id, virtual_id
-                );
-                virtual_id += 1;
-                trans_freq.push(0);
-                tests_freq.push(0);
-                virtual_id - 1
-            });
-            t.customer_id = idx;
-            tests_freq[idx as usize] += 1;
-        });
+        let path: PathBuf = path.into();
+        let format = io::data_format(&path)?;
+        let mode = config::from_env(config::PARSE_MODE, io::ParseMode::Strict);
+        info!("Data format: {:?}, {:?} parsing", format, mode);
+        let sources = format.sources(&path)?;
+        let cache_path = path.join(config::DATA_CACHE);
+        // Rows rejected in lenient mode are not in the cache, so it only
+        // stands for the same format and mode.
+        let checksum = cache::source_checksum(&format!("{:?} {:?}", format, mode), &sources)?;
+        let raw = match cache::load(&cache_path, checksum) {
+            Ok(raw) => raw,
+            Err(err) => {
+                info!("Not using the data cache {:?}: {}", cache_path, err);
+                let mut rejects = io::Rejects::new(mode);
+                let raw = format.read(&path, &mut rejects)?;
+                rejects.log_summary();
+                if let Err(err) = cache::save(&cache_path, &raw, checksum) {
+                    warn!("Failed to write the data cache {:?}: {}", cache_path, err);
+                }
+                raw
+            }
+        };
+        Ok(Self::from_raw(raw))
+    }
 
-        // 20% of training data is used for cross validation.
-        let num_cross_valid = transactions.len() / 5;
+    /// Count the transactions of every customer and hold out the end of
+    /// the training data for cross validation.
+    pub fn from_raw(raw: RawData) -> Self {
+        let RawData {
+            transactions,
+            test_data,
+            movies,
+            customer_ids,
+            movie_ids,
+            scale,
+            num_cross_valid,
+        } = raw;
+        let num_customers = customer_ids.len();
+        let mut trans_freq = vec![0; num_customers];
+        transactions
+            .iter()
+            .for_each(|t| trans_freq[t.customer_id] += 1);
+        let mut tests_freq = vec![0; num_customers];
+        test_data
+            .iter()
+            .for_each(|t| tests_freq[t.customer_id] += 1);
+
+        // 20% of training data is used for cross validation, unless the
+        // dataset says otherwise.
+        let num_cross_valid = num_cross_valid
+            .unwrap_or(transactions.len() / 5)
+            .min(transactions.len());
         let num_train = transactions.len() - num_cross_valid;
 
         let mut transactions: VecDeque<Transaction> = transactions.into();
 
-        Ok(Data {
+        let mut data = Data {
+            scale,
             metadata: MetaData {
-                num_customers: virtual_id as usize,
+                num_customers,
                 num_movies: movies.len(),
-                num_train: num_train,
-                num_cross_valid: num_cross_valid,
-                trans_freq: trans_freq,
-                tests_freq: tests_freq,
+                num_train,
+                num_cross_valid,
+                trans_freq,
+                tests_freq,
             },
             train: transactions.drain(0..num_train).collect(),
             cross_valid: transactions.drain(0..num_cross_valid).collect(),
-            movies: movies,
-            test_data: test_data,
-        })
+            movies,
+            test_data,
+            customer_ids,
+            movie_ids,
+            fingerprint: 0,
+        };
+        data.fingerprint = data.hash_ratings();
+        data
+    }
+}
+
+impl Data {
+    /// Shape of the dataset and # of ratings of every customer.
+    pub fn metadata(&self) -> &MetaData {
+        &self.metadata
+    }
+
+    /// Ratings to train on.
+    pub fn train(&self) -> &[Transaction] {
+        &self.train
+    }
+
+    /// Ratings held out to measure models, see `models::rmse`.
+    pub fn cross_valid(&self) -> &[Transaction] {
+        &self.cross_valid
+    }
+
+    /// A 64 bit FNV-1a hash of the shape and every rating used for
+    /// training or cross validation. Saved models remember it so they are
+    /// never used against another dataset.
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    fn hash_ratings(&self) -> u64 {
+        const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+        let hash = |hash: u64, x: usize| {
+            (x as u64)
+                .to_le_bytes()
+                .iter()
+                .fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+        };
+        let header = hash(
+            hash(OFFSET, self.metadata.num_customers),
+            self.metadata.num_movies,
+        );
+        self.train
+            .iter()
+            .chain(&self.cross_valid)
+            .fold(header, |h, t| {
+                let rating = t.rating.map_or(usize::MAX, |r| r.to_bits() as usize);
+                hash(hash(hash(h, t.movie_id), t.customer_id), rating)
+            })
     }
 }
 
@@ -123,8 +518,408 @@ impl TrainingDataToMatrix for Data {
     fn training_data_to_matrix(&self) -> DMatrix<f64> {
         let mut ret = DMatrix::zeros(self.metadata.num_customers, self.metadata.num_movies);
         self.train.iter().for_each(|t| {
-            ret[(t.customer_id, t.movie_id)] = t.rating as f64;
+            if let Some(rating) = t.rating {
+                ret[(t.customer_id, t.movie_id)] = rating as f64;
+            }
         });
         ret
     }
 }
+
+/// A compressed sparse row (CSR) matrix.
+///
+/// The ratings matrix is 480k x 17k but less than 2% filled, so we never
+/// want to see it dense. Entries are stored even if they are 0, which is a
+/// rating on some scales, but `from_dense` leaves zeros out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SparseMatrix {
+    pub nrows: usize,
+    pub ncols: usize,
+    /// Row `i` is stored in `indices[indptr[i]..indptr[i + 1]]`.
+    pub indptr: Vec<usize>,
+    /// Column indices, sorted within each row.
+    pub indices: Vec<usize>,
+    pub values: Vec<f64>,
+}
+
+impl Default for SparseMatrix {
+    fn default() -> Self {
+        Self::from_triplets(0, 0, vec![])
+    }
+}
+
+impl SparseMatrix {
+    /// Build from `(row, col, value)` triplets. If an entry shows up
+    /// more than once the last one wins, the same as writing a dense matrix.
+    pub fn from_triplets(
+        nrows: usize,
+        ncols: usize,
+        mut triplets: Vec<(usize, usize, f64)>,
+    ) -> Self {
+        // Sort is stable, so among duplicates the last one stays last.
+        triplets.sort_by_key(|&(i, j, _)| (i, j));
+        let mut indptr = vec![0; nrows + 1];
+        let mut indices = Vec::with_capacity(triplets.len());
+        let mut values = Vec::with_capacity(triplets.len());
+        for (k, &(i, j, v)) in triplets.iter().enumerate() {
+            if let Some(&(ni, nj, _)) = triplets.get(k + 1) {
+                if (ni, nj) == (i, j) {
+                    continue;
+                }
+            }
+            indptr[i + 1] += 1;
+            indices.push(j);
+            values.push(v);
+        }
+        for i in 0..nrows {
+            indptr[i + 1] += indptr[i];
+        }
+        Self {
+            nrows,
+            ncols,
+            indptr,
+            indices,
+            values,
+        }
+    }
+
+    pub fn from_dense(matrix: &DMatrix<f64>) -> Self {
+        let (n, m) = matrix.shape();
+        let mut triplets = vec![];
+        for i in 0..n {
+            for j in 0..m {
+                if matrix[(i, j)] != 0f64 {
+                    triplets.push((i, j, matrix[(i, j)]));
+                }
+            }
+        }
+        Self::from_triplets(n, m, triplets)
+    }
+
+    #[allow(dead_code)]
+    pub fn to_dense(&self) -> DMatrix<f64> {
+        let mut ret = DMatrix::zeros(self.nrows, self.ncols);
+        for i in 0..self.nrows {
+            let (cols, vals) = self.row(i);
+            cols.iter().zip(vals).for_each(|(&j, &v)| ret[(i, j)] = v);
+        }
+        ret
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.nrows, self.ncols)
+    }
+
+    /// Number of stored (non zero) entries.
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Column indices and values of row `i`.
+    pub fn row(&self, i: usize) -> (&[usize], &[f64]) {
+        let range = self.indptr[i]..self.indptr[i + 1];
+        (&self.indices[range.clone()], &self.values[range])
+    }
+
+    /// A copy with the `(row, col, value)` entries of `triplets` set,
+    /// growing rows as needed. The last of duplicate entries wins, and rows
+    /// `triplets` do not touch are copied as they are.
+    pub fn with_entries(&self, mut triplets: Vec<(usize, usize, f64)>) -> Result<Self, String> {
+        if let Some(&(i, j, _)) = triplets.iter().find(|&&(_, j, _)| j >= self.ncols) {
+            return Err(format!(
+                "Entry ({}, {}) is past the {} columns",
+                i, j, self.ncols
+            ));
+        }
+        // Sort is stable, so among duplicates the last one stays last.
+        triplets.sort_by_key(|&(i, j, _)| (i, j));
+        let triplets: Vec<(usize, usize, f64)> = triplets
+            .iter()
+            .enumerate()
+            .filter(|&(k, &(i, j, _))| {
+                triplets
+                    .get(k + 1)
+                    .is_none_or(|&(ni, nj, _)| (ni, nj) != (i, j))
+            })
+            .map(|(_, &t)| t)
+            .collect();
+        let nrows = triplets
+            .last()
+            .map_or(0, |&(i, _, _)| i + 1)
+            .max(self.nrows);
+        let mut indptr = Vec::with_capacity(nrows + 1);
+        let mut indices = Vec::with_capacity(self.nnz() + triplets.len());
+        let mut values = Vec::with_capacity(self.nnz() + triplets.len());
+        indptr.push(0);
+        let mut next = 0;
+        for i in 0..nrows {
+            let (cols, vals) = if i < self.nrows {
+                self.row(i)
+            } else {
+                (&[][..], &[][..])
+            };
+            let start = next;
+            while next < triplets.len() && triplets[next].0 == i {
+                next += 1;
+            }
+            // Merge the sorted columns of the row and of the new entries.
+            let mut new = triplets[start..next]
+                .iter()
+                .map(|&(_, j, v)| (j, v))
+                .peekable();
+            let mut old = cols.iter().copied().zip(vals.iter().copied()).peekable();
+            loop {
+                let (j, v) = match (old.peek(), new.peek()) {
+                    (Some(&(j, _)), Some(&(nj, _))) if nj <= j => {
+                        if nj == j {
+                            old.next();
+                        }
+                        new.next().unwrap()
+                    }
+                    (Some(_), _) => old.next().unwrap(),
+                    (None, Some(_)) => new.next().unwrap(),
+                    (None, None) => break,
+                };
+                indices.push(j);
+                values.push(v);
+            }
+            indptr.push(indices.len());
+        }
+        Ok(Self {
+            nrows,
+            ncols: self.ncols,
+            indptr,
+            indices,
+            values,
+        })
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut indptr = vec![0; self.ncols + 1];
+        self.indices.iter().for_each(|&j| indptr[j + 1] += 1);
+        for j in 0..self.ncols {
+            indptr[j + 1] += indptr[j];
+        }
+        let mut next = indptr.clone();
+        let mut indices = vec![0; self.nnz()];
+        let mut values = vec![0f64; self.nnz()];
+        // Rows are visited in order, so indices in each new row come out sorted.
+        for i in 0..self.nrows {
+            let (cols, vals) = self.row(i);
+            cols.iter().zip(vals).for_each(|(&j, &v)| {
+                indices[next[j]] = i;
+                values[next[j]] = v;
+                next[j] += 1;
+            });
+        }
+        Self {
+            nrows: self.ncols,
+            ncols: self.nrows,
+            indptr,
+            indices,
+            values,
+        }
+    }
+
+    /// $`y = A x`$, rows are computed in parallel.
+    pub fn mul_vec(&self, x: &DVector<f64>) -> DVector<f64> {
+        assert!(x.len() == self.ncols, "Dimension mismatch in mul_vec.");
+        let y: Vec<f64> = (0..self.nrows)
+            .into_par_iter()
+            .map(|i| {
+                let (cols, vals) = self.row(i);
+                cols.iter().zip(vals).map(|(&j, &v)| v * x[j]).sum()
+            })
+            .collect();
+        DVector::from_vec(y)
+    }
+
+    /// $`A X`$ with a dense $`X`$, rows are computed in parallel.
+    pub fn mul_dense(&self, x: &DMatrix<f64>) -> DMatrix<f64> {
+        assert!(x.nrows() == self.ncols, "Dimension mismatch in mul_dense.");
+        let k = x.ncols();
+        // Rows of x as columns, so each one is contiguous.
+        let xt = x.transpose();
+        let rows: Vec<Vec<f64>> = (0..self.nrows)
+            .into_par_iter()
+            .map(|i| {
+                let mut acc = vec![0f64; k];
+                let (cols, vals) = self.row(i);
+                cols.iter().zip(vals).for_each(|(&j, &v)| {
+                    acc.iter_mut()
+                        .zip(xt.column(j).iter())
+                        .for_each(|(a, x)| *a += v * x)
+                });
+                acc
+            })
+            .collect();
+        DMatrix::from_vec(k, self.nrows, rows.concat()).transpose()
+    }
+
+    /// Entrywise $`\max(A, A^T)`$ over the stored entries, for a square matrix
+    /// that should have been symmetric, e.g. one where each row only kept its
+    /// top k entries: $`(i, j)`$ is kept if either row kept it.
+    pub fn symmetrize(&self) -> Self {
+        assert!(
+            self.nrows == self.ncols,
+            "Only square matrix can be symmetrized."
+        );
+        let transposed = self.transpose();
+        let mut triplets = Vec::with_capacity(self.nnz() * 2);
+        for i in 0..self.nrows {
+            let (cols, vals) = self.row(i);
+            let (t_cols, t_vals) = transposed.row(i);
+            let (mut p, mut q) = (0, 0);
+            while p < cols.len() || q < t_cols.len() {
+                let (j, v) = if q == t_cols.len() || (p < cols.len() && cols[p] < t_cols[q]) {
+                    p += 1;
+                    (cols[p - 1], vals[p - 1])
+                } else if p == cols.len() || t_cols[q] < cols[p] {
+                    q += 1;
+                    (t_cols[q - 1], t_vals[q - 1])
+                } else {
+                    p += 1;
+                    q += 1;
+                    (cols[p - 1], f64::max(vals[p - 1], t_vals[q - 1]))
+                };
+                triplets.push((i, j, v));
+            }
+        }
+        Self::from_triplets(self.nrows, self.ncols, triplets)
+    }
+
+    /// Keep only the columns `cols`, column `j` of the result is column
+    /// `cols[j]` of `self`.
+    pub fn select_columns(&self, cols: &[usize]) -> Self {
+        let mut position = vec![None; self.ncols];
+        cols.iter()
+            .enumerate()
+            .for_each(|(j, &c)| position[c] = Some(j));
+        let mut triplets = vec![];
+        for i in 0..self.nrows {
+            let (idx, vals) = self.row(i);
+            idx.iter()
+                .zip(vals)
+                .filter_map(|(&c, &v)| position[c].map(|j| (i, j, v)))
+                .for_each(|t| triplets.push(t));
+        }
+        Self::from_triplets(self.nrows, cols.len(), triplets)
+    }
+
+    /// Keep only the first `ncols` columns.
+    pub fn first_columns(&self, ncols: usize) -> Self {
+        let mut triplets = vec![];
+        for i in 0..self.nrows {
+            let (cols, vals) = self.row(i);
+            cols.iter()
+                .zip(vals)
+                .filter(|(&j, _)| j < ncols)
+                .for_each(|(&j, &v)| triplets.push((i, j, v)));
+        }
+        Self::from_triplets(self.nrows, ncols, triplets)
+    }
+}
+
+pub trait TrainingDataToSparse {
+    fn training_data_to_sparse(&self) -> SparseMatrix;
+}
+
+impl TrainingDataToSparse for Data {
+    /// Same as `training_data_to_matrix` but stays sparse.
+    fn training_data_to_sparse(&self) -> SparseMatrix {
+        SparseMatrix::from_triplets(
+            self.metadata.num_customers,
+            self.metadata.num_movies,
+            self.train
+                .iter()
+                .filter_map(|t| t.rating.map(|r| (t.customer_id, t.movie_id, r as f64)))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Every one of `num_customers` customers rates every one of
+    /// `num_movies` movies, 5 if both have ids of the same parity and 1
+    /// otherwise. Customer `u` has the original id `100 + u`.
+    pub fn two_tastes(num_customers: usize, num_movies: usize) -> Data {
+        Data::from_raw(two_tastes_raw(num_customers, num_movies))
+    }
+
+    /// `two_tastes` before the split, without cross validation.
+    pub fn two_tastes_raw(num_customers: usize, num_movies: usize) -> RawData {
+        let mut movie_ids = IdMapper::new();
+        let movies = (0..num_movies)
+            .map(|j| Movie {
+                movie_id: movie_ids.get_or_insert(j),
+                year_produced: 2000,
+                title: j.to_string(),
+                genres: vec![],
+            })
+            .collect();
+        let transactions = (0..num_customers)
+            .flat_map(|u| {
+                (0..num_movies).map(move |j| Transaction {
+                    movie_id: j,
+                    customer_id: 100 + u,
+                    rating: Some(if (u + j) % 2 == 0 { 5f32 } else { 1f32 }),
+                    date: 12000,
+                })
+            })
+            .collect();
+        RawData::new(
+            transactions,
+            vec![],
+            movies,
+            movie_ids,
+            RatingScale::NETFLIX,
+            Some(0),
+        )
+    }
+
+    #[test]
+    fn test_with_entries() {
+        let matrix = SparseMatrix::from_dense(&DMatrix::from_row_slice(
+            2,
+            4,
+            &[1f64, 0f64, 2f64, 0f64, 0f64, 3f64, 0f64, 4f64],
+        ));
+        let updated = matrix
+            .with_entries(vec![
+                (0, 1, 5f64),
+                (0, 2, 1f64),
+                (0, 2, 6f64),
+                (1, 3, 0f64),
+                (3, 0, 7f64),
+            ])
+            .unwrap();
+        let expected = DMatrix::from_row_slice(
+            4,
+            4,
+            &[
+                1f64, 5f64, 6f64, 0f64, //
+                0f64, 3f64, 0f64, 0f64, //
+                0f64, 0f64, 0f64, 0f64, //
+                7f64, 0f64, 0f64, 0f64,
+            ],
+        );
+        assert!(updated.to_dense() == expected);
+        // The 0 is stored, it may be a rating.
+        assert!(updated.nnz() == 6 && updated.indptr == vec![0, 3, 5, 5, 6]);
+        assert!(matrix.with_entries(vec![]).unwrap().to_dense() == matrix.to_dense());
+        assert!(matrix.with_entries(vec![(0, 4, 1f64)]).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let data = two_tastes(4, 3);
+        assert!(data.fingerprint() == two_tastes(4, 3).fingerprint());
+        assert!(data.fingerprint() != two_tastes(3, 4).fingerprint());
+        let mut other = two_tastes_raw(4, 3);
+        other.transactions[5].rating = Some(3f32);
+        assert!(data.fingerprint() != Data::from_raw(other).fingerprint());
+    }
+}
@@ -1,52 +1,473 @@
+/// Generic delimited rating files.
+pub mod delimited;
+/// MovieLens datasets.
+pub mod movielens;
+/// The original Netflix Prize distribution.
+pub mod netflix;
+/// Prediction output formats.
+pub mod output;
+
+use crate::config;
 use crate::data::*;
 use csv::StringRecord;
 use elapsed::measure_time;
-use log::info;
-use std::{error::Error, fmt::Display, fs::File, io::Write, ops::Sub, path::PathBuf};
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fmt::{self, Debug, Display},
+    fs::File,
+    io::{self as std_io, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// How a dataset is laid out on disk. Every format is read into the same
+/// `RawData`, with dense movie ids counting from 0.
+pub trait DataFormat: Debug {
+    /// Every file `read` parses, to tell whether the cache is stale.
+    fn sources(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>>;
+    /// Parse the dataset in `path`, bad rows going through `rejects`.
+    fn read(&self, path: &Path, rejects: &mut Rejects) -> Result<RawData, Box<dyn Error>>;
+}
+
+/// The format named by `$DATA_FORMAT`, or else guessed from the files in
+/// `path`.
+pub fn data_format(path: &Path) -> Result<Box<dyn DataFormat>, Box<dyn Error>> {
+    let name = match env::var(config::DATA_FORMAT) {
+        Ok(name) => name,
+        Err(_) if netflix::is_netflix_prize(path) => "netflix_prize".to_string(),
+        Err(_) if path.join(config::TRAINING_DATA).is_file() => "netflix_csv".to_string(),
+        Err(_) if movielens::MovieLens::detect(path).is_some() => "movielens".to_string(),
+        Err(_) => {
+            return Err(format!(
+                "Cannot tell the format of {:?}, please set ${}",
+                path,
+                config::DATA_FORMAT
+            )
+            .into())
+        }
+    };
+    match name.as_str() {
+        "netflix_csv" => Ok(Box::new(NetflixCsv)),
+        "netflix_prize" => Ok(Box::new(netflix::NetflixPrize)),
+        "movielens" => Ok(Box::new(movielens::MovieLens::detect(path).ok_or_else(
+            || format!("No MovieLens ratings in {:?}", path),
+        )?)),
+        "delimited" => Ok(Box::new(delimited::Delimited::from_env()?)),
+        _ => Err(format!(
+            "Unknown data format {:?}, expecting netflix_csv, netflix_prize, movielens or delimited",
+            name
+        )
+        .into()),
+    }
+}
+
+/// Our massaged Netflix data, `train.csv`, `test.csv` and
+/// `movie_titles.csv`.
+#[derive(Debug)]
+pub struct NetflixCsv;
+
+impl DataFormat for NetflixCsv {
+    fn sources(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        Ok([
+            config::TRAINING_DATA,
+            config::TEST_DATA,
+            config::MOVIE_TITLES,
+        ]
+        .iter()
+        .map(|name| path.join(name))
+        .collect())
+    }
+    fn read(&self, path: &Path, rejects: &mut Rejects) -> Result<RawData, Box<dyn Error>> {
+        let transactions =
+            Transaction::from_csv_where(path.join(config::TRAINING_DATA), rejects, |t| {
+                if t.rating.is_none() {
+                    Err(FieldError::Missing { column: 2 })
+                } else {
+                    Ok(())
+                }
+            })?;
+        let movies = Movie::from_csv(path.join(config::MOVIE_TITLES), rejects)?;
+        let test_data = Transaction::from_csv(path.join(config::TEST_DATA), rejects)?;
+        let movie_ids = netflix_movie_ids(&movies, &transactions, &test_data);
+        Ok(RawData::new(
+            transactions,
+            test_data,
+            movies,
+            movie_ids,
+            RatingScale::NETFLIX,
+            None,
+        ))
+    }
+}
+
+/// Give movies dense ids, first those of `movies` in order, then any other
+/// movie rated in `transactions` or `test_data`, which is named after its
+/// original id.
+pub fn densify_movies(
+    movies: Vec<Movie>,
+    transactions: &mut [Transaction],
+    test_data: &mut [Transaction],
+) -> (Vec<Movie>, IdMapper) {
+    let mut movie_ids = IdMapper::new();
+    let mut dense = Vec::with_capacity(movies.len());
+    for mut movie in movies {
+        if movie_ids.to_virtual(movie.movie_id).is_some() {
+            warn!(
+                "Movie {} is listed twice, keeping the first",
+                movie.movie_id
+            );
+            continue;
+        }
+        movie.movie_id = movie_ids.get_or_insert(movie.movie_id);
+        dense.push(movie);
+    }
+    for t in transactions.iter_mut().chain(test_data.iter_mut()) {
+        let original = t.movie_id;
+        t.movie_id = movie_ids.get_or_insert(original);
+        if t.movie_id == dense.len() {
+            dense.push(Movie {
+                movie_id: t.movie_id,
+                year_produced: 0,
+                title: original.to_string(),
+                genres: vec![],
+            });
+        }
+    }
+    (dense, movie_ids)
+}
+
+/// Netflix movie ids count from 1 in the files, but from 0 once parsed by
+/// `parse_movie_id`, so they are already dense.
+pub fn netflix_movie_ids(
+    movies: &[Movie],
+    transactions: &[Transaction],
+    test_data: &[Transaction],
+) -> IdMapper {
+    let num_movies = movies
+        .iter()
+        .map(|m| m.movie_id + 1)
+        .chain(transactions.iter().chain(test_data).map(|t| t.movie_id + 1))
+        .max()
+        .unwrap_or(0);
+    IdMapper::from_originals((1..=num_movies).collect())
+}
+
+/// Seconds since 1970-01-01 to a `Day`.
+pub fn timestamp_to_day(timestamp: &str) -> Result<Day, Box<dyn Error>> {
+    let days = timestamp.trim().parse::<u64>()? / 86_400;
+    if days > Day::MAX as u64 {
+        return Err(format!("Timestamp {} out of range", timestamp).into());
+    }
+    Ok(days as Day)
+}
+
+/// Why a field could not be parsed, columns counting from 0.
+#[derive(Debug)]
+pub enum FieldError {
+    Missing {
+        column: usize,
+    },
+    Invalid {
+        column: usize,
+        value: String,
+        reason: String,
+    },
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Missing { column } => write!(f, "column {}: missing", column + 1),
+            Self::Invalid {
+                column,
+                value,
+                reason,
+            } => write!(
+                f,
+                "column {}: {:?} is invalid, {}",
+                column + 1,
+                value,
+                reason
+            ),
+        }
+    }
+}
+
+impl Error for FieldError {}
+
+/// Why a data file could not be parsed, lines counting from 1.
+#[derive(Debug)]
+pub enum CsvError {
+    /// The file cannot be opened or read.
+    Io {
+        file: PathBuf,
+        source: std_io::Error,
+    },
+    /// The line itself is broken, e.g. unbalanced quotes, invalid UTF-8 or
+    /// the wrong # of fields.
+    Malformed {
+        file: PathBuf,
+        line: u64,
+        reason: String,
+    },
+    /// A field of the line is missing or invalid.
+    Field {
+        file: PathBuf,
+        line: u64,
+        source: FieldError,
+    },
+}
+
+impl CsvError {
+    pub fn from_csv(file: &Path, err: csv::Error) -> Self {
+        let line = err.position().map_or(0, |pos| pos.line());
+        let reason = match err.kind() {
+            csv::ErrorKind::UnequalLengths {
+                expected_len, len, ..
+            } => format!("{} fields but {} expected", len, expected_len),
+            csv::ErrorKind::Utf8 { err, .. } => format!("invalid UTF-8, {}", err),
+            _ => err.to_string(),
+        };
+        match err.into_kind() {
+            csv::ErrorKind::Io(source) => Self::Io {
+                file: file.to_path_buf(),
+                source,
+            },
+            _ => Self::Malformed {
+                file: file.to_path_buf(),
+                line,
+                reason,
+            },
+        }
+    }
+    pub fn file(&self) -> &Path {
+        match self {
+            Self::Io { file, .. } | Self::Malformed { file, .. } | Self::Field { file, .. } => file,
+        }
+    }
+}
+
+impl Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io { file, source } => write!(f, "{:?}: {}", file, source),
+            Self::Malformed { file, line, reason } => {
+                write!(f, "{:?} line {}: {}", file, line, reason)
+            }
+            Self::Field { file, line, source } => write!(f, "{:?} line {}, {}", file, line, source),
+        }
+    }
+}
+
+impl Error for CsvError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Malformed { .. } => None,
+            Self::Field { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Parse the field `value` of `column` with `parse`.
+pub fn parse_field<T, E, F>(value: Option<&str>, column: usize, parse: F) -> Result<T, FieldError>
+where
+    E: Display,
+    F: FnOnce(&str) -> Result<T, E>,
+{
+    let value = value.ok_or(FieldError::Missing { column })?;
+    parse(value).map_err(|err| FieldError::Invalid {
+        column,
+        value: value.to_string(),
+        reason: err.to_string(),
+    })
+}
+
+/// Parse the field `value` of `column` with `FromStr`.
+pub fn field<T>(value: Option<&str>, column: usize) -> Result<T, FieldError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_field(value, column, |s| s.trim().parse())
+}
+
+/// Movie ids start counting from 1 in the data files, we don't like that.
+pub fn parse_movie_id(s: &str) -> Result<usize, Box<dyn Error>> {
+    Ok(s.trim()
+        .parse::<usize>()?
+        .checked_sub(1)
+        .ok_or("movie ids start from 1")?)
+}
+
+/// `NULL` or empty years are 0.
+pub fn parse_year(s: &str) -> Result<u16, Box<dyn Error>> {
+    match s.trim() {
+        "" | "NULL" => Ok(0),
+        s => Ok(s.parse()?),
+    }
+}
+
+/// Ratings are 1 to 5, an empty one is unknown for the test set.
+fn parse_rating(s: &str) -> Result<Option<Rating>, Box<dyn Error>> {
+    match s.trim() {
+        "" => Ok(None),
+        s => RatingScale::NETFLIX.parse(s).map(Some),
+    }
+}
+
+/// What to do with rows that cannot be parsed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseMode {
+    /// Fail on the first one.
+    Strict,
+    /// Skip and count them.
+    Lenient,
+}
+
+impl FromStr for ParseMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err(format!(
+                "Unknown parse mode {:?}, expecting strict or lenient",
+                s
+            )),
+        }
+    }
+}
+
+/// # of rejected rows whose errors are kept for the summary.
+const MAX_EXAMPLES: usize = 10;
+
+/// The rows that could not be parsed while loading a dataset.
+#[derive(Debug)]
+pub struct Rejects {
+    pub mode: ParseMode,
+    /// # of rejected rows of every file.
+    pub counts: BTreeMap<PathBuf, usize>,
+    /// The first `MAX_EXAMPLES` errors.
+    pub examples: Vec<CsvError>,
+}
+
+impl Rejects {
+    pub fn new(mode: ParseMode) -> Self {
+        Self {
+            mode,
+            counts: BTreeMap::new(),
+            examples: vec![],
+        }
+    }
+    /// Fail with `err` in strict mode, remember it in lenient mode. A file
+    /// that cannot be read always fails.
+    pub fn reject(&mut self, err: CsvError) -> Result<(), CsvError> {
+        if self.mode == ParseMode::Strict || matches!(err, CsvError::Io { .. }) {
+            return Err(err);
+        }
+        *self.counts.entry(err.file().to_path_buf()).or_insert(0) += 1;
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(err);
+        }
+        Ok(())
+    }
+    /// Add the rejects of another part of the dataset.
+    pub fn merge(&mut self, other: Self) {
+        for (file, count) in other.counts {
+            *self.counts.entry(file).or_insert(0) += count;
+        }
+        let room = MAX_EXAMPLES - self.examples.len();
+        self.examples.extend(other.examples.into_iter().take(room));
+    }
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+    pub fn log_summary(&self) {
+        if self.total() == 0 {
+            return;
+        }
+        warn!("Rejected {} malformed rows:", self.total());
+        for (file, count) in &self.counts {
+            warn!("    {:?}: {} rows", file, count);
+        }
+        warn!("The first {} of them:", self.examples.len());
+        for err in &self.examples {
+            warn!("    {}", err);
+        }
+    }
+}
 
 /// Converts a `StringRecord` to our type.
 pub trait FromStringRecord {
-    fn from_string_record(record: StringRecord) -> Result<Self, Box<dyn Error>>
+    fn from_string_record(record: &StringRecord) -> Result<Self, FieldError>
     where
         Self: std::marker::Sized;
 }
 
 impl FromStringRecord for Movie {
-    fn from_string_record(record: StringRecord) -> Result<Self, Box<dyn Error>> {
+    fn from_string_record(record: &StringRecord) -> Result<Self, FieldError> {
         Ok(Self {
-            // movie_id starts counting from 1, we don't like that.
-            movie_id: record.get(0).unwrap().parse::<usize>()?.sub(1),
-            year_produced: record.get(1).unwrap().parse().unwrap_or(0),
-            title: record.get(2).unwrap().to_string(),
+            movie_id: parse_field(record.get(0), 0, parse_movie_id)?,
+            year_produced: parse_field(record.get(1), 1, parse_year)?,
+            title: record
+                .get(2)
+                .ok_or(FieldError::Missing { column: 2 })?
+                .to_string(),
+            genres: vec![],
         })
     }
 }
 
 impl FromStringRecord for Transaction {
-    fn from_string_record(record: StringRecord) -> Result<Self, Box<dyn Error>> {
+    fn from_string_record(record: &StringRecord) -> Result<Self, FieldError> {
         Ok(Self {
-            // movie_id starts counting from 1, we don't like that.
-            movie_id: record.get(0).unwrap().parse::<usize>()?.sub(1),
-            customer_id: record.get(1).unwrap().parse()?,
-            rating: record.get(2).unwrap().parse().unwrap_or(0),
-            date: record.get(3).unwrap().to_string(),
+            movie_id: parse_field(record.get(0), 0, parse_movie_id)?,
+            customer_id: field(record.get(1), 1)?,
+            rating: parse_field(record.get(2), 2, parse_rating)?,
+            date: parse_field(record.get(3), 3, parse_day)?,
         })
     }
 }
 
-/// Convert a CSV file to `Vec<T>` if `T: FromStringRecord`.
+/// Convert a CSV file with a header line to `Vec<T>` if
+/// `T: FromStringRecord`.
 pub trait FromCsv: FromStringRecord {
-    fn from_csv(p: PathBuf) -> Result<Vec<Self>, Box<dyn Error>>
+    fn from_csv(p: PathBuf, rejects: &mut Rejects) -> Result<Vec<Self>, CsvError>
     where
         Self: std::marker::Sized,
+    {
+        Self::from_csv_where(p, rejects, |_| Ok(()))
+    }
+    /// Also reject the rows that fail `check`.
+    fn from_csv_where<F>(p: PathBuf, rejects: &mut Rejects, check: F) -> Result<Vec<Self>, CsvError>
+    where
+        Self: std::marker::Sized,
+        F: Fn(&Self) -> Result<(), FieldError>,
     {
         info!("Loading csv from {:?}", p);
         let (elapsed, ret) = measure_time(|| {
-            let file = File::open(p).unwrap();
-            let mut rdr = csv::Reader::from_reader(file);
+            let mut rdr = csv::Reader::from_path(&p).map_err(|err| CsvError::from_csv(&p, err))?;
             let mut ret = vec![];
             for result in rdr.records() {
-                ret.push(Self::from_string_record(result?)?);
+                let row = result
+                    .map_err(|err| CsvError::from_csv(&p, err))
+                    .and_then(|record| {
+                        Self::from_string_record(&record)
+                            .and_then(|row| check(&row).map(|_| row))
+                            .map_err(|source| CsvError::Field {
+                                file: p.clone(),
+                                line: record.position().map_or(0, |pos| pos.line()),
+                                source,
+                            })
+                    });
+                match row {
+                    Ok(row) => ret.push(row),
+                    Err(err) => rejects.reject(err)?,
+                }
             }
             Ok(ret)
         });
@@ -59,16 +480,82 @@ impl FromCsv for Transaction {}
 
 /// Dump something into a file.
 pub trait DumpToFile {
-    /// By default it outputs to `<model-name>.txt` in the working directory.
-    fn dump_to_file(&self, file_name: String);
+    /// One item per line.
+    fn dump_to_file(&self, path: &Path);
 }
 impl<T: Display> DumpToFile for Vec<T> {
-    fn dump_to_file(&self, file_name: String) {
-        let mut file = File::create(file_name.clone())
-            .expect(format!("Unable to create file {}", file_name).as_str());
+    fn dump_to_file(&self, path: &Path) {
+        let mut file =
+            File::create(path).unwrap_or_else(|_| panic!("Unable to create file {:?}", path));
         self.iter().for_each(|t| {
             file.write_fmt(format_args!("{}\n", t))
-                .expect(format!("Write to file {} failed.", file_name).as_str());
+                .unwrap_or_else(|_| panic!("Write to file {:?} failed.", path));
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    #[test]
+    fn test_from_csv_rejects() {
+        let dir = std::env::temp_dir().join(format!("from_csv_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("train.csv");
+        fs::write(
+            &file,
+            "movie,customer,rating,date\n\
+             1,10,5,2005-09-06\n\
+             2,x,3,2005-09-06\n\
+             3,10,,2005-09-06\n\
+             4,10,5\n\
+             5,10,9,2005-09-06\n\
+             6,20,1,2005-02-30\n\
+             7,20,1,2005-02-28\n",
+        )
+        .unwrap();
+        let labelled = |t: &Transaction| {
+            if t.rating.is_none() {
+                Err(FieldError::Missing { column: 2 })
+            } else {
+                Ok(())
+            }
+        };
+        let err = Transaction::from_csv_where(
+            file.clone(),
+            &mut Rejects::new(ParseMode::Strict),
+            labelled,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            CsvError::Field {
+                line: 3,
+                source: FieldError::Invalid { column: 1, .. },
+                ..
+            }
+        ));
+
+        let mut rejects = Rejects::new(ParseMode::Lenient);
+        let transactions =
+            Transaction::from_csv_where(file.clone(), &mut rejects, labelled).unwrap();
+        assert!(transactions.len() == 2);
+        assert!(transactions[1].movie_id == 6);
+        assert!(rejects.total() == 5 && rejects.counts[&file] == 5);
+        let lines: Vec<_> = rejects
+            .examples
+            .iter()
+            .map(|err| match err {
+                CsvError::Field { line, .. } | CsvError::Malformed { line, .. } => *line,
+                CsvError::Io { .. } => 0,
+            })
+            .collect();
+        assert!(lines == vec![3, 4, 5, 6, 7]);
+        assert!(matches!(rejects.examples[2], CsvError::Malformed { .. }));
+
+        // A missing file fails even in lenient mode.
+        assert!(Movie::from_csv(dir.join("missing.csv"), &mut rejects).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+//! Any delimited rating file, configured by the `DELIMITED_*` environment
+//! variables, see `config`. Movies are named after their ids, and ratings
+//! are sorted by date, if there is one, so that the most recent ones are
+//! held out for cross validation.
+use super::*;
+
+use std::str::FromStr;
+
+/// What a column of a delimited file holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Customer,
+    Movie,
+    Rating,
+    /// `YYYY-MM-DD` or a unix timestamp.
+    Date,
+    Skip,
+}
+
+impl FromStr for Column {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "customer" => Ok(Self::Customer),
+            "movie" => Ok(Self::Movie),
+            "rating" => Ok(Self::Rating),
+            "date" => Ok(Self::Date),
+            "_" => Ok(Self::Skip),
+            _ => Err(format!(
+                "Unknown column {:?}, expecting customer, movie, rating, date or _",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Delimited {
+    /// Rating file, relative to the data path.
+    pub ratings: String,
+    /// Test file with the same columns, whose ratings are ignored.
+    pub test: Option<String>,
+    pub separator: u8,
+    pub header: bool,
+    pub columns: Vec<Column>,
+    pub scale: RatingScale,
+}
+
+impl Delimited {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let separator = config::from_env(config::DELIMITED_SEPARATOR, ",".to_string());
+        let separator = match separator.as_str() {
+            "tab" | "\\t" => b'\t',
+            s if s.len() == 1 => s.as_bytes()[0],
+            s => return Err(format!("Separator {:?} is not one ASCII character", s).into()),
+        };
+        let columns = env::var(config::DELIMITED_COLUMNS)
+            .unwrap_or_else(|_| "customer,movie,rating,date".to_string())
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Column>, _>>()?;
+        for column in &[Column::Customer, Column::Movie, Column::Rating] {
+            if !columns.contains(column) {
+                return Err(format!("No {:?} in ${}", column, config::DELIMITED_COLUMNS).into());
+            }
+        }
+        Ok(Self {
+            ratings: config::from_env(config::DELIMITED_RATINGS, "ratings.csv".to_string()),
+            test: env::var(config::DELIMITED_TEST).ok(),
+            separator,
+            header: config::from_env(config::DELIMITED_HEADER, true),
+            columns,
+            scale: config::from_env(config::DELIMITED_SCALE, RatingScale::NETFLIX),
+        })
+    }
+
+    /// Transactions of `file`, with unknown ratings if `test`.
+    fn read_file(
+        &self,
+        file: &Path,
+        test: bool,
+        rejects: &mut Rejects,
+    ) -> Result<Vec<Transaction>, CsvError> {
+        info!("Loading {:?}", file);
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(self.separator)
+            .has_headers(self.header)
+            .flexible(true)
+            .from_path(file)
+            .map_err(|err| CsvError::from_csv(file, err))?;
+        let mut transactions = vec![];
+        for record in rdr.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    rejects.reject(CsvError::from_csv(file, err))?;
+                    continue;
+                }
+            };
+            let transaction = || -> Result<Transaction, FieldError> {
+                let mut t = Transaction {
+                    movie_id: 0,
+                    customer_id: 0,
+                    rating: None,
+                    date: 0,
+                };
+                for (i, column) in self.columns.iter().enumerate() {
+                    let value = record.get(i);
+                    match column {
+                        Column::Customer => t.customer_id = field(value, i)?,
+                        Column::Movie => t.movie_id = field(value, i)?,
+                        Column::Rating if !test => {
+                            t.rating = Some(parse_field(value, i, |s| self.scale.parse(s))?)
+                        }
+                        Column::Date => t.date = parse_field(value, i, parse_date)?,
+                        _ => (),
+                    }
+                }
+                Ok(t)
+            };
+            match transaction() {
+                Ok(t) => transactions.push(t),
+                Err(source) => rejects.reject(CsvError::Field {
+                    file: file.to_path_buf(),
+                    line: record.position().map_or(0, |pos| pos.line()),
+                    source,
+                })?,
+            }
+        }
+        Ok(transactions)
+    }
+}
+
+/// `YYYY-MM-DD` or a unix timestamp.
+fn parse_date(date: &str) -> Result<Day, Box<dyn Error>> {
+    if date.contains('-') {
+        parse_day(date)
+    } else {
+        timestamp_to_day(date)
+    }
+}
+
+impl DataFormat for Delimited {
+    fn sources(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        Ok(std::iter::once(&self.ratings)
+            .chain(&self.test)
+            .map(|file| path.join(file))
+            .collect())
+    }
+    fn read(&self, path: &Path, rejects: &mut Rejects) -> Result<RawData, Box<dyn Error>> {
+        let (elapsed, ret) = measure_time(|| -> Result<RawData, Box<dyn Error>> {
+            let mut transactions = self.read_file(&path.join(&self.ratings), false, rejects)?;
+            transactions.sort_by_key(|t| t.date);
+            let mut test_data = match &self.test {
+                Some(test) => self.read_file(&path.join(test), true, rejects)?,
+                None => vec![],
+            };
+            let (movies, movie_ids) = densify_movies(vec![], &mut transactions, &mut test_data);
+            Ok(RawData::new(
+                transactions,
+                test_data,
+                movies,
+                movie_ids,
+                self.scale,
+                None,
+            ))
+        });
+        info!("Elapsed {}", elapsed);
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    #[test]
+    fn test_read_delimited() {
+        let dir = std::env::temp_dir().join(format!("delimited_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("ratings.tsv"),
+            "42\tx\t30\t0\t2005-01-02\n7\tx\t10\t2.25\t2005-01-01\n",
+        )
+        .unwrap();
+        fs::write(dir.join("test.tsv"), "7\tx\t30\t\t1104710400\n").unwrap();
+        let format = Delimited {
+            ratings: "ratings.tsv".to_string(),
+            test: Some("test.tsv".to_string()),
+            separator: b'\t',
+            header: false,
+            columns: "customer,_,movie,rating,date"
+                .split(',')
+                .map(|c| c.parse().unwrap())
+                .collect(),
+            scale: "0:10".parse().unwrap(),
+        };
+        assert!(format.sources(&dir).unwrap().len() == 2);
+
+        let raw = format
+            .read(&dir, &mut Rejects::new(ParseMode::Strict))
+            .unwrap();
+        let ratings: Vec<_> = raw
+            .transactions
+            .iter()
+            .map(|t| {
+                (
+                    raw.customer_ids.to_original(t.customer_id),
+                    t.movie_id,
+                    t.rating,
+                )
+            })
+            .collect();
+        assert!(ratings == vec![(7, 0, Some(2.25)), (42, 1, Some(0f32))]);
+        assert!(raw.movies[1].title == "30");
+        let test = &raw.test_data[0];
+        assert!(raw.customer_ids.to_original(test.customer_id) == 7 && test.movie_id == 1);
+        assert!(test.rating.is_none() && test.date == parse_day("2005-01-03").unwrap());
+        // A rating of 0 is not a missing one.
+        let data = Data::from_raw(raw);
+        assert!(data.train().iter().any(|t| t.rating == Some(0f32)));
+        assert!(data.training_data_to_sparse().nnz() == data.train().len());
+
+        assert!("".parse::<Column>().is_err());
+        assert!("5:1".parse::<RatingScale>().is_err());
+        assert!("0:0".parse::<RatingScale>().is_err());
+        assert!("1:10:4".parse::<RatingScale>().is_err());
+        assert!("-5:5:2.5".parse::<RatingScale>().unwrap().contains(5f64));
+        assert!(
+            "1:5:0.5".parse::<RatingScale>().unwrap()
+                == RatingScale::new(1f64, 5f64, Some(0.5)).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
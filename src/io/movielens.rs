@@ -0,0 +1,363 @@
+//! MovieLens releases, told apart by their rating file:
+//!
+//! - 100K, `u.data` with tab separated `user item rating timestamp`, and
+//!   `u.item` with `|` separated `id|title|release date|video release
+//!   date|url` followed by 19 genre flags.
+//! - 1M and 10M, `ratings.dat` with `user::movie::rating::timestamp`, and
+//!   `movies.dat` with `movie::title (year)::genre|genre`. Only 1M comes
+//!   with `users.dat`.
+//! - 20M and later, `ratings.csv` and `movies.csv`, the same with a header
+//!   line, comma separated and titles quoted when needed.
+//!
+//! 100K and 1M rate by whole stars from 1 to 5, but 10M and later by half
+//! stars from 0.5 to 5. There is no test set, and ratings are sorted by date so that the most
+//! recent ones are held out for cross validation.
+use super::*;
+
+use log::warn;
+use std::fs;
+
+/// Genres of the flags at the end of `u.item` lines, from `u.genre`.
+const GENRES_100K: [&str; 19] = [
+    "unknown",
+    "Action",
+    "Adventure",
+    "Animation",
+    "Children's",
+    "Comedy",
+    "Crime",
+    "Documentary",
+    "Drama",
+    "Fantasy",
+    "Film-Noir",
+    "Horror",
+    "Musical",
+    "Mystery",
+    "Romance",
+    "Sci-Fi",
+    "Thriller",
+    "War",
+    "Western",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Release {
+    /// `u.data` and `u.item`.
+    Ml100k,
+    /// `ratings.dat`, `movies.dat` and `users.dat`.
+    Ml1m,
+    /// `ratings.dat` and `movies.dat`.
+    Ml10m,
+    /// `ratings.csv` and `movies.csv`.
+    Csv,
+}
+
+/// A MovieLens dataset of the given release.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieLens(pub Release);
+
+impl MovieLens {
+    /// The release in `path`, if any.
+    pub fn detect(path: &Path) -> Option<Self> {
+        [Release::Ml100k, Release::Ml1m, Release::Ml10m, Release::Csv]
+            .iter()
+            .copied()
+            .find(|release| {
+                path.join(release.ratings_file()).is_file()
+                    && (*release != Release::Ml1m || path.join("users.dat").is_file())
+            })
+            .map(MovieLens)
+    }
+}
+
+impl Release {
+    fn ratings_file(self) -> &'static str {
+        match self {
+            Self::Ml100k => "u.data",
+            Self::Ml1m | Self::Ml10m => "ratings.dat",
+            Self::Csv => "ratings.csv",
+        }
+    }
+    /// `ratings.dat` has whole stars in 1M but half stars in 10M.
+    fn scale(self) -> RatingScale {
+        match self {
+            Self::Ml100k | Self::Ml1m => RatingScale::NETFLIX,
+            Self::Ml10m | Self::Csv => RatingScale::HALF_STARS,
+        }
+    }
+    fn movies_file(self) -> &'static str {
+        match self {
+            Self::Ml100k => "u.item",
+            Self::Ml1m | Self::Ml10m => "movies.dat",
+            Self::Csv => "movies.csv",
+        }
+    }
+
+    fn read_ratings(
+        self,
+        file: &Path,
+        rejects: &mut Rejects,
+    ) -> Result<Vec<Transaction>, CsvError> {
+        let separator = match self {
+            Self::Ml100k => "\t",
+            Self::Ml1m | Self::Ml10m => "::",
+            Self::Csv => ",",
+        };
+        let text = read_text(file)?;
+        let mut lines = text.lines().enumerate();
+        if self == Self::Csv {
+            lines.next();
+        }
+        let mut transactions = vec![];
+        for (i, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.trim().split(separator).collect();
+            let transaction = || -> Result<Transaction, FieldError> {
+                Ok(Transaction {
+                    customer_id: field(fields.first().copied(), 0)?,
+                    movie_id: field(fields.get(1).copied(), 1)?,
+                    rating: Some(parse_field(fields.get(2).copied(), 2, |s| {
+                        self.scale().parse(s)
+                    })?),
+                    date: parse_field(fields.get(3).copied(), 3, timestamp_to_day)?,
+                })
+            };
+            match transaction() {
+                Ok(t) => transactions.push(t),
+                Err(source) => rejects.reject(CsvError::Field {
+                    file: file.to_path_buf(),
+                    line: i as u64 + 1,
+                    source,
+                })?,
+            }
+        }
+        Ok(transactions)
+    }
+
+    /// Movies with their original ids.
+    fn read_movies(self, file: &Path, rejects: &mut Rejects) -> Result<Vec<Movie>, CsvError> {
+        let rows: Vec<(u64, Vec<String>)> = match self {
+            Self::Ml100k | Self::Ml1m | Self::Ml10m => {
+                let separator = if self == Self::Ml100k { "|" } else { "::" };
+                read_text(file)?
+                    .lines()
+                    .enumerate()
+                    .filter(|(_, line)| !line.trim().is_empty())
+                    .map(|(i, line)| {
+                        let fields = line.split(separator).map(str::to_string).collect();
+                        (i as u64 + 1, fields)
+                    })
+                    .collect()
+            }
+            Self::Csv => {
+                let mut rows = vec![];
+                let mut rdr =
+                    csv::Reader::from_path(file).map_err(|err| CsvError::from_csv(file, err))?;
+                for record in rdr.records() {
+                    match record {
+                        Ok(record) => rows.push((
+                            record.position().map_or(0, |pos| pos.line()),
+                            record.iter().map(str::to_string).collect(),
+                        )),
+                        Err(err) => rejects.reject(CsvError::from_csv(file, err))?,
+                    }
+                }
+                rows
+            }
+        };
+        let mut movies = vec![];
+        for (line, fields) in rows {
+            let movie = || -> Result<Movie, FieldError> {
+                let get = |i: usize| fields.get(i).map(String::as_str);
+                let (title, year_produced) =
+                    split_year(get(1).ok_or(FieldError::Missing { column: 1 })?);
+                let genres = if self == Self::Ml100k {
+                    fields
+                        .get(5..)
+                        .unwrap_or_default()
+                        .iter()
+                        .zip(&GENRES_100K)
+                        .filter(|(flag, _)| flag.as_str() == "1")
+                        .map(|(_, genre)| genre.to_string())
+                        .collect()
+                } else {
+                    get(2)
+                        .ok_or(FieldError::Missing { column: 2 })?
+                        .split('|')
+                        .filter(|genre| *genre != "(no genres listed)")
+                        .map(str::to_string)
+                        .collect()
+                };
+                Ok(Movie {
+                    movie_id: field(get(0), 0)?,
+                    year_produced,
+                    title,
+                    genres,
+                })
+            };
+            match movie() {
+                Ok(movie) => movies.push(movie),
+                Err(source) => rejects.reject(CsvError::Field {
+                    file: file.to_path_buf(),
+                    line,
+                    source,
+                })?,
+            }
+        }
+        Ok(movies)
+    }
+}
+
+impl DataFormat for MovieLens {
+    fn sources(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let release = self.0;
+        let mut sources = vec![path.join(release.ratings_file())];
+        if path.join(release.movies_file()).is_file() {
+            sources.push(path.join(release.movies_file()));
+        }
+        Ok(sources)
+    }
+    fn read(&self, path: &Path, rejects: &mut Rejects) -> Result<RawData, Box<dyn Error>> {
+        let release = self.0;
+        info!("Loading MovieLens from {:?}", path);
+        let (elapsed, ret) = measure_time(|| -> Result<RawData, Box<dyn Error>> {
+            let mut transactions =
+                release.read_ratings(&path.join(release.ratings_file()), rejects)?;
+            transactions.sort_by_key(|t| t.date);
+            let movies_file = path.join(release.movies_file());
+            let movies = if movies_file.is_file() {
+                release.read_movies(&movies_file, rejects)?
+            } else {
+                warn!("No {:?}, movies are named by their ids", movies_file);
+                vec![]
+            };
+            let (movies, movie_ids) = densify_movies(movies, &mut transactions, &mut []);
+            Ok(RawData::new(
+                transactions,
+                vec![],
+                movies,
+                movie_ids,
+                release.scale(),
+                None,
+            ))
+        });
+        info!("Elapsed {}", elapsed);
+        ret
+    }
+}
+
+/// UTF-8, or else ISO-8859-1 like the older releases.
+fn read_text(file: &Path) -> Result<String, CsvError> {
+    let bytes = fs::read(file).map_err(|source| CsvError::Io {
+        file: file.to_path_buf(),
+        source,
+    })?;
+    Ok(String::from_utf8(bytes)
+        .unwrap_or_else(|err| err.into_bytes().into_iter().map(char::from).collect()))
+}
+
+/// `Toy Story (1995)` to `Toy Story` and 1995, the year is 0 if there is
+/// none.
+fn split_year(title: &str) -> (String, u16) {
+    let title = title.trim();
+    if let Some(rest) = title.strip_suffix(')') {
+        if let Some(open) = rest.rfind('(') {
+            if let Ok(year) = rest[open + 1..].parse() {
+                return (rest[..open].trim_end().to_string(), year);
+            }
+        }
+    }
+    (title.to_string(), 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_read_movielens() {
+        let dir = std::env::temp_dir().join(format!("movielens_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("ratings.csv"),
+            "userId,movieId,rating,timestamp\n7,1,4.0,1000000000\n7,500,0.5,86400\n9,1,3.5,1100000000\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("movies.csv"),
+            "movieId,title,genres\n1,Toy Story (1995),Adventure|Animation\n\
+             2,\"American President, The (1995)\",Comedy|Drama|Romance\n\
+             500,Untitled,(no genres listed)\n",
+        )
+        .unwrap();
+        let release = MovieLens::detect(&dir).unwrap();
+        assert!(release == MovieLens(Release::Csv));
+
+        let raw = release
+            .read(&dir, &mut Rejects::new(ParseMode::Strict))
+            .unwrap();
+        let ratings: Vec<_> = raw
+            .transactions
+            .iter()
+            .map(|t| {
+                (
+                    raw.customer_ids.to_original(t.customer_id),
+                    t.movie_id,
+                    t.rating,
+                    t.date,
+                )
+            })
+            .collect();
+        // Sorted by date, and movie 500 is the 3rd movie.
+        assert!(
+            ratings
+                == vec![
+                    (7, 2, Some(0.5), 1),
+                    (7, 0, Some(4f32), 11574),
+                    (9, 0, Some(3.5), 12731)
+                ]
+        );
+        assert!(raw.scale == RatingScale::HALF_STARS);
+        assert!(raw.movie_ids.originals() == [1, 2, 500]);
+        assert!(raw.movie_ids.to_virtual(500) == Some(2));
+        assert!(raw.test_data.is_empty());
+        assert!(raw.movies[1].title == "American President, The");
+        assert!(raw.movies[1].year_produced == 1995);
+        assert!(raw.movies[0].genres == vec!["Adventure", "Animation"]);
+        assert!(raw.movies[2].year_produced == 0 && raw.movies[2].genres.is_empty());
+
+        fs::remove_file(dir.join("ratings.csv")).unwrap();
+        fs::remove_file(dir.join("movies.csv")).unwrap();
+        fs::write(dir.join("u.data"), "196\t242\t3\t881250949\n").unwrap();
+        fs::write(
+            dir.join("u.item"),
+            b"242|Kolya (1996)|24-Jan-1997||http://x|0|0|0|0|0|1|0|0|0|0|0|0|0|0|0|0|0|0|0\n\
+              243|Caf\xe9 (1997)|01-Jan-1997||http://x|0|0|0|0|0|0|0|0|1|0|0|0|0|0|1|0|0|0|0\n",
+        )
+        .unwrap();
+        let release = MovieLens::detect(&dir).unwrap();
+        assert!(release == MovieLens(Release::Ml100k));
+        let raw = release
+            .read(&dir, &mut Rejects::new(ParseMode::Strict))
+            .unwrap();
+        assert!(raw.transactions[0].movie_id == 0 && raw.transactions[0].rating == Some(3f32));
+        assert!(raw.movies[0].genres == vec!["Comedy"]);
+        assert!(raw.movies[1].title == "Café");
+        assert!(raw.movies[1].genres == vec!["Drama", "Romance"]);
+
+        fs::remove_file(dir.join("u.data")).unwrap();
+        fs::remove_file(dir.join("u.item")).unwrap();
+        fs::write(dir.join("ratings.dat"), "1::1193::5::978300760\n").unwrap();
+        let release = MovieLens::detect(&dir).unwrap();
+        assert!(release == MovieLens(Release::Ml10m));
+        fs::write(dir.join("users.dat"), "1::F::1::10::48067\n").unwrap();
+        let release = MovieLens::detect(&dir).unwrap();
+        assert!(release == MovieLens(Release::Ml1m));
+        let raw = release
+            .read(&dir, &mut Rejects::new(ParseMode::Strict))
+            .unwrap();
+        assert!(raw.scale == RatingScale::NETFLIX && raw.transactions[0].rating == Some(5f32));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
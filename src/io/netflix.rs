@@ -0,0 +1,329 @@
+//! The Netflix Prize distribution as it was downloaded:
+//!
+//! - `training_set/mv_0000001.txt` ... `mv_0017770.txt`, one per movie,
+//!   a `<movie id>:` line followed by `<customer id>,<rating>,<date>`.
+//! - `qualifying.txt`, blocks of a `<movie id>:` line followed by
+//!   `<customer id>,<date>` lines whose ratings are to be predicted.
+//! - `probe.txt`, blocks of a `<movie id>:` line followed by
+//!   `<customer id>` lines, a subset of the training set whose
+//!   distribution matches `qualifying.txt`.
+//! - `movie_titles.txt`, `<movie id>,<year>,<title>` in ISO-8859-1, where
+//!   titles have unquoted commas and the year may be `NULL`.
+use super::*;
+
+use log::warn;
+use rayon::prelude::*;
+use std::{collections::HashSet, fs};
+
+const TRAINING_SET: &str = "training_set";
+const QUALIFYING: &str = "qualifying.txt";
+const PROBE: &str = "probe.txt";
+const MOVIE_TITLES: &str = "movie_titles.txt";
+
+/// Whether `path` holds the original distribution instead of our CSVs.
+pub fn is_netflix_prize(path: &Path) -> bool {
+    path.join(TRAINING_SET).is_dir()
+}
+
+/// The original distribution. The ratings listed in `probe.txt` are moved
+/// to the end of the training data and held out for cross validation.
+#[derive(Debug)]
+pub struct NetflixPrize;
+
+impl DataFormat for NetflixPrize {
+    /// `probe.txt` is only a source if there is one.
+    fn sources(&self, path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut sources = training_files(path)?;
+        sources.push(path.join(QUALIFYING));
+        sources.push(path.join(MOVIE_TITLES));
+        if path.join(PROBE).is_file() {
+            sources.push(path.join(PROBE));
+        }
+        Ok(sources)
+    }
+    fn read(&self, path: &Path, rejects: &mut Rejects) -> Result<RawData, Box<dyn Error>> {
+        read(path, rejects)
+    }
+}
+
+fn training_files(path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = fs::read_dir(path.join(TRAINING_SET))?
+        .map(|entry| Ok(entry?.path()))
+        .filter(|p: &Result<PathBuf, Box<dyn Error>>| match p {
+            Ok(p) => p.extension().is_some_and(|e| e == "txt"),
+            Err(_) => true,
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+    Ok(files)
+}
+
+fn read(path: &Path, rejects: &mut Rejects) -> Result<RawData, Box<dyn Error>> {
+    info!("Loading the Netflix Prize distribution from {:?}", path);
+    let (elapsed, ret) = measure_time(|| -> Result<RawData, Box<dyn Error>> {
+        let mode = rejects.mode;
+        let files = training_files(path)?
+            .par_iter()
+            .map(|file| {
+                let mut file_rejects = Rejects::new(mode);
+                read_training_file(file, &mut file_rejects).map(|t| (t, file_rejects))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut transactions = vec![];
+        for (file_transactions, file_rejects) in files {
+            transactions.extend(file_transactions);
+            rejects.merge(file_rejects);
+        }
+        let test_data = read_qualifying(&path.join(QUALIFYING), rejects)?;
+        let movies = read_movie_titles(&path.join(MOVIE_TITLES), rejects)?;
+        let (transactions, num_cross_valid) = if path.join(PROBE).is_file() {
+            let probe = read_probe(&path.join(PROBE), rejects)?;
+            let (mut train, cross_valid): (Vec<_>, Vec<_>) = transactions
+                .into_iter()
+                .partition(|t| !probe.contains(&(t.movie_id, t.customer_id)));
+            if cross_valid.len() < probe.len() {
+                warn!(
+                    "{} probe ratings are not in the training set",
+                    probe.len() - cross_valid.len()
+                );
+            }
+            let num_cross_valid = cross_valid.len();
+            train.extend(cross_valid);
+            (train, Some(num_cross_valid))
+        } else {
+            warn!(
+                "No {} in {:?}, cross validating on the last movies instead",
+                PROBE, path
+            );
+            (transactions, None)
+        };
+        let movie_ids = netflix_movie_ids(&movies, &transactions, &test_data);
+        Ok(RawData::new(
+            transactions,
+            test_data,
+            movies,
+            movie_ids,
+            RatingScale::NETFLIX,
+            num_cross_valid,
+        ))
+    });
+    info!("Elapsed {}", elapsed);
+    ret
+}
+
+/// Call `f(movie_id, fields)` on every line of a file made of
+/// `<movie id>:` blocks, movie ids counting from 0. A broken `<movie id>:`
+/// line fails even in lenient mode, otherwise the lines after it would go
+/// to the previous movie.
+fn for_each_line<F>(file: &Path, rejects: &mut Rejects, mut f: F) -> Result<(), CsvError>
+where
+    F: FnMut(usize, &[&str]) -> Result<(), FieldError>,
+{
+    let text = fs::read_to_string(file).map_err(|source| CsvError::Io {
+        file: file.to_path_buf(),
+        source,
+    })?;
+    let field_error = |line, source| CsvError::Field {
+        file: file.to_path_buf(),
+        line,
+        source,
+    };
+    let mut movie_id = None;
+    for (i, line) in text.lines().enumerate() {
+        let (line, line_number) = (line.trim(), i as u64 + 1);
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(id) = line.strip_suffix(':') {
+            let id = parse_field(Some(id), 0, parse_movie_id)
+                .map_err(|source| field_error(line_number, source))?;
+            movie_id = Some(id);
+            continue;
+        }
+        let ret = match movie_id {
+            Some(movie_id) => f(movie_id, &line.split(',').collect::<Vec<_>>())
+                .map_err(|source| field_error(line_number, source)),
+            None => Err(CsvError::Malformed {
+                file: file.to_path_buf(),
+                line: line_number,
+                reason: "no `<movie id>:` line before".to_string(),
+            }),
+        };
+        if let Err(err) = ret {
+            rejects.reject(err)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_training_file(file: &Path, rejects: &mut Rejects) -> Result<Vec<Transaction>, CsvError> {
+    let mut transactions = vec![];
+    for_each_line(file, rejects, |movie_id, fields| {
+        transactions.push(Transaction {
+            movie_id,
+            customer_id: field(fields.first().copied(), 0)?,
+            rating: Some(parse_field(fields.get(1).copied(), 1, |s| {
+                RatingScale::NETFLIX.parse(s)
+            })?),
+            date: parse_field(fields.get(2).copied(), 2, parse_day)?,
+        });
+        Ok(())
+    })?;
+    Ok(transactions)
+}
+
+fn read_qualifying(file: &Path, rejects: &mut Rejects) -> Result<Vec<Transaction>, CsvError> {
+    let mut transactions = vec![];
+    for_each_line(file, rejects, |movie_id, fields| {
+        transactions.push(Transaction {
+            movie_id,
+            customer_id: field(fields.first().copied(), 0)?,
+            rating: None,
+            date: parse_field(fields.get(1).copied(), 1, parse_day)?,
+        });
+        Ok(())
+    })?;
+    Ok(transactions)
+}
+
+/// (movie id, customer id) of every probe rating.
+fn read_probe(file: &Path, rejects: &mut Rejects) -> Result<HashSet<(usize, usize)>, CsvError> {
+    let mut probe = HashSet::new();
+    for_each_line(file, rejects, |movie_id, fields| {
+        probe.insert((movie_id, field(fields.first().copied(), 0)?));
+        Ok(())
+    })?;
+    Ok(probe)
+}
+
+fn read_movie_titles(file: &Path, rejects: &mut Rejects) -> Result<Vec<Movie>, CsvError> {
+    // ISO-8859-1 maps every byte to the code point of the same value.
+    let text: String = fs::read(file)
+        .map_err(|source| CsvError::Io {
+            file: file.to_path_buf(),
+            source,
+        })?
+        .into_iter()
+        .map(char::from)
+        .collect();
+    let mut movies = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.trim_end().splitn(3, ',').collect();
+        let movie = || -> Result<Movie, FieldError> {
+            Ok(Movie {
+                movie_id: parse_field(fields.first().copied(), 0, parse_movie_id)?,
+                year_produced: parse_field(fields.get(1).copied(), 1, parse_year)?,
+                title: fields
+                    .get(2)
+                    .ok_or(FieldError::Missing { column: 2 })?
+                    .to_string(),
+                genres: vec![],
+            })
+        };
+        match movie() {
+            Ok(movie) => movies.push(movie),
+            Err(source) => rejects.reject(CsvError::Field {
+                file: file.to_path_buf(),
+                line: i as u64 + 1,
+                source,
+            })?,
+        }
+    }
+    Ok(movies)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_read_netflix_prize() {
+        let dir = std::env::temp_dir().join(format!("netflix_test_{}", std::process::id()));
+        fs::create_dir_all(dir.join(TRAINING_SET)).unwrap();
+        fs::write(
+            dir.join(TRAINING_SET).join("mv_0000001.txt"),
+            "1:\n10,5,2005-09-06\n20,3,2005-05-13\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(TRAINING_SET).join("mv_0000002.txt"),
+            "2:\n20,1,2004-02-29\n30,4,2005-12-31\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(QUALIFYING),
+            "1:\n30,2005-12-19\n2:\n40,2005-12-20\n",
+        )
+        .unwrap();
+        fs::write(dir.join(PROBE), "1:\n20\n").unwrap();
+        fs::write(
+            dir.join(MOVIE_TITLES),
+            b"1,2003,Dinosaur Planet\n2,NULL,Am\xe9lie, Part 1, Part 2\n",
+        )
+        .unwrap();
+        assert!(is_netflix_prize(&dir));
+        assert!(NetflixPrize.sources(&dir).unwrap().len() == 5);
+
+        let raw = read(&dir, &mut Rejects::new(ParseMode::Strict)).unwrap();
+        // Customer 20's rating of movie 1 is moved to the end.
+        let ratings: Vec<_> = raw
+            .transactions
+            .iter()
+            .map(|t| {
+                (
+                    t.movie_id,
+                    raw.customer_ids.to_original(t.customer_id),
+                    t.rating,
+                )
+            })
+            .collect();
+        assert!(
+            ratings
+                == vec![
+                    (0, 10, Some(5f32)),
+                    (1, 20, Some(1f32)),
+                    (1, 30, Some(4f32)),
+                    (0, 20, Some(3f32))
+                ]
+        );
+        assert!(raw.num_cross_valid == Some(1));
+        assert!(raw.transactions[1].date == parse_day("2004-02-29").unwrap());
+        let tests: Vec<_> = raw
+            .test_data
+            .iter()
+            .map(|t| {
+                (
+                    t.movie_id,
+                    raw.customer_ids.to_original(t.customer_id),
+                    t.rating,
+                )
+            })
+            .collect();
+        assert!(tests == vec![(0, 30, None), (1, 40, None)]);
+        assert!(raw.movies[0].year_produced == 2003);
+        assert!(raw.movies[1].year_produced == 0);
+        assert!(raw.movies[1].title == "Amélie, Part 1, Part 2");
+        assert!(raw.movie_ids.originals() == [1, 2]);
+
+        // A probe line without a movie, and a rating out of range.
+        fs::write(dir.join(PROBE), "20\n").unwrap();
+        fs::write(
+            dir.join(TRAINING_SET).join("mv_0000002.txt"),
+            "2:\n20,1,2004-02-29\n30,6,2005-12-31\n",
+        )
+        .unwrap();
+        assert!(read(&dir, &mut Rejects::new(ParseMode::Strict)).is_err());
+        let mut rejects = Rejects::new(ParseMode::Lenient);
+        let raw = read(&dir, &mut rejects).unwrap();
+        assert!(raw.transactions.len() == 3 && rejects.total() == 2);
+        assert!(rejects.examples[0]
+            .to_string()
+            .ends_with("line 3, column 2: \"6\" is invalid, 6 is not a rating of 1:5:1"));
+        // A broken movie line always fails.
+        fs::write(dir.join(PROBE), "x:\n20\n").unwrap();
+        assert!(read(&dir, &mut Rejects::new(ParseMode::Lenient)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Writers of predictions, in which customers and movies go by the ids of
+//! the data files, see `IdMapper`.
+use super::*;
+
+use serde::Serialize;
+use std::io::BufWriter;
+
+/// How predictions are written, see `$OUTPUT_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// One bare prediction per line, in the order of the test data.
+    Plain,
+    /// The Netflix Prize submission, a `<movie id>:` line followed by the
+    /// predictions of that movie, movies in the order they first appear in
+    /// the test data and predictions in the order of the test data.
+    Submission,
+    /// `movie_id,customer_id,date,prediction` with a header line.
+    Csv,
+    /// One JSON object per line, with the same fields as `Csv`.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "plain" => Ok(Self::Plain),
+            "submission" => Ok(Self::Submission),
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => Err(format!(
+                "Unknown output format {:?}, expecting plain, submission, csv or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// A row of `OutputFormat::Csv` and `OutputFormat::JsonLines`.
+#[derive(Debug, Serialize)]
+struct Prediction {
+    movie_id: usize,
+    customer_id: usize,
+    date: String,
+    prediction: Rating,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Plain | Self::Submission => "txt",
+            Self::Csv => "csv",
+            Self::JsonLines => "jsonl",
+        }
+    }
+
+    /// Write the `predictions` of `test_data`, which must be as many.
+    pub fn write(
+        self,
+        data: &Data,
+        test_data: &[Transaction],
+        predictions: &[Rating],
+        writer: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        if test_data.len() != predictions.len() {
+            return Err(format!(
+                "{} predictions for {} transactions",
+                predictions.len(),
+                test_data.len()
+            )
+            .into());
+        }
+        let rows = || {
+            test_data
+                .iter()
+                .zip(predictions)
+                .map(|(t, &prediction)| Prediction {
+                    movie_id: data.movie_ids.to_original(t.movie_id),
+                    customer_id: data.customer_ids.to_original(t.customer_id),
+                    date: format_day(t.date),
+                    prediction,
+                })
+        };
+        match self {
+            Self::Plain => {
+                for prediction in predictions {
+                    writeln!(writer, "{}", prediction)?;
+                }
+            }
+            Self::Submission => {
+                let mut movies = IdMapper::new();
+                let mut groups: Vec<Vec<Rating>> = vec![];
+                for (t, &prediction) in test_data.iter().zip(predictions) {
+                    let i = movies.get_or_insert(t.movie_id);
+                    if i == groups.len() {
+                        groups.push(vec![]);
+                    }
+                    groups[i].push(prediction);
+                }
+                for (&movie_id, group) in movies.originals().iter().zip(groups) {
+                    writeln!(writer, "{}:", data.movie_ids.to_original(movie_id))?;
+                    for prediction in group {
+                        writeln!(writer, "{}", prediction)?;
+                    }
+                }
+            }
+            Self::Csv => {
+                let mut wtr = csv::Writer::from_writer(writer);
+                for row in rows() {
+                    wtr.serialize(row)?;
+                }
+                wtr.flush()?;
+            }
+            Self::JsonLines => {
+                for row in rows() {
+                    serde_json::to_writer(&mut *writer, &row)?;
+                    writeln!(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write to `<name>.<extension>` in `out`, which is returned.
+    pub fn write_file(
+        self,
+        out: &Path,
+        name: &str,
+        data: &Data,
+        test_data: &[Transaction],
+        predictions: &[Rating],
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let path = out.join(format!("{}.{}", name, self.extension()));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        self.write(data, test_data, predictions, &mut writer)?;
+        writer.flush()?;
+        info!("Predictions written to {:?} as {:?}", path, self);
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_write_predictions() {
+        let transaction = |movie_id, customer_id, rating| Transaction {
+            movie_id,
+            customer_id,
+            rating,
+            date: 12000,
+        };
+        let mut train = vec![
+            transaction(30, 7, Some(4f32)),
+            transaction(10, 9, Some(2f32)),
+        ];
+        let mut test = vec![
+            transaction(10, 9, None),
+            transaction(30, 9, None),
+            transaction(10, 7, None),
+        ];
+        let (movies, movie_ids) = densify_movies(vec![], &mut train, &mut test);
+        let data = Data::from_raw(RawData::new(
+            train,
+            test,
+            movies,
+            movie_ids,
+            RatingScale::HALF_STARS,
+            None,
+        ));
+        let write = |format: OutputFormat| {
+            let mut out = vec![];
+            format
+                .write(&data, &data.test_data, &[3.5, 1f32, 5f32], &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(write(OutputFormat::Plain) == "3.5\n1\n5\n");
+        assert!(write(OutputFormat::Submission) == "10:\n3.5\n5\n30:\n1\n");
+        assert!(
+            write(OutputFormat::Csv)
+                == "movie_id,customer_id,date,prediction\n\
+                    10,9,2002-11-09,3.5\n30,9,2002-11-09,1.0\n10,7,2002-11-09,5.0\n"
+        );
+        assert!(write(OutputFormat::JsonLines).starts_with(
+            "{\"movie_id\":10,\"customer_id\":9,\"date\":\"2002-11-09\",\"prediction\":3.5}\n"
+        ));
+        let mut out = vec![];
+        assert!(OutputFormat::Plain
+            .write(&data, &data.test_data, &[], &mut out)
+            .is_err());
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -23,6 +23,9 @@
 /// The config for the models are put in their code, NOT here.
 mod config;
 
+/// Command line interface.
+mod cli;
+
 /// Deals with all data.
 mod data;
 
@@ -35,20 +38,33 @@ mod plot;
 /// Handles input/output of the data.
 mod io;
 
+/// Binary cache of the parsed dataset.
+mod cache;
+
 /// Any common algorithms go here.
 mod algorithm;
 
-use log::{error, info, warn};
-use std::{env, path::Path, process};
+/// Similarity measures between items.
+mod similarity;
+
+/// Records of past runs, and their comparison.
+mod tracking;
+
+/// Human readable reports, e.g. which movies cluster together.
+mod report;
 
-use crate::data::Data;
-use crate::io::DumpToFile;
-use crate::models::ModelHolder;
+use log::error;
+use std::{env, process};
+use structopt::StructOpt;
 
 extern crate pretty_env_logger;
 
 /// All the dirty work goes here.
 fn main() {
+    let opt = cli::Opt::from_args();
+    if let Some(level) = &opt.log_level {
+        env::set_var(config::RUST_LOG, level);
+    }
     // By default we show all logs.
     if env::var(config::RUST_LOG).is_err() {
         env::set_var(config::RUST_LOG, "trace");
@@ -56,61 +72,8 @@ fn main() {
     // Init logger.
     pretty_env_logger::init();
 
-    let data_path = match env::var(config::DATA_PATH) {
-        Ok(val) => Path::new(&val).to_path_buf(),
-        Err(_) => {
-            warn!(
-                "${} not set, using ${}/data/ as default.",
-                config::DATA_PATH,
-                config::RECOMMEND_HOME
-            );
-            match env::var(config::RECOMMEND_HOME) {
-                Ok(val) => Path::new(&val).join("data"),
-                Err(_) => {
-                    error!("${} not set.", config::RECOMMEND_HOME);
-                    process::exit(1);
-                }
-            }
-        }
-    };
-
-    let data = match Data::new(data_path) {
-        Ok(d) => d,
-        Err(err) => {
-            error!("error running example: {}", err);
-            process::exit(1);
-        }
-    };
-    let metadata = &data.metadata;
-    let (num_trans, num_tests) = metadata
-        .trans_freq
-        .iter()
-        .zip(metadata.tests_freq.iter())
-        .fold((0, 0), |(num_trans, num_tests), (&trans, &tests)| {
-            (num_trans + trans, num_tests + tests)
-        });
-    info!("Retrived metadata");
-    info!(
-        "Total # customers: {}, # movies: {}",
-        metadata.num_customers, metadata.num_movies
-    );
-    info!(
-        "Total # of transactions: {}, # of tests: {}",
-        num_trans, num_tests
-    );
-
-    /*
-    plot::plot_data_freq(metadata).expect("Cannot plot freq histogram.");
-
-    plot::plot_initial_matrix(&data).expect("Cannot plot initial matrix.");
-    info!("Initial matrix plotted.");
-    */
-    for model_holder in inventory::iter::<ModelHolder> {
-        let mut model = model_holder.get_model();
-        model
-            .init(&data)
-            .train()
-            .predict_all(&data.test_data)
-            .dump_to_file(format!("{}.txt", model_holder.get_name()));
+    if let Err(err) = cli::run(&opt) {
+        error!("{}", err);
+        process::exit(1);
     }
 }
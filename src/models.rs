@@ -1,15 +1,39 @@
+/// Fallback predictions for customers and movies without ratings.
+pub mod cold_start;
+/// Model configurations read from experiment files.
+pub mod experiment;
+/// Item based k nearest neighbours.
+pub mod knn;
 /// Matrix completion.
 pub mod matrix_completion;
+/// Mixture model clustering of customers.
+pub mod mixture;
+/// Saving and loading trained models.
+pub mod persist;
+/// PureSVD, a truncated SVD of the ratings.
+pub mod pure_svd;
+/// Grid and random search over hyperparameters.
+pub mod search;
 /// Spectral clustering.
 pub mod spectral_clustering;
 
 use elapsed::measure_time;
 use log::*;
-use std::fmt::Debug;
+use rayon::prelude::*;
+use std::{
+    error::Error,
+    fmt::Debug,
+    io::{Read, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use nalgebra::core::DMatrix;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+use crate::config;
 use crate::data::*;
+use experiment::{merge_params, to_params, ModelConfig};
 
 /// `DefaultModel` generate uninitialized `Model`.
 pub trait DefaultModel: Model {
@@ -44,14 +68,66 @@ impl ModelHolder {
     pub fn get_name(&self) -> &'static str {
         self.inner.get_name()
     }
+    pub fn get_tags(&self) -> &'static [&'static str] {
+        self.inner.tags()
+    }
+    /// Names of every registered model.
+    pub fn names() -> Vec<&'static str> {
+        inventory::iter::<ModelHolder>
+            .into_iter()
+            .map(ModelHolder::get_name)
+            .collect()
+    }
+    /// The registered holder of the model named `name`.
+    pub fn find(name: &str) -> Option<&'static ModelHolder> {
+        inventory::iter::<ModelHolder>
+            .into_iter()
+            .find(|holder| holder.get_name() == name)
+    }
+    /// A `Model::default()` of `config.model`, configured by
+    /// `config.params`.
+    pub fn from_config(config: &ModelConfig) -> Result<Box<dyn Model>, Box<dyn Error>> {
+        let holder = Self::find(&config.model).ok_or_else(|| {
+            format!(
+                "Unknown model {:?}, expecting one of {}",
+                config.model,
+                Self::names().join(", ")
+            )
+        })?;
+        let mut model = holder.get_model();
+        model
+            .configure(&config.params)
+            .map_err(|err| format!("{}: {}", config.name, err))?;
+        Ok(model)
+    }
 }
 
 /// `Model` is a public trait where all necessary functions
-/// for a model should be implemented here.
-pub trait Model {
+/// for a model should be implemented here. Models are `Sync` so that
+/// trained ones predict in parallel.
+pub trait Model: Sync {
     fn get_name(&self) -> &'static str {
         "GenericModel"
     }
+    /// What kind of model it is, `baseline`, `slow` or `experimental`,
+    /// so that a kind can be selected or left out, see
+    /// `experiment::select`.
+    fn tags(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Hyperparameters, see `configure`.
+    fn config(&self) -> Map<String, Value> {
+        Map::new()
+    }
+    /// Override hyperparameters before `init`, those missing from `params`
+    /// keep their value. See `experiment::merge_params`.
+    fn configure(&mut self, params: &Map<String, Value>) -> Result<(), Box<dyn Error>> {
+        if params.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{} has no hyperparameters", self.get_name()).into())
+        }
+    }
     /// Initialize a `Model` with given `Data`. `Model` do not need a
     /// `new()` method but should be `Default` for factory pattern.
     fn init(&mut self, data: &Data) -> &mut dyn Model;
@@ -59,9 +135,181 @@ pub trait Model {
     fn train(&mut self) -> &mut dyn Model;
     /// Given one `Transaction`, predict the `Rating`.
     fn predict(&self, trans: &Transaction) -> Rating;
-    fn predict_all(&self, test_data: &Vec<Transaction>) -> Vec<Rating> {
-        test_data.iter().map(|t| self.predict(t)).collect()
+    /// `predict` every transaction, in parallel, see `predict_batch`.
+    fn predict_all(&self, test_data: &[Transaction]) -> Vec<Rating> {
+        predict_batch(
+            self,
+            test_data,
+            config::from_env(config::PREDICT_CHUNK, 10_000),
+        )
+    }
+    /// Objective after every iteration of training, for models that
+    /// iterate.
+    fn training_curve(&self) -> &[f64] {
+        &[]
+    }
+    /// Fold the ratings of `new` in without retraining, customers past
+    /// those of training being new ones. Ratings of movies unseen in
+    /// training are left out, see `new_ratings`.
+    fn update(&mut self, _new: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        Err(format!("{} cannot be updated, retrain it", self.get_name()).into())
+    }
+    /// m x d learned representation of the movies, row `j` being movie
+    /// `j`, for models that have one.
+    fn movie_embedding(&self) -> Option<&DMatrix<f64>> {
+        None
+    }
+    /// Write the trained state, hyperparameters included, so that `load`
+    /// can skip `init` and `train`. See `persist::save_model`.
+    fn save(&self, _writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        Err(format!("{} cannot be saved", self.get_name()).into())
+    }
+    /// Restore the state written by `save`.
+    fn load(&mut self, _reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        Err(format!("{} cannot be loaded", self.get_name()).into())
     }
 }
 
 inventory::collect!(ModelHolder);
+
+/// Average rating of every movie (column) of a customer x movie matrix,
+/// and the global average. Movies nobody rated get the global average.
+pub fn rating_averages(customer_movie: &SparseMatrix) -> (Vec<f64>, f64) {
+    let global_avg =
+        customer_movie.values.iter().sum::<f64>() / usize::max(customer_movie.nnz(), 1) as f64;
+    let mut sum = vec![0f64; customer_movie.ncols];
+    let mut cnt = vec![0usize; customer_movie.ncols];
+    customer_movie
+        .indices
+        .iter()
+        .zip(&customer_movie.values)
+        .for_each(|(&j, &v)| {
+            sum[j] += v;
+            cnt[j] += 1;
+        });
+    let movie_avg = sum
+        .into_iter()
+        .zip(cnt)
+        .map(|(s, c)| if c == 0 { global_avg } else { s / c as f64 })
+        .collect();
+    (movie_avg, global_avg)
+}
+
+/// The known ratings of `new` as `(customer, movie, rating)` triplets, for
+/// movies before `num_movies` only, and the customers they rate, sorted.
+pub fn new_ratings(
+    new: &[Transaction],
+    num_movies: usize,
+) -> (Vec<(usize, usize, f64)>, Vec<usize>) {
+    let triplets: Vec<(usize, usize, f64)> = new
+        .iter()
+        .filter(|t| t.movie_id < num_movies)
+        .filter_map(|t| t.rating.map(|r| (t.customer_id, t.movie_id, r as f64)))
+        .collect();
+    let skipped = new.len() - triplets.len();
+    if skipped > 0 {
+        warn!(
+            "Left out {} new transactions of unknown movies or ratings",
+            skipped
+        );
+    }
+    let mut customers: Vec<usize> = triplets.iter().map(|&(i, _, _)| i).collect();
+    customers.sort_unstable();
+    customers.dedup();
+    (triplets, customers)
+}
+
+/// `model.predict` every transaction of `test_data`, in order. Chunks of
+/// `chunk_size` transactions are predicted in parallel, and progress is
+/// logged every tenth of the way if there are several chunks.
+pub fn predict_batch<M: Model + ?Sized>(
+    model: &M,
+    test_data: &[Transaction],
+    chunk_size: usize,
+) -> Vec<Rating> {
+    let chunk_size = chunk_size.max(1);
+    let total = test_data.len();
+    let step = (total / 10).max(1);
+    let done = AtomicUsize::new(0);
+    let (elapsed, chunks) = measure_time(|| {
+        test_data
+            .par_chunks(chunk_size)
+            .map(|chunk| {
+                let predictions: Vec<Rating> = chunk.iter().map(|t| model.predict(t)).collect();
+                let before = done.fetch_add(chunk.len(), Ordering::Relaxed);
+                let after = before + chunk.len();
+                if total > chunk_size && after / step > before / step {
+                    info!(
+                        "{} predicted {} of {} ({:.0}%)",
+                        model.get_name(),
+                        after,
+                        total,
+                        100f64 * after as f64 / total as f64
+                    );
+                }
+                predictions
+            })
+            .collect::<Vec<_>>()
+    });
+    debug!(
+        "{} predicted {} transactions... elapsed: {}",
+        model.get_name(),
+        total,
+        elapsed
+    );
+    chunks.concat()
+}
+
+/// Root mean squared error of `model` over the `transactions` whose rating
+/// is known, NaN if there is none.
+pub fn rmse(model: &dyn Model, transactions: &[Transaction]) -> f64 {
+    let predictions = model.predict_all(transactions);
+    let (sum, cnt) = transactions
+        .iter()
+        .zip(predictions)
+        .filter_map(|(t, p)| t.rating.map(|r| (p, r)))
+        .fold((0f64, 0usize), |(sum, cnt), (p, r)| {
+            let err = f64::from(p) - f64::from(r);
+            (sum + err * err, cnt + 1)
+        });
+    (sum / cnt as f64).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Predicts the id of the movie.
+    #[derive(Debug, Default)]
+    struct MovieId;
+
+    impl Model for MovieId {
+        fn init(&mut self, _data: &Data) -> &mut dyn Model {
+            self
+        }
+        fn train(&mut self) -> &mut dyn Model {
+            self
+        }
+        fn predict(&self, trans: &Transaction) -> Rating {
+            trans.movie_id as Rating
+        }
+    }
+
+    #[test]
+    fn test_predict_batch() {
+        let test_data: Vec<Transaction> = (0..1000)
+            .map(|movie_id| Transaction {
+                movie_id,
+                customer_id: 0,
+                rating: Some(movie_id as Rating + 2f32),
+                date: 0,
+            })
+            .collect();
+        let expected: Vec<Rating> = (0..1000).map(|i| i as Rating).collect();
+        for &chunk_size in &[0, 1, 7, 1000, 5000] {
+            assert!(predict_batch(&MovieId, &test_data, chunk_size) == expected);
+        }
+        assert!(predict_batch(&MovieId, &[], 10).is_empty());
+        assert!(rmse(&MovieId, &test_data) == 2f64);
+    }
+}
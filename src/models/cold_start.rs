@@ -0,0 +1,524 @@
+//! Predictions for customers and movies without a training rating
+//!
+//! Models are trained on customers and movies that have ratings, and have
+//! no defined behaviour for the others, e.g. customers of the test data
+//! missing from the training data. `ColdStart` wraps any `Model` and
+//! predicts those from a chain of fallbacks instead, the first one that
+//! knows enough giving the prediction, e.g.
+//! `customer_bias,movie_mean,year_prior,global_mean`.
+use super::*;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+/// Means are shrunk towards their prior as if it had been rated this many
+/// times, so that a movie rated once does not get its one rating.
+const SHRINKAGE: f64 = 10f64;
+
+/// A prediction that needs less than a trained model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fallback {
+    /// What the rest of the chain predicts, plus how much better than the
+    /// movie means the customer rates, if the customer has ratings.
+    CustomerBias,
+    /// Mean rating of the movie, if it has ratings.
+    MovieMean,
+    /// Mean rating of the movies produced the same year, see
+    /// `Movie::year_produced`, if any of them has ratings.
+    YearPrior,
+    /// Mean of every rating, always known.
+    GlobalMean,
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "customer_bias" => Ok(Fallback::CustomerBias),
+            "movie_mean" => Ok(Fallback::MovieMean),
+            "year_prior" => Ok(Fallback::YearPrior),
+            "global_mean" => Ok(Fallback::GlobalMean),
+            _ => Err(format!(
+                "Unknown fallback {:?}, try customer_bias, movie_mean, year_prior or global_mean",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Fallback::CustomerBias => "customer_bias",
+            Fallback::MovieMean => "movie_mean",
+            Fallback::YearPrior => "year_prior",
+            Fallback::GlobalMean => "global_mean",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Fallbacks in the order they are tried, `none` if models predict cold
+/// customers and movies themselves. The global mean ends every chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackChain(pub Vec<Fallback>);
+
+impl Default for FallbackChain {
+    fn default() -> Self {
+        FallbackChain(vec![
+            Fallback::CustomerBias,
+            Fallback::MovieMean,
+            Fallback::YearPrior,
+            Fallback::GlobalMean,
+        ])
+    }
+}
+
+/// Parses comma separated fallbacks, or `none`.
+impl FromStr for FallbackChain {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "none" => Ok(FallbackChain(vec![])),
+            s => s
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map(FallbackChain),
+        }
+    }
+}
+
+impl fmt::Display for FallbackChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let names: Vec<String> = self.0.iter().map(Fallback::to_string).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Sums and counts of the training ratings, from which the fallbacks are
+/// estimated.
+#[derive(Debug, Clone, Default)]
+pub struct Priors {
+    global_sum: f64,
+    global_cnt: usize,
+    movie_sum: Vec<f64>,
+    movie_cnt: Vec<usize>,
+    /// Sum of the ratings of every customer minus the movie means.
+    customer_residual: Vec<f64>,
+    customer_cnt: Vec<usize>,
+    /// Year produced of every movie, 0 if unknown.
+    years: Vec<u16>,
+    year_sum: BTreeMap<u16, f64>,
+    year_cnt: BTreeMap<u16, usize>,
+    /// Rating and residual of every rating folded in by `update`, by
+    /// customer and movie, so that a later one can replace it.
+    folded: HashMap<(usize, usize), (f64, f64)>,
+}
+
+impl Priors {
+    /// From the training ratings of `data` and the ratings folded in since,
+    /// which replace the training ratings of the same customer and movie.
+    pub fn new(data: &Data, updates: &[Transaction]) -> Self {
+        let num_movies = data.movie_ids.len().max(data.movies.len());
+        let mut years = vec![0; num_movies];
+        data.movies
+            .iter()
+            .for_each(|m| years[m.movie_id] = m.year_produced);
+        let mut priors = Self {
+            movie_sum: vec![0f64; num_movies],
+            movie_cnt: vec![0; num_movies],
+            years,
+            ..Default::default()
+        };
+        let replaced: HashSet<(usize, usize)> = updates
+            .iter()
+            .map(|t| (t.customer_id, t.movie_id))
+            .collect();
+        let train = || {
+            data.train()
+                .iter()
+                .filter(|t| !replaced.contains(&(t.customer_id, t.movie_id)))
+        };
+        priors.add_movies(train());
+        priors.add_customers(train());
+        priors.update(updates);
+        priors
+    }
+
+    /// Add `new` to the movie and year sums.
+    fn add_movies<'a>(&mut self, new: impl Iterator<Item = &'a Transaction>) {
+        for t in new {
+            if let (Some(r), true) = (t.rating, t.movie_id < self.movie_sum.len()) {
+                let r = r as f64;
+                self.global_sum += r;
+                self.global_cnt += 1;
+                self.movie_sum[t.movie_id] += r;
+                self.movie_cnt[t.movie_id] += 1;
+                let year = self.years[t.movie_id];
+                if year != 0 {
+                    *self.year_sum.entry(year).or_insert(0f64) += r;
+                    *self.year_cnt.entry(year).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    /// Add `new` to the customer residuals, against the current movie means.
+    fn add_customers<'a>(&mut self, new: impl Iterator<Item = &'a Transaction>) {
+        for t in new {
+            if let (Some(r), true) = (t.rating, t.movie_id < self.movie_sum.len()) {
+                if t.customer_id >= self.customer_cnt.len() {
+                    self.customer_residual.resize(t.customer_id + 1, 0f64);
+                    self.customer_cnt.resize(t.customer_id + 1, 0);
+                }
+                let movie_mean = self.movie_mean(t.movie_id).unwrap_or(self.global_mean());
+                self.customer_residual[t.customer_id] += r as f64 - movie_mean;
+                self.customer_cnt[t.customer_id] += 1;
+            }
+        }
+    }
+
+    /// Take out rating `r` of `customer` and `movie`, whose residual was
+    /// `residual`.
+    fn remove(&mut self, customer: usize, movie: usize, r: f64, residual: f64) {
+        self.global_sum -= r;
+        self.global_cnt -= 1;
+        self.movie_sum[movie] -= r;
+        self.movie_cnt[movie] -= 1;
+        let year = self.years[movie];
+        if year != 0 {
+            *self.year_sum.get_mut(&year).unwrap() -= r;
+            *self.year_cnt.get_mut(&year).unwrap() -= 1;
+        }
+        self.customer_residual[customer] -= residual;
+        self.customer_cnt[customer] -= 1;
+    }
+
+    /// Fold new ratings in. They replace the ratings folded in before for
+    /// the same customer and movie, but are added to training ratings until
+    /// the next `Priors::new`. Residuals of earlier ratings are not updated
+    /// to the new movie means.
+    pub fn update(&mut self, new: &[Transaction]) {
+        // Only the last rating of a customer and movie counts.
+        let mut seen = HashSet::new();
+        let num_movies = self.movie_sum.len();
+        let mut new: Vec<&Transaction> = new
+            .iter()
+            .rev()
+            .filter(|t| t.rating.is_some() && t.movie_id < num_movies)
+            .filter(|t| seen.insert((t.customer_id, t.movie_id)))
+            .collect();
+        new.reverse();
+        for t in &new {
+            if let Some((r, residual)) = self.folded.remove(&(t.customer_id, t.movie_id)) {
+                self.remove(t.customer_id, t.movie_id, r, residual);
+            }
+        }
+        self.add_movies(new.iter().copied());
+        self.add_customers(new.iter().copied());
+        for t in new {
+            let r = t.rating.unwrap() as f64;
+            let movie_mean = self.movie_mean(t.movie_id).unwrap_or(self.global_mean());
+            self.folded
+                .insert((t.customer_id, t.movie_id), (r, r - movie_mean));
+        }
+    }
+
+    pub fn global_mean(&self) -> f64 {
+        if self.global_cnt == 0 {
+            0f64
+        } else {
+            self.global_sum / self.global_cnt as f64
+        }
+    }
+
+    /// `sum / cnt` shrunk towards `prior`.
+    fn shrunk(sum: f64, cnt: usize, prior: f64) -> f64 {
+        (sum + SHRINKAGE * prior) / (cnt as f64 + SHRINKAGE)
+    }
+
+    pub fn movie_mean(&self, movie: usize) -> Option<f64> {
+        match self.movie_cnt.get(movie) {
+            Some(&cnt) if cnt > 0 => Some(Self::shrunk(
+                self.movie_sum[movie],
+                cnt,
+                self.year_prior(movie).unwrap_or(self.global_mean()),
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn year_prior(&self, movie: usize) -> Option<f64> {
+        let year = *self.years.get(movie)?;
+        let cnt = *self.year_cnt.get(&year)?;
+        Some(Self::shrunk(self.year_sum[&year], cnt, self.global_mean()))
+    }
+
+    pub fn customer_bias(&self, customer: usize) -> Option<f64> {
+        match self.customer_cnt.get(customer) {
+            Some(&cnt) if cnt > 0 => {
+                Some(self.customer_residual[customer] / (cnt as f64 + SHRINKAGE))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_cold(&self, trans: &Transaction) -> bool {
+        !matches!(self.customer_cnt.get(trans.customer_id), Some(&c) if c > 0)
+            || !matches!(self.movie_cnt.get(trans.movie_id), Some(&c) if c > 0)
+    }
+
+    /// What the first fallback of `chain` that knows enough predicts.
+    pub fn estimate(&self, chain: &[Fallback], trans: &Transaction) -> f64 {
+        let (customer, movie) = (trans.customer_id, trans.movie_id);
+        for (i, fallback) in chain.iter().enumerate() {
+            let estimate = match fallback {
+                Fallback::CustomerBias => self
+                    .customer_bias(customer)
+                    .map(|bias| self.estimate(&chain[i + 1..], trans) + bias),
+                Fallback::MovieMean => self.movie_mean(movie),
+                Fallback::YearPrior => self.year_prior(movie),
+                Fallback::GlobalMean => Some(self.global_mean()),
+            };
+            if let Some(estimate) = estimate {
+                return estimate;
+            }
+        }
+        self.global_mean()
+    }
+
+    /// Every movie, most rated first, then best rated first.
+    pub fn popular_movies(&self) -> Vec<usize> {
+        let means: Vec<f64> = (0..self.movie_cnt.len())
+            .map(|j| self.movie_mean(j).unwrap_or(f64::MIN))
+            .collect();
+        let mut movies: Vec<usize> = (0..self.movie_cnt.len()).collect();
+        movies.sort_by(|&a, &b| {
+            self.movie_cnt[b]
+                .cmp(&self.movie_cnt[a])
+                .then(means[b].partial_cmp(&means[a]).unwrap())
+                .then(a.cmp(&b))
+        });
+        movies
+    }
+}
+
+/// A `Model` that predicts cold customers and movies, those without a
+/// training rating, from a `FallbackChain` instead. Everything else is
+/// left to the wrapped model, including saving it.
+pub struct ColdStart {
+    model: Box<dyn Model>,
+    chain: FallbackChain,
+    scale: RatingScale,
+    priors: Priors,
+}
+
+impl ColdStart {
+    /// `model` alone if `chain` is `none`. `updates` are the ratings folded
+    /// in since `model` was trained on `data`.
+    pub fn wrap(
+        model: Box<dyn Model>,
+        chain: &FallbackChain,
+        data: &Data,
+        updates: &[Transaction],
+    ) -> Box<dyn Model> {
+        if chain.0.is_empty() {
+            return model;
+        }
+        let priors = Priors::new(data, updates);
+        let cold_customers = (0..data.metadata().num_customers)
+            .filter(|&u| priors.customer_bias(u).is_none())
+            .count();
+        let cold_movies = priors.movie_cnt.iter().filter(|&&c| c == 0).count();
+        info!(
+            "{} cold start: {} customers and {} movies without ratings fall back on {}",
+            model.get_name(),
+            cold_customers,
+            cold_movies,
+            chain
+        );
+        Box::new(Self {
+            model,
+            chain: chain.clone(),
+            scale: data.scale,
+            priors,
+        })
+    }
+}
+
+impl Model for ColdStart {
+    fn get_name(&self) -> &'static str {
+        self.model.get_name()
+    }
+    fn tags(&self) -> &'static [&'static str] {
+        self.model.tags()
+    }
+    fn config(&self) -> Map<String, Value> {
+        self.model.config()
+    }
+    fn configure(&mut self, params: &Map<String, Value>) -> Result<(), Box<dyn Error>> {
+        self.model.configure(params)
+    }
+    fn init(&mut self, data: &Data) -> &mut dyn Model {
+        self.priors = Priors::new(data, &[]);
+        self.scale = data.scale;
+        self.model.init(data);
+        self
+    }
+    fn train(&mut self) -> &mut dyn Model {
+        self.model.train();
+        self
+    }
+    fn predict(&self, trans: &Transaction) -> Rating {
+        if self.priors.is_cold(trans) {
+            self.scale.clamp(self.priors.estimate(&self.chain.0, trans))
+        } else {
+            self.model.predict(trans)
+        }
+    }
+    fn training_curve(&self) -> &[f64] {
+        self.model.training_curve()
+    }
+    fn update(&mut self, new: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        self.model.update(new)?;
+        self.priors.update(new);
+        Ok(())
+    }
+    fn movie_embedding(&self) -> Option<&DMatrix<f64>> {
+        self.model.movie_embedding()
+    }
+    fn save(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        self.model.save(writer)
+    }
+    fn load(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        self.model.load(reader)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Predicts 0, which no rating is.
+    #[derive(Debug, Default)]
+    struct Zero;
+
+    impl Model for Zero {
+        fn init(&mut self, _data: &Data) -> &mut dyn Model {
+            self
+        }
+        fn train(&mut self) -> &mut dyn Model {
+            self
+        }
+        fn predict(&self, _trans: &Transaction) -> Rating {
+            0f32
+        }
+    }
+
+    #[test]
+    fn test_cold_start() {
+        let transaction = |movie_id, customer_id, rating| Transaction {
+            movie_id,
+            customer_id,
+            rating,
+            date: 12000,
+        };
+        // Customer 1 rates one better than customer 0, movie 1 is the
+        // best, movie 2 shares its year and movie 3 was never rated.
+        let train = (0..20)
+            .flat_map(|i| {
+                vec![
+                    transaction(0, 100 + i % 2, Some(2f32 + (i % 2) as f32)),
+                    transaction(1, 100 + i % 2, Some(4f32 + (i % 2) as f32)),
+                ]
+            })
+            .collect();
+        let test = vec![transaction(2, 102, None), transaction(3, 101, None)];
+        let mut movie_ids = IdMapper::new();
+        let movies: Vec<Movie> = (0..4)
+            .map(|movie_id| Movie {
+                movie_id: movie_ids.get_or_insert(movie_id),
+                year_produced: [1990, 2000, 2000, 0][movie_id],
+                title: movie_id.to_string(),
+                genres: vec![],
+            })
+            .collect();
+        let data = Data::from_raw(RawData::new(
+            train,
+            test,
+            movies,
+            movie_ids,
+            RatingScale::NETFLIX,
+            Some(0),
+        ));
+        let priors = Priors::new(&data, &[]);
+        assert!(priors.global_mean() == 3.5);
+        assert!(priors.movie_mean(3).is_none());
+        assert!(priors.year_prior(1) == priors.year_prior(2));
+        assert!(priors.year_prior(3).is_none());
+        let (bias_0, bias_1) = (
+            priors.customer_bias(0).unwrap(),
+            priors.customer_bias(1).unwrap(),
+        );
+        assert!(bias_0 < 0f64 && bias_1 > 0f64 && (bias_1 + bias_0).abs() < 1e-9);
+        assert!(priors.movie_mean(0) < priors.movie_mean(1));
+        assert!(priors.popular_movies()[..2] == [1, 0]);
+
+        let chain: FallbackChain = "customer_bias,movie_mean,year_prior,global_mean"
+            .parse()
+            .unwrap();
+        assert!(
+            chain == FallbackChain::default() && chain.to_string().parse() == Ok(chain.clone())
+        );
+        assert!("none".parse() == Ok(FallbackChain(vec![])));
+        assert!("movie_mean,popularity".parse::<FallbackChain>().is_err());
+
+        let model = ColdStart::wrap(Box::new(Zero), &chain, &data, &[]);
+        let year_2000 = priors.year_prior(2).unwrap();
+        // Warm pairs are left to the model.
+        assert!(model.predict(&transaction(1, 0, None)) == 0f32);
+        // A new customer gets the movie mean, or the mean of its year.
+        let new_customer = data.customer_ids.len();
+        assert!(model.predict(&transaction(0, new_customer, None)) == 3f32);
+        assert!(
+            model.predict(&transaction(2, new_customer, None))
+                == RatingScale::NETFLIX.clamp(year_2000)
+        );
+        assert!(model.predict(&transaction(3, new_customer, None)) == 4f32);
+        // A new movie gets the bias of the customer on top.
+        assert!(priors.estimate(&chain.0, &transaction(2, 1, None)) == year_2000 + bias_1);
+        assert!(priors.estimate(&chain.0, &transaction(3, 0, None)) == 3.5 + bias_0);
+        assert!(priors.estimate(&[], &transaction(3, 0, None)) == 3.5);
+
+        let model = ColdStart::wrap(Box::new(Zero), &"none".parse().unwrap(), &data, &[]);
+        assert!(model.predict(&transaction(0, new_customer, None)) == 0f32);
+
+        // Ratings folded in twice count once, and replace training ratings
+        // of the same customer and movie once the priors are rebuilt.
+        let new = vec![
+            transaction(2, new_customer, Some(5f32)),
+            transaction(0, 0, Some(5f32)),
+        ];
+        let mut once = Priors::new(&data, &[]);
+        once.update(&new);
+        let mut twice = once.clone();
+        twice.update(&new);
+        assert!(once.global_cnt == 42 && twice.global_cnt == 42);
+        assert!(twice.movie_mean(2) == once.movie_mean(2));
+        for &customer in &[0, new_customer] {
+            let (a, b) = (once.customer_bias(customer), twice.customer_bias(customer));
+            assert!((a.unwrap() - b.unwrap()).abs() < 1e-9);
+        }
+        let rebuilt = Priors::new(&data, &new);
+        // Customer 0 rated movie 0 ten times.
+        assert!(rebuilt.global_cnt == 32 && rebuilt.movie_cnt[0] == 11);
+        assert!(rebuilt.movie_mean(0) > priors.movie_mean(0));
+    }
+}
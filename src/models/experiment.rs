@@ -0,0 +1,309 @@
+//! Experiment files name configurations of the registered models, in TOML
+//!
+//! ```toml
+//! [[model]]
+//! name = "svd-20"
+//! model = "PureSvd"
+//! [model.params]
+//! rank = 20
+//! ```
+//!
+//! or the same in JSON, `{"model": [{"name": "svd-20", ...}]}`. Every
+//! hyperparameter left out keeps its default. Searches over
+//! hyperparameters are declared the same way, see `search`.
+use super::*;
+
+use search::Search;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::{fs, path::Path};
+
+/// A registered model and its hyperparameters, under a name of its own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelConfig {
+    /// Name of the configuration, which results are written under.
+    pub name: String,
+    /// Name of the registered model, see `Model::get_name`.
+    pub model: String,
+    #[serde(default)]
+    pub params: Map<String, Value>,
+}
+
+impl ModelConfig {
+    /// The registered model `model` with its default hyperparameters.
+    pub fn default_for(model: &str) -> Self {
+        Self {
+            name: model.to_string(),
+            model: model.to_string(),
+            params: Map::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Experiment {
+    #[serde(rename = "model", default)]
+    pub models: Vec<ModelConfig>,
+    #[serde(rename = "search", default, skip_serializing_if = "Vec::is_empty")]
+    pub searches: Vec<Search>,
+}
+
+impl Experiment {
+    /// Read a `.toml` or `.json` experiment file.
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{:?}: {}", path, err))?;
+        let experiment: Self = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text)?,
+            Some("json") => serde_json::from_str(&text)?,
+            _ => return Err(format!("{:?} is neither .toml nor .json", path).into()),
+        };
+        for (i, config) in experiment.models.iter().enumerate() {
+            if experiment.models[..i].iter().any(|c| c.name == config.name) {
+                return Err(format!("{:?} names two models {:?}", path, config.name).into());
+            }
+        }
+        for (i, search) in experiment.searches.iter().enumerate() {
+            if experiment.searches[..i]
+                .iter()
+                .any(|s| s.name == search.name)
+            {
+                return Err(format!("{:?} names two searches {:?}", path, search.name).into());
+            }
+        }
+        Ok(experiment)
+    }
+}
+
+/// `config` with the values of `params` instead, typically a model's
+/// hyperparameters overridden by a `ModelConfig`. Unknown keys fail, so a
+/// misspelled hyperparameter is not silently ignored.
+pub fn merge_params<T: Serialize + DeserializeOwned>(
+    config: &T,
+    params: &Map<String, Value>,
+) -> Result<T, Box<dyn Error>> {
+    let mut merged = match serde_json::to_value(config)? {
+        Value::Object(map) => map,
+        _ => return Err("Hyperparameters are not a map".into()),
+    };
+    for (key, value) in params {
+        match merged.get_mut(key) {
+            Some(slot) => *slot = value.clone(),
+            None => {
+                return Err(format!(
+                    "Unknown hyperparameter {:?}, expecting one of {:?}",
+                    key,
+                    merged.keys().collect::<Vec<_>>()
+                )
+                .into())
+            }
+        }
+    }
+    Ok(serde_json::from_value(Value::Object(merged))?)
+}
+
+/// The hyperparameters `config` as a map.
+pub fn to_params<T: Serialize>(config: &T) -> Map<String, Value> {
+    match serde_json::to_value(config) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+/// Whether `name` matches the glob `pattern`, in which `*` stands for
+/// any characters and `?` for one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Backtrack to the last `*` on mismatch, which is enough for globs.
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether `config` is matched by `pattern`, a glob of its name or
+/// `tag:<tag>` for the tags of its model, see `Model::tags`.
+fn matches(pattern: &str, config: &ModelConfig) -> bool {
+    match pattern.strip_prefix("tag:") {
+        Some(tag) => {
+            ModelHolder::find(&config.model).is_some_and(|holder| holder.get_tags().contains(&tag))
+        }
+        None => glob_match(pattern, &config.name),
+    }
+}
+
+/// The configurations of `available` matched by `patterns`, in their
+/// order. Patterns are names, globs such as `*Knn` or `tag:baseline`, and
+/// leave configurations out if prefixed by `!`, e.g. `!tag:slow`. Only
+/// leaving out starts from `defaults`. A pattern matching nothing fails
+/// with what is available.
+pub fn select(
+    patterns: &[String],
+    available: &[ModelConfig],
+    defaults: &[ModelConfig],
+) -> Result<Vec<ModelConfig>, Box<dyn Error>> {
+    let (excludes, includes): (Vec<&str>, Vec<&str>) = patterns
+        .iter()
+        .map(|pattern| pattern.trim())
+        .partition(|pattern| pattern.starts_with('!'));
+    let mut selected: Vec<ModelConfig> = if includes.is_empty() {
+        defaults.to_vec()
+    } else {
+        vec![]
+    };
+    for pattern in includes {
+        let matched: Vec<&ModelConfig> = available.iter().filter(|c| matches(pattern, c)).collect();
+        if matched.is_empty() {
+            let names: Vec<String> = available
+                .iter()
+                .map(|config| {
+                    let tags = ModelHolder::find(&config.model).map_or(&[][..], |h| h.get_tags());
+                    match (config.name == config.model, tags.is_empty()) {
+                        (true, true) => config.name.clone(),
+                        (true, false) => format!("{} [{}]", config.name, tags.join(", ")),
+                        (false, _) => format!("{} ({})", config.name, config.model),
+                    }
+                })
+                .collect();
+            return Err(format!(
+                "No model matches {:?}, expecting one of {}, a glob such as *Knn, \
+                 tag:<tag> or !<pattern> to leave models out",
+                pattern,
+                names.join(", ")
+            )
+            .into());
+        }
+        for config in matched {
+            if !selected.iter().any(|c| c.name == config.name) {
+                selected.push(config.clone());
+            }
+        }
+    }
+    for pattern in excludes {
+        selected.retain(|config| !matches(&pattern[1..], config));
+    }
+    if selected.is_empty() {
+        return Err(format!("No model left by {}", patterns.join(" ")).into());
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_experiment() {
+        let dir = std::env::temp_dir().join(format!("experiment_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("experiment.toml");
+        fs::write(
+            &path,
+            "[[model]]\nname = \"svd-20\"\nmodel = \"PureSvd\"\n[model.params]\nrank = 20\n\n\
+             [[model]]\nname = \"knn\"\nmodel = \"ItemKnn\"\n",
+        )
+        .unwrap();
+        let experiment = Experiment::from_file(&path).unwrap();
+        assert!(experiment.models.len() == 2);
+        assert!(experiment.models[0].params["rank"] == 20);
+        assert!(
+            experiment.models[1]
+                == ModelConfig {
+                    name: "knn".to_string(),
+                    ..ModelConfig::default_for("ItemKnn")
+                }
+        );
+
+        let json = dir.join("experiment.json");
+        fs::write(
+            &json,
+            r#"{"model": [{"name": "svd-20", "model": "PureSvd", "params": {"rank": 20}}]}"#,
+        )
+        .unwrap();
+        assert!(Experiment::from_file(&json).unwrap().models[0] == experiment.models[0]);
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Config {
+            rank: usize,
+            seed: u64,
+        }
+        let config = Config { rank: 50, seed: 1 };
+        let merged = merge_params(&config, &experiment.models[0].params).unwrap();
+        assert!(merged == Config { rank: 20, seed: 1 });
+        assert!(to_params(&merged)["rank"] == 20);
+        let mut params = Map::new();
+        params.insert("rnak".to_string(), Value::from(20));
+        assert!(merge_params(&config, &params).is_err());
+        params.clear();
+        params.insert("rank".to_string(), Value::from("x"));
+        assert!(merge_params(&config, &params).is_err());
+
+        // Models refuse hyperparameters they cannot train with.
+        let invalid = |model: &str, param: &str, value: Value| {
+            let mut config = ModelConfig::default_for(model);
+            config.params.insert(param.to_string(), value);
+            ModelHolder::from_config(&config).is_err()
+        };
+        let zero = |model: &str, param: &str| invalid(model, param, Value::from(0));
+        assert!(zero("PureSvd", "rank"));
+        assert!(zero("SpectralClustering", "num_eigen"));
+        assert!(zero("SpectralClustering", "num_clusters"));
+        assert!(zero("ItemKnn", "neighbours"));
+        assert!(invalid("Mixture", "components", Value::from(vec![2, 0])));
+        assert!(!invalid("Mixture", "components", Value::from(vec![2, 4])));
+        assert!(!zero("SpectralClustering", "seed"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_select() {
+        assert!(glob_match("*Knn", "ItemKnn") && glob_match("P?re*", "PureSvd"));
+        assert!(glob_match("*", "") && glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("*Knn", "ItemKnnX") && !glob_match("Pure", "PureSvd"));
+
+        let svd = ModelConfig {
+            name: "svd-20".to_string(),
+            ..ModelConfig::default_for("PureSvd")
+        };
+        let defaults: Vec<ModelConfig> = ModelHolder::names()
+            .into_iter()
+            .map(ModelConfig::default_for)
+            .collect();
+        let available: Vec<ModelConfig> = std::iter::once(svd.clone())
+            .chain(defaults.clone())
+            .collect();
+        let names = |patterns: &[&str]| -> Vec<String> {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            select(&patterns, &available, &defaults)
+                .unwrap()
+                .into_iter()
+                .map(|c| c.name)
+                .collect()
+        };
+        assert!(names(&["svd-20", "*Knn", "svd-20"]) == vec!["svd-20", "ItemKnn"]);
+        assert!(names(&["tag:baseline", "!MatrixCompletion"]) == vec!["svd-20", "PureSvd"]);
+        assert!(names(&["!tag:slow"]).len() == defaults.len() - 3);
+        let err = select(&["Knn".to_string()], &available, &defaults).unwrap_err();
+        assert!(err.to_string().contains("ItemKnn [slow]"));
+        assert!(err.to_string().contains("svd-20 (PureSvd)"));
+        assert!(select(&["!*".to_string()], &available, &defaults).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use super::*;
+
+use crate::similarity::{self, Similarity, SimilarityMatrix};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemKnnConfig {
+    /// # of neighbours kept for every movie.
+    pub neighbours: usize,
+    /// See `similarity::from_name`, `$SIMILARITY` by default.
+    pub similarity: String,
+}
+
+impl Default for ItemKnnConfig {
+    fn default() -> Self {
+        Self {
+            neighbours: 50,
+            similarity: similarity::from_env().get_name().to_string(),
+        }
+    }
+}
+
+/// Item based k nearest neighbours.
+///
+/// The rating of customer $`u`$ on movie $`i`$ is predicted from the
+/// movies $`N(i)`$ most similar to $`i`$ that $`u`$ has rated:
+/// ```math
+/// \hat{r}_{ui} = \bar{r}_i + \frac{
+///     \sum_{j \in N(i)} s_{ij} (r_{uj} - \bar{r}_j)
+/// }{
+///     \sum_{j \in N(i)} |s_{ij}|
+/// }
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+struct ItemKnn {
+    config: ItemKnnConfig,
+    customer_movie: SparseMatrix,
+    /// Row `i` holds the `config.neighbours` most similar movies of movie
+    /// `i`.
+    neighbours: SparseMatrix,
+    movie_avg: Vec<f64>,
+    global_avg: f64,
+    scale: RatingScale,
+    #[serde(with = "similarity::by_name")]
+    similarity: Box<dyn Similarity>,
+}
+
+impl Default for ItemKnn {
+    fn default() -> Self {
+        ItemKnn {
+            config: ItemKnnConfig::default(),
+            customer_movie: SparseMatrix::default(),
+            neighbours: SparseMatrix::default(),
+            movie_avg: vec![],
+            global_avg: 0f64,
+            scale: RatingScale::default(),
+            similarity: Box::new(similarity::Pearson),
+        }
+    }
+}
+
+inventory::submit!(ModelHolder::new(Box::new(ItemKnn::default())));
+
+impl Model for ItemKnn {
+    fn get_name(&self) -> &'static str {
+        "ItemKnn"
+    }
+    fn tags(&self) -> &'static [&'static str] {
+        &["slow"]
+    }
+    fn config(&self) -> Map<String, Value> {
+        to_params(&self.config)
+    }
+    fn configure(&mut self, params: &Map<String, Value>) -> Result<(), Box<dyn Error>> {
+        let config: ItemKnnConfig = merge_params(&self.config, params)?;
+        similarity::check_name(&config.similarity)?;
+        if config.neighbours == 0 {
+            return Err("neighbours must be positive".into());
+        }
+        self.config = config;
+        Ok(())
+    }
+    fn init(&mut self, data: &Data) -> &mut dyn Model {
+        info!("{}.init(&Data)", self.get_name());
+        self.scale = data.scale;
+        self.similarity = similarity::from_name(&self.config.similarity)
+            .expect("Similarity checked by configure.");
+        info!("Using {} similarity", self.similarity.get_name());
+        self.customer_movie = data.training_data_to_sparse();
+        let (movie_avg, global_avg) = rating_averages(&self.customer_movie);
+        self.movie_avg = movie_avg;
+        self.global_avg = global_avg;
+        self
+    }
+    fn train(&mut self) -> &mut dyn Model {
+        info!("{}.train()", self.get_name());
+        let (elapsed, _) = measure_time(|| {
+            self.neighbours = self
+                .customer_movie
+                .get_top_k_similarity(self.similarity.as_ref(), self.config.neighbours);
+        });
+        info!(
+            "{}.train() finished... elapsed: {}",
+            self.get_name(),
+            elapsed
+        );
+        self
+    }
+    fn predict(&self, trans: &Transaction) -> Rating {
+        let i = trans.movie_id;
+        let base = self.movie_avg.get(i).copied().unwrap_or(self.global_avg);
+        if trans.customer_id >= self.customer_movie.nrows || i >= self.neighbours.nrows {
+            return self.scale.clamp(base);
+        }
+        let (rated, ratings) = self.customer_movie.row(trans.customer_id);
+        let (movies, sims) = self.neighbours.row(i);
+        let (num, den) = movies
+            .iter()
+            .zip(sims)
+            .filter_map(|(&j, &s)| {
+                rated
+                    .binary_search(&j)
+                    .ok()
+                    .map(|p| (s * (ratings[p] - self.movie_avg[j]), s.abs()))
+            })
+            .fold((0f64, 0f64), |(num, den), (n, d)| (num + n, den + d));
+        if den == 0f64 {
+            self.scale.clamp(base)
+        } else {
+            self.scale.clamp(base + num / den)
+        }
+    }
+    /// New ratings join the customers' rows, and move the averages, but
+    /// neighbourhoods are kept as trained, which is how new customers are
+    /// folded in.
+    fn update(&mut self, new: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        let (triplets, customers) = new_ratings(new, self.customer_movie.ncols);
+        self.customer_movie = self.customer_movie.with_entries(triplets)?;
+        let (movie_avg, global_avg) = rating_averages(&self.customer_movie);
+        self.movie_avg = movie_avg;
+        self.global_avg = global_avg;
+        info!("{} updated {} customers", self.get_name(), customers.len());
+        Ok(())
+    }
+    fn save(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        persist::save_state(self, writer)
+    }
+    fn load(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        *self = persist::load_state(reader)?;
+        Ok(())
+    }
+}
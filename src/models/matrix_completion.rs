@@ -3,12 +3,14 @@ use super::*;
 #[derive(Debug)]
 struct MatrixCompletion {
     customer_movie: DMatrix<f64>,
+    scale: RatingScale,
 }
 
 impl Default for MatrixCompletion {
     fn default() -> Self {
         MatrixCompletion {
             customer_movie: DMatrix::zeros(1, 1),
+            scale: RatingScale::default(),
         }
     }
 }
@@ -19,14 +21,18 @@ impl Model for MatrixCompletion {
     fn get_name(&self) -> &'static str {
         "MatrixCompletion"
     }
+    fn tags(&self) -> &'static [&'static str] {
+        &["baseline", "slow"]
+    }
     fn init(&mut self, data: &Data) -> &mut dyn Model {
         self.customer_movie = data.training_data_to_matrix();
+        self.scale = data.scale;
         self
     }
     fn train(&mut self) -> &mut dyn Model {
         self
     }
     fn predict(&self, _trans: &Transaction) -> Rating {
-        0
+        self.scale.clamp(self.scale.middle())
     }
 }
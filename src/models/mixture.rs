@@ -0,0 +1,394 @@
+use super::*;
+
+use rand::{prelude::*, rngs::StdRng};
+use rayon::prelude::*;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MixtureConfig {
+    /// Candidate # of components, the one with the lowest BIC is kept.
+    pub components: Vec<usize>,
+    /// Pseudo count added to every rating of every movie in every
+    /// component, otherwise a component that never saw a rating would rule
+    /// it out.
+    pub smoothing: f64,
+    /// EM iterations, at most.
+    pub max_iter: usize,
+    /// EM stops once the log likelihood improves by less than `tolerance`
+    /// of itself.
+    pub tolerance: f64,
+    /// Seed of the initial posterior.
+    pub seed: u64,
+}
+
+impl Default for MixtureConfig {
+    fn default() -> Self {
+        Self {
+            components: vec![2, 4, 8, 16],
+            smoothing: 1f64,
+            max_iter: 100,
+            tolerance: 1e-6,
+            seed: 271,
+        }
+    }
+}
+
+/// A mixture of multinomials over the levels of a `RatingScale`, fitted
+/// by EM.
+///
+/// Customer $`u`$ belongs to component $`k`$ with probability $`\pi_k`$,
+/// and then rates movie $`i`$ with $`r`$ with probability $`\theta_{kir}`$
+/// independently of the other movies. Unknown ratings are simply left out
+/// of the likelihood
+/// ```math
+/// p(u) = \sum_k \pi_k \prod_{i \in R(u)} \theta_{k i r_{ui}}
+/// ```
+/// so nothing has to be imputed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultinomialMixture {
+    pub num_components: usize,
+    pub log_likelihood: f64,
+    /// `log_likelihood` after every EM iteration.
+    pub curve: Vec<f64>,
+    pub bic: f64,
+    scale: RatingScale,
+    log_pi: Vec<f64>,
+    /// $`\log \theta_{kir}`$ at `(k * m + i) * L + l`, `l` being the
+    /// level of `r` out of `L`.
+    log_theta: Vec<f64>,
+    /// n x K, $`p(k | u)`$ of every training customer.
+    posterior: DMatrix<f64>,
+}
+
+impl MultinomialMixture {
+    /// Fit `num_components` components to the n x m `customer_movie`,
+    /// rated on `scale`. `config.components` is ignored.
+    pub fn fit(
+        customer_movie: &SparseMatrix,
+        scale: RatingScale,
+        num_components: usize,
+        config: &MixtureConfig,
+    ) -> Self {
+        let (n, m) = customer_movie.shape();
+        let k = num_components;
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let mut posterior = DMatrix::from_fn(n, k, |_, _| rng.gen_range(0.1f64, 1f64));
+        for mut row in posterior.row_iter_mut() {
+            let sum = row.sum();
+            row /= sum;
+        }
+        let mut mixture = Self {
+            num_components: k,
+            log_likelihood: f64::NEG_INFINITY,
+            curve: vec![],
+            bic: f64::INFINITY,
+            scale,
+            log_pi: vec![],
+            log_theta: vec![],
+            posterior,
+        };
+        for iter in 0..config.max_iter {
+            mixture.maximize(customer_movie, config.smoothing);
+            let (posterior, log_likelihood) = mixture.expect(customer_movie);
+            mixture.posterior = posterior;
+            let improvement = log_likelihood - mixture.log_likelihood;
+            mixture.log_likelihood = log_likelihood;
+            mixture.curve.push(log_likelihood);
+            debug!(
+                "EM with {} components, iteration {}: log likelihood {}",
+                k, iter, log_likelihood
+            );
+            if improvement.abs() <= config.tolerance * log_likelihood.abs() {
+                break;
+            }
+        }
+        let mut rated = vec![false; m];
+        customer_movie.indices.iter().for_each(|&i| rated[i] = true);
+        let rated_movies = rated.iter().filter(|&&r| r).count();
+        let num_params = (k - 1) + k * rated_movies * (scale.num_levels() - 1);
+        mixture.bic = -2f64 * mixture.log_likelihood + num_params as f64 * (n as f64).ln();
+        mixture
+    }
+
+    /// Fit every candidate # of components of `config` and keep the lowest
+    /// BIC.
+    pub fn select(
+        customer_movie: &SparseMatrix,
+        scale: RatingScale,
+        config: &MixtureConfig,
+    ) -> Self {
+        config
+            .components
+            .iter()
+            .map(|&k| {
+                let mixture = Self::fit(customer_movie, scale, k, config);
+                info!(
+                    "Mixture with {} components: log likelihood {}, BIC {}",
+                    k, mixture.log_likelihood, mixture.bic
+                );
+                mixture
+            })
+            .min_by(|a, b| a.bic.partial_cmp(&b.bic).unwrap())
+            .expect("No candidate # of components.")
+    }
+
+    /// M step, $`\pi`$ and $`\theta`$ from the current posterior.
+    fn maximize(&mut self, customer_movie: &SparseMatrix, smoothing: f64) {
+        let (n, m) = customer_movie.shape();
+        let k = self.num_components;
+        let (scale, levels) = (self.scale, self.scale.num_levels());
+        let posterior = &self.posterior;
+        // One component at a time, so that memory stays at one set of
+        // counts however rayon splits the work.
+        let counts: Vec<Vec<f64>> = (0..k)
+            .into_par_iter()
+            .map(|c| {
+                let mut counts = vec![0f64; m * levels];
+                for u in 0..n {
+                    let weight = posterior[(u, c)];
+                    let (movies, ratings) = customer_movie.row(u);
+                    for (&i, &r) in movies.iter().zip(ratings) {
+                        counts[i * levels + scale.level(r)] += weight;
+                    }
+                }
+                counts
+            })
+            .collect();
+        self.log_pi = (0..k)
+            .map(|c| ((posterior.column(c).sum() + 1f64) / (n + k) as f64).ln())
+            .collect();
+        self.log_theta = counts
+            .iter()
+            .flat_map(|counts| counts.chunks(levels))
+            .flat_map(|counts| {
+                let total = counts.iter().sum::<f64>() + smoothing * levels as f64;
+                counts.iter().map(move |c| ((c + smoothing) / total).ln())
+            })
+            .collect();
+    }
+
+    /// E step, the posterior of every customer and the log likelihood.
+    fn expect(&self, customer_movie: &SparseMatrix) -> (DMatrix<f64>, f64) {
+        let n = customer_movie.nrows;
+        let k = self.num_components;
+        let rows: Vec<(Vec<f64>, f64)> = (0..n)
+            .into_par_iter()
+            .map(|u| {
+                let (movies, ratings) = customer_movie.row(u);
+                self.log_posterior(movies, ratings)
+            })
+            .collect();
+        let log_likelihood = rows.iter().map(|(_, l)| l).sum();
+        let posterior = DMatrix::from_fn(n, k, |u, c| rows[u].0[c]);
+        (posterior, log_likelihood)
+    }
+
+    /// $`p(k | u)`$ given the ratings of `u`, and $`\log p(u)`$.
+    fn log_posterior(&self, movies: &[usize], ratings: &[f64]) -> (Vec<f64>, f64) {
+        let levels = self.scale.num_levels();
+        let m = self.log_theta.len() / self.num_components / levels;
+        let mut log_p: Vec<f64> = self.log_pi.clone();
+        for (&i, &r) in movies.iter().zip(ratings) {
+            for (c, log_p) in log_p.iter_mut().enumerate() {
+                *log_p += self.log_theta[(c * m + i) * levels + self.scale.level(r)];
+            }
+        }
+        let max = log_p.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let log_sum = max + log_p.iter().map(|l| (l - max).exp()).sum::<f64>().ln();
+        (log_p.iter().map(|l| (l - log_sum).exp()).collect(), log_sum)
+    }
+
+    /// Posterior of `customers`, after their ratings in `customer_movie`
+    /// changed, with $`\pi`$ and $`\theta`$ kept. Customers past those
+    /// of training are new ones.
+    pub fn fold_in(&mut self, customer_movie: &SparseMatrix, customers: &[usize]) {
+        let k = self.num_components;
+        let n = customer_movie.nrows.max(self.posterior.nrows());
+        if n > self.posterior.nrows() {
+            let prior: Vec<f64> = self.log_pi.iter().map(|l| l.exp()).collect();
+            let old = std::mem::replace(&mut self.posterior, DMatrix::zeros(0, 0));
+            let old_n = old.nrows();
+            self.posterior =
+                DMatrix::from_fn(n, k, |u, c| if u < old_n { old[(u, c)] } else { prior[c] });
+        }
+        for &u in customers {
+            let (movies, ratings) = customer_movie.row(u);
+            let (posterior, _) = self.log_posterior(movies, ratings);
+            for (c, p) in posterior.into_iter().enumerate() {
+                self.posterior[(u, c)] = p;
+            }
+        }
+    }
+
+    /// Most likely component of every training customer.
+    #[allow(dead_code)]
+    pub fn assignments(&self) -> Vec<usize> {
+        self.posterior
+            .row_iter()
+            .map(|row| row.transpose().argmax().0)
+            .collect()
+    }
+
+    /// $`E[r_{ui}] = \sum_k p(k | u) \sum_r r \theta_{kir}`$. Customers
+    /// unseen in training are weighed by the prior $`\pi`$.
+    pub fn expected_rating(&self, customer_id: usize, movie_id: usize) -> f64 {
+        let levels = self.scale.num_levels();
+        let m = self.log_theta.len() / self.num_components / levels;
+        (0..self.num_components)
+            .map(|c| {
+                let weight = if customer_id < self.posterior.nrows() {
+                    self.posterior[(customer_id, c)]
+                } else {
+                    self.log_pi[c].exp()
+                };
+                let offset = (c * m + movie_id) * levels;
+                let expected: f64 = self.log_theta[offset..offset + levels]
+                    .iter()
+                    .enumerate()
+                    .map(|(l, log_theta)| self.scale.level_value(l) * log_theta.exp())
+                    .sum();
+                weight * expected
+            })
+            .sum()
+    }
+}
+
+/// Clusters customers with a `MultinomialMixture` and predicts the
+/// expected rating under each customer's posterior cluster membership.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Mixture {
+    config: MixtureConfig,
+    customer_movie: SparseMatrix,
+    scale: RatingScale,
+    mixture: Option<MultinomialMixture>,
+}
+
+inventory::submit!(ModelHolder::new(Box::new(Mixture::default())));
+
+impl Model for Mixture {
+    fn get_name(&self) -> &'static str {
+        "Mixture"
+    }
+    fn tags(&self) -> &'static [&'static str] {
+        &["experimental"]
+    }
+    fn config(&self) -> Map<String, Value> {
+        to_params(&self.config)
+    }
+    fn configure(&mut self, params: &Map<String, Value>) -> Result<(), Box<dyn Error>> {
+        let config: MixtureConfig = merge_params(&self.config, params)?;
+        if config.components.is_empty() {
+            return Err("No candidate # of components".into());
+        }
+        if config.components.contains(&0) {
+            return Err("# of components must be positive".into());
+        }
+        self.config = config;
+        Ok(())
+    }
+    fn init(&mut self, data: &Data) -> &mut dyn Model {
+        info!("{}.init(&Data)", self.get_name());
+        self.customer_movie = data.training_data_to_sparse();
+        self.scale = data.scale;
+        self
+    }
+    fn train(&mut self) -> &mut dyn Model {
+        info!("{}.train()", self.get_name());
+        let (elapsed, mixture) = measure_time(|| {
+            MultinomialMixture::select(&self.customer_movie, self.scale, &self.config)
+        });
+        info!(
+            "{}.train() finished... elapsed: {}",
+            self.get_name(),
+            elapsed
+        );
+        let mut sizes = vec![0; mixture.num_components];
+        mixture.assignments().iter().for_each(|&c| sizes[c] += 1);
+        info!(
+            "Picked {} components by BIC, cluster sizes: {:?}",
+            mixture.num_components, sizes
+        );
+        self.mixture = Some(mixture);
+        self
+    }
+    fn training_curve(&self) -> &[f64] {
+        self.mixture.as_ref().map_or(&[], |mixture| &mixture.curve)
+    }
+    /// Folds the customers in, see `MultinomialMixture::fold_in`.
+    fn update(&mut self, new: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        let (triplets, customers) = new_ratings(new, self.customer_movie.ncols);
+        self.customer_movie = self.customer_movie.with_entries(triplets)?;
+        let mixture = self.mixture.as_mut().ok_or("Mixture is not trained")?;
+        mixture.fold_in(&self.customer_movie, &customers);
+        info!(
+            "{} folded in {} customers",
+            self.get_name(),
+            customers.len()
+        );
+        Ok(())
+    }
+    fn predict(&self, trans: &Transaction) -> Rating {
+        match &self.mixture {
+            Some(mixture) if trans.movie_id < self.customer_movie.ncols => self
+                .scale
+                .clamp(mixture.expected_rating(trans.customer_id, trans.movie_id)),
+            _ => self.scale.clamp(self.scale.middle()),
+        }
+    }
+    fn save(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        persist::save_state(self, writer)
+    }
+    fn load(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        *self = persist::load_state(reader)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_multinomial_mixture() {
+        // Even customers love movies 0..3 and hate 3..6, odd ones the
+        // opposite, and everybody skips one movie.
+        let triplets = (0..40)
+            .flat_map(|u| {
+                (0..6).filter(move |i| i % 6 != u % 6).map(move |i| {
+                    let love = (i < 3) == (u % 2 == 0);
+                    (u, i, if love { 5f64 } else { 1f64 })
+                })
+            })
+            .collect();
+        let matrix = SparseMatrix::from_triplets(40, 6, triplets);
+        let mixture = MultinomialMixture::select(
+            &matrix,
+            RatingScale::NETFLIX,
+            &MixtureConfig {
+                components: vec![1, 2],
+                seed: 1,
+                ..Default::default()
+            },
+        );
+        assert!(mixture.num_components == 2);
+        let assignments = mixture.assignments();
+        for u in 0..40 {
+            assert!(assignments[u] == assignments[u % 2]);
+        }
+        assert!(assignments[0] != assignments[1]);
+        // Customer 0 skipped movie 0, customer 1 skipped movie 1.
+        assert!(mixture.expected_rating(0, 0) > 4f64);
+        assert!(mixture.expected_rating(1, 1) < 2f64);
+        // Unknown customers are in between.
+        let unknown = mixture.expected_rating(40, 0);
+        assert!(unknown > 2f64 && unknown < 4f64);
+
+        // Customer 40 is new, loves movies 1 and 2 and hates movie 4.
+        let mut mixture = mixture;
+        let matrix = matrix
+            .with_entries(vec![(40, 1, 5f64), (40, 2, 5f64), (40, 4, 1f64)])
+            .unwrap();
+        mixture.fold_in(&matrix, &[40]);
+        assert!(mixture.expected_rating(40, 0) > 4f64);
+        assert!(mixture.expected_rating(40, 3) < 2f64);
+        assert!(mixture.assignments()[40] == assignments[0]);
+    }
+}
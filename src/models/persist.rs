@@ -0,0 +1,269 @@
+//! A saved model is a `Header` followed by whatever the model wrote in
+//! `Model::save`, both encoded by bincode. The header tells what the model
+//! learnt from, see `Provenance`.
+
+use super::*;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+/// First bytes of every saved model.
+const MAGIC: [u8; 4] = *b"RNFM";
+
+/// Bumped whenever the layout of `Header` or of any model state changes,
+/// older files are then refused instead of misread.
+pub const FORMAT_VERSION: u32 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 4],
+    version: u32,
+    /// `Model::get_name`, used to find the model in the registry.
+    model: String,
+    provenance: Provenance,
+}
+
+/// What a model learnt from: the dataset it was trained on, and the
+/// ratings `Model::update` folded in since.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// `Data::fingerprint` of the dataset the model was trained on.
+    pub fingerprint: u64,
+    /// Original ids of the folded in customers missing from the dataset,
+    /// whose virtual ids follow those of the dataset in this order.
+    pub new_customers: Vec<usize>,
+    /// Every rating folded in, with virtual ids.
+    pub updates: Vec<Transaction>,
+}
+
+impl Provenance {
+    /// A model trained on `data` and never updated.
+    pub fn new(data: &Data) -> Self {
+        Self {
+            fingerprint: data.fingerprint(),
+            ..Default::default()
+        }
+    }
+
+    /// The customer ids of `data`, followed by the new customers.
+    pub fn customer_ids(&self, data: &Data) -> IdMapper {
+        let mut ids = data.customer_ids.clone();
+        self.new_customers.iter().for_each(|&c| {
+            ids.get_or_insert(c);
+        });
+        ids
+    }
+
+    /// Give the customers of `new`, by their original ids, virtual ids,
+    /// new customers getting the next ones, and record the ratings as
+    /// folded in. A rating replaces any earlier one of the same customer
+    /// and movie, so only the last ones are returned.
+    pub fn fold_in(&mut self, data: &Data, mut new: Vec<Transaction>) -> Vec<Transaction> {
+        let mut customers = self.customer_ids(data);
+        new.iter_mut()
+            .for_each(|t| t.customer_id = customers.get_or_insert(t.customer_id));
+        let mut seen = HashSet::new();
+        let mut new: Vec<Transaction> = new
+            .into_iter()
+            .rev()
+            .filter(|t| seen.insert((t.customer_id, t.movie_id)))
+            .collect();
+        new.reverse();
+        self.new_customers = customers.originals()[data.customer_ids.len()..].to_vec();
+        self.updates
+            .retain(|t| !seen.contains(&(t.customer_id, t.movie_id)));
+        self.updates.extend(new.iter().cloned());
+        new
+    }
+}
+
+/// Save the trained `model` to `path`.
+pub fn save_model<P: AsRef<Path>>(
+    model: &dyn Model,
+    provenance: &Provenance,
+    path: P,
+) -> Result<(), Box<dyn Error>> {
+    // Models that cannot be saved must not leave a file behind.
+    let mut state = vec![];
+    model.save(&mut state)?;
+    let mut writer = BufWriter::new(File::create(path.as_ref())?);
+    let header = Header {
+        magic: MAGIC,
+        version: FORMAT_VERSION,
+        model: model.get_name().to_string(),
+        provenance: provenance.clone(),
+    };
+    save_state(&header, &mut writer)?;
+    writer.write_all(&state)?;
+    writer.flush()?;
+    info!("{} saved to {:?}", model.get_name(), path.as_ref());
+    Ok(())
+}
+
+/// Load a model saved by `save_model`, whatever model it is, and what it
+/// learnt from. Fails if the file is of another format version, or if it
+/// was trained on a dataset other than the one of `fingerprint`.
+pub fn load_model<P: AsRef<Path>>(
+    path: P,
+    fingerprint: u64,
+) -> Result<(Box<dyn Model>, Provenance), Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path.as_ref())?);
+    let header: Header = load_state(&mut reader)?;
+    if header.magic != MAGIC {
+        return Err(format!("{:?} is not a saved model", path.as_ref()).into());
+    }
+    if header.version != FORMAT_VERSION {
+        return Err(format!(
+            "{:?} is of format version {}, expected {}",
+            path.as_ref(),
+            header.version,
+            FORMAT_VERSION
+        )
+        .into());
+    }
+    if header.provenance.fingerprint != fingerprint {
+        return Err(format!(
+            "{:?} was trained on another dataset (fingerprint {:x}, expected {:x})",
+            path.as_ref(),
+            header.provenance.fingerprint,
+            fingerprint
+        )
+        .into());
+    }
+    let holder = ModelHolder::find(&header.model).ok_or_else(|| {
+        format!(
+            "{:?} holds an unknown model {}",
+            path.as_ref(),
+            header.model
+        )
+    })?;
+    let mut model = holder.get_model();
+    model.load(&mut reader)?;
+    info!("{} loaded from {:?}", header.model, path.as_ref());
+    if !header.provenance.updates.is_empty() {
+        info!(
+            "{} has {} ratings folded in since training, of {} new customers",
+            header.model,
+            header.provenance.updates.len(),
+            header.provenance.new_customers.len()
+        );
+    }
+    Ok((model, header.provenance))
+}
+
+/// `Model::save` for models that are `Serialize`.
+pub fn save_state<T: Serialize>(state: &T, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    Ok(bincode::serialize_into(writer, state)?)
+}
+
+/// `Model::load` for models that are `Deserialize`.
+pub fn load_state<T: DeserializeOwned>(reader: &mut dyn Read) -> Result<T, Box<dyn Error>> {
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_save_load_model() {
+        let path = std::env::temp_dir().join(format!("persist_{}.model", std::process::id()));
+        let data = crate::data::test::two_tastes(8, 6);
+        let config = ModelConfig {
+            params: serde_json::from_str("{\"rank\": 2}").unwrap(),
+            ..ModelConfig::default_for("PureSvd")
+        };
+        let mut model = ModelHolder::from_config(&config).unwrap();
+        model.init(&data).train();
+        let expected = model.predict_all(data.train());
+        // Trained factors tell the two tastes apart.
+        assert!(expected[0] == 5f32 && expected[1] == 1f32);
+        let provenance = Provenance::new(&data);
+        save_model(model.as_ref(), &provenance, &path).unwrap();
+
+        let (model, loaded) = load_model(&path, data.fingerprint()).unwrap();
+        assert!(loaded == provenance);
+        assert!(model.get_name() == "PureSvd");
+        assert!(model.config() == ModelHolder::from_config(&config).unwrap().config());
+        assert!(model.predict_all(data.train()) == expected);
+        assert!(load_model(&path, data.fingerprint() + 1).is_err());
+
+        // A newer format version is refused.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[4] += 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(load_model(&path, data.fingerprint()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Ratings of the first `num_movies - 2` movies by `customer`, who likes
+    /// even movies if `even`.
+    fn ratings(customer: usize, even: bool, num_movies: usize) -> Vec<Transaction> {
+        (0..num_movies - 2)
+            .map(|j| Transaction {
+                movie_id: j,
+                customer_id: customer,
+                rating: Some(if (j % 2 == 0) == even { 5f32 } else { 1f32 }),
+                date: 12000,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_update_new_customers() {
+        let path = std::env::temp_dir().join(format!("update_{}.model", std::process::id()));
+        let data = crate::data::test::two_tastes(8, 6);
+        let config = ModelConfig {
+            params: serde_json::from_str("{\"rank\": 1}").unwrap(),
+            ..ModelConfig::default_for("PureSvd")
+        };
+        let mut model = ModelHolder::from_config(&config).unwrap();
+        model.init(&data).train();
+        let mut provenance = Provenance::new(&data);
+
+        // Each update is saved, and the next one starts from the saved model.
+        for &(customer, even) in &[(900, true), (901, false)] {
+            let new = provenance.fold_in(&data, ratings(customer, even, 6));
+            model.update(&new).unwrap();
+            save_model(model.as_ref(), &provenance, &path).unwrap();
+            let loaded = load_model(&path, data.fingerprint()).unwrap();
+            model = loaded.0;
+            assert!(loaded.1 == provenance);
+        }
+        assert!(provenance.new_customers == vec![900, 901]);
+        assert!(provenance.updates.len() == 8);
+        // Folding the same ratings in again replaces them.
+        let mut again = provenance.clone();
+        assert!(again.fold_in(&data, ratings(900, true, 6)).len() == 4);
+        assert!(again.new_customers == provenance.new_customers);
+        assert!(again.updates.len() == 8);
+        let customers = provenance.customer_ids(&data);
+        let ids: Vec<usize> = [900, 901]
+            .iter()
+            .map(|&c| customers.to_virtual(c).unwrap())
+            .collect();
+        assert!(ids == vec![8, 9]);
+        let unrated: Vec<Transaction> = ids
+            .iter()
+            .flat_map(|&customer_id| {
+                (4..6).map(move |movie_id| Transaction {
+                    movie_id,
+                    customer_id,
+                    rating: None,
+                    date: 12000,
+                })
+            })
+            .collect();
+        let predictions = model.predict_all(&unrated);
+        assert!(predictions[0] >= 4f32 && predictions[1] <= 2f32);
+        assert!(predictions[2] <= 2f32 && predictions[3] >= 4f32);
+
+        // Another dataset is refused, even after updates.
+        assert!(load_model(&path, data.fingerprint() + 1).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
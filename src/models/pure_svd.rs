@@ -0,0 +1,225 @@
+use super::*;
+
+use crate::algorithm::RandomizedSvd;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PureSvdConfig {
+    /// Rank of the decomposition.
+    pub rank: usize,
+    pub oversampling: usize,
+    pub power_iterations: usize,
+    pub seed: u64,
+}
+
+impl Default for PureSvdConfig {
+    fn default() -> Self {
+        let svd = RandomizedSvd::new(50);
+        Self {
+            rank: svd.rank,
+            oversampling: svd.oversampling,
+            power_iterations: svd.power_iterations,
+            seed: svd.seed,
+        }
+    }
+}
+
+/// PureSVD, see Cremonesi, Koren and Turrin, 2010.
+///
+/// Every rating is centered by its movie's average, the unknown ones are
+/// taken as 0 (i.e. the average), and the resulting matrix is replaced by
+/// its truncated SVD:
+/// ```math
+/// \hat{r}_{ui} = \bar{r}_i + u_u^T \Sigma v_i
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+struct PureSvd {
+    config: PureSvdConfig,
+    customer_movie: SparseMatrix,
+    movie_avg: Vec<f64>,
+    global_avg: f64,
+    scale: RatingScale,
+    /// n x `RANK`, $`U \Sigma`$.
+    customer_factors: DMatrix<f64>,
+    /// m x `RANK`, $`V`$.
+    movie_factors: DMatrix<f64>,
+}
+
+impl Default for PureSvd {
+    fn default() -> Self {
+        PureSvd {
+            config: PureSvdConfig::default(),
+            customer_movie: SparseMatrix::default(),
+            movie_avg: vec![],
+            global_avg: 0f64,
+            scale: RatingScale::default(),
+            customer_factors: DMatrix::zeros(0, 0),
+            movie_factors: DMatrix::zeros(0, 0),
+        }
+    }
+}
+
+inventory::submit!(ModelHolder::new(Box::new(PureSvd::default())));
+
+impl Model for PureSvd {
+    fn get_name(&self) -> &'static str {
+        "PureSvd"
+    }
+    fn tags(&self) -> &'static [&'static str] {
+        &["baseline"]
+    }
+    fn config(&self) -> Map<String, Value> {
+        to_params(&self.config)
+    }
+    fn configure(&mut self, params: &Map<String, Value>) -> Result<(), Box<dyn Error>> {
+        let config: PureSvdConfig = merge_params(&self.config, params)?;
+        if config.rank == 0 {
+            return Err("rank must be positive".into());
+        }
+        self.config = config;
+        Ok(())
+    }
+    fn init(&mut self, data: &Data) -> &mut dyn Model {
+        info!("{}.init(&Data)", self.get_name());
+        self.scale = data.scale;
+        self.customer_movie = data.training_data_to_sparse();
+        let (movie_avg, global_avg) = rating_averages(&self.customer_movie);
+        self.movie_avg = movie_avg;
+        self.global_avg = global_avg;
+        let movie_avg = &self.movie_avg;
+        self.customer_movie
+            .indices
+            .iter()
+            .zip(self.customer_movie.values.iter_mut())
+            .for_each(|(&j, v)| *v -= movie_avg[j]);
+        self
+    }
+    fn train(&mut self) -> &mut dyn Model {
+        info!("{}.train()", self.get_name());
+        let config = &self.config;
+        let svd = RandomizedSvd {
+            rank: config.rank,
+            oversampling: config.oversampling,
+            power_iterations: config.power_iterations,
+            seed: config.seed,
+        };
+        let (elapsed, svd) = measure_time(|| svd.decompose(&self.customer_movie));
+        info!(
+            "{}.train() finished... elapsed: {}",
+            self.get_name(),
+            elapsed
+        );
+        info!(
+            "Top {} singular values: {:?}",
+            self.config.rank,
+            svd.singular_values.as_slice()
+        );
+        let mut customer_factors = svd.u;
+        for (c, s) in svd.singular_values.iter().enumerate() {
+            customer_factors.column_mut(c).scale_mut(*s);
+        }
+        self.customer_factors = customer_factors;
+        self.movie_factors = svd.v;
+        self
+    }
+    fn predict(&self, trans: &Transaction) -> Rating {
+        let i = trans.movie_id;
+        let base = self.movie_avg.get(i).copied().unwrap_or(self.global_avg);
+        if trans.customer_id >= self.customer_factors.nrows() || i >= self.movie_factors.nrows() {
+            return self.scale.clamp(base);
+        }
+        let residual = self
+            .customer_factors
+            .row(trans.customer_id)
+            .dot(&self.movie_factors.row(i));
+        self.scale.clamp(base + residual)
+    }
+    /// Folds the customers in, $`U \Sigma = A V`$ row by row, with
+    /// the movie averages and factors kept as trained.
+    fn update(&mut self, new: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        let num_movies = self.movie_factors.nrows();
+        let (triplets, customers) = new_ratings(new, num_movies);
+        let movie_avg = &self.movie_avg;
+        let residuals = triplets
+            .into_iter()
+            .map(|(i, j, r)| (i, j, r - movie_avg[j]))
+            .collect();
+        self.customer_movie = self.customer_movie.with_entries(residuals)?;
+        let (n, rank) = (self.customer_movie.nrows, self.movie_factors.ncols());
+        if n > self.customer_factors.nrows() {
+            let factors = std::mem::replace(&mut self.customer_factors, DMatrix::zeros(0, 0));
+            self.customer_factors = factors.resize_vertically(n, 0f64);
+        }
+        for &u in &customers {
+            let (movies, residuals) = self.customer_movie.row(u);
+            let mut row = DMatrix::zeros(1, rank);
+            for (&j, &r) in movies.iter().zip(residuals) {
+                row += self.movie_factors.row(j) * r;
+            }
+            self.customer_factors.set_row(u, &row.row(0));
+        }
+        info!(
+            "{} folded in {} customers",
+            self.get_name(),
+            customers.len()
+        );
+        Ok(())
+    }
+    fn movie_embedding(&self) -> Option<&DMatrix<f64>> {
+        Some(&self.movie_factors)
+    }
+    fn save(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        persist::save_state(self, writer)
+    }
+    fn load(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        *self = persist::load_state(reader)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_update() {
+        let data = crate::data::test::two_tastes(8, 6);
+        let mut model = PureSvd::default();
+        model.config.rank = 1;
+        model.init(&data).train();
+        // A new customer who likes the even movies, as half of the others do.
+        let new: Vec<Transaction> = (0..4)
+            .map(|j| Transaction {
+                movie_id: j,
+                customer_id: 8,
+                rating: Some(if j % 2 == 0 { 5f32 } else { 1f32 }),
+                date: 12000,
+            })
+            .collect();
+        model.update(&new).unwrap();
+        let unrated: Vec<Transaction> = (4..6)
+            .map(|j| Transaction {
+                rating: None,
+                movie_id: j,
+                ..new[0].clone()
+            })
+            .collect();
+        let predictions = model.predict_all(&unrated);
+        assert!(predictions[0] >= 4f32 && predictions[1] <= 2f32);
+        // Customers who did not rate anything new are left alone.
+        assert!(
+            model.predict_all(data.train()) == {
+                let mut trained = PureSvd::default();
+                trained.config.rank = 1;
+                trained.init(&data).train();
+                trained.predict_all(data.train())
+            }
+        );
+        // Ratings of movies past those of the data are left out.
+        model
+            .update(&[Transaction {
+                movie_id: 6,
+                ..new[0].clone()
+            }])
+            .unwrap();
+        assert!(model.predict_all(&unrated) == predictions);
+    }
+}
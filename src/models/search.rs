@@ -0,0 +1,353 @@
+//! Hyperparameter search, declared in experiment files next to the models
+//!
+//! ```toml
+//! [[search]]
+//! name = "svd"
+//! model = "PureSvd"
+//! strategy = "random"
+//! samples = 20
+//! [search.params]
+//! power_iterations = 1
+//! [search.space]
+//! rank = [10, 20, 50, 100]
+//! oversampling = { min = 5, max = 20 }
+//! ```
+//!
+//! Grid search tries every combination of the lists of `space`, random
+//! search draws `samples` candidates, uniformly from lists and ranges, and
+//! log uniformly from ranges with `log = true`. `params` are the same for
+//! every candidate. Candidates are trained one after another, so only one
+//! model is in memory at a time, scored by their RMSE on the cross
+//! validation data, and ranked in a leaderboard.
+use super::*;
+
+use itertools::Itertools;
+use rand::{distributions::Uniform, prelude::*, rngs::StdRng};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use cold_start::{ColdStart, FallbackChain};
+use experiment::Experiment;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    Grid,
+    Random,
+}
+
+/// The values a hyperparameter is searched over.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Domain {
+    Values(Vec<Value>),
+    /// Random search only. Integers if both bounds are, `max` included.
+    Range {
+        min: Value,
+        max: Value,
+        #[serde(default)]
+        log: bool,
+    },
+}
+
+impl Domain {
+    fn sample(&self, rng: &mut StdRng) -> Result<Value, Box<dyn Error>> {
+        match self {
+            Domain::Values(values) => values
+                .choose(rng)
+                .cloned()
+                .ok_or_else(|| "No value to search".into()),
+            Domain::Range { min, max, log } => {
+                let bounds = (min.as_f64(), max.as_f64());
+                let (lo, hi) = match bounds {
+                    (Some(lo), Some(hi)) if lo <= hi && (!log || lo > 0f64) => (lo, hi),
+                    _ => return Err(format!("Invalid range {} to {}", min, max).into()),
+                };
+                if let (Some(lo), Some(hi)) = (min.as_i64(), max.as_i64()) {
+                    return Ok(Value::from(if !log {
+                        Uniform::new_inclusive(lo, hi).sample(rng)
+                    } else {
+                        // Integer i gets the weight of [i, i + 1) on the
+                        // log scale.
+                        let (ln_lo, ln_end) = ((lo as f64).ln(), (hi as f64 + 1f64).ln());
+                        let u: f64 = rng.gen();
+                        let x = (ln_lo + u * (ln_end - ln_lo)).exp();
+                        (x.floor() as i64).max(lo).min(hi)
+                    }));
+                }
+                if lo == hi {
+                    return Ok(Value::from(lo));
+                }
+                Ok(Value::from(if *log {
+                    Uniform::new_inclusive(lo.ln(), hi.ln())
+                        .sample(rng)
+                        .exp()
+                        .max(lo)
+                        .min(hi)
+                } else {
+                    Uniform::new_inclusive(lo, hi).sample(rng)
+                }))
+            }
+        }
+    }
+}
+
+/// A search over the hyperparameters of one registered model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Search {
+    /// Candidates are named `<name>-<i>`, and results `<name>.*`.
+    pub name: String,
+    /// Name of the registered model, see `Model::get_name`.
+    pub model: String,
+    #[serde(default = "Search::default_strategy")]
+    pub strategy: Strategy,
+    /// # of candidates of random search.
+    #[serde(default = "Search::default_samples")]
+    pub samples: usize,
+    /// Seed of random search.
+    #[serde(default = "Search::default_seed")]
+    pub seed: u64,
+    /// Hyperparameters shared by every candidate.
+    #[serde(default)]
+    pub params: Map<String, Value>,
+    pub space: BTreeMap<String, Domain>,
+}
+
+impl Search {
+    fn default_strategy() -> Strategy {
+        Strategy::Grid
+    }
+    fn default_samples() -> usize {
+        10
+    }
+    fn default_seed() -> u64 {
+        271
+    }
+
+    /// Configurations of every candidate, checked against the model.
+    pub fn candidates(&self) -> Result<Vec<ModelConfig>, Box<dyn Error>> {
+        let names: Vec<&String> = self.space.keys().collect();
+        let points: Vec<Vec<Value>> = match self.strategy {
+            Strategy::Grid => {
+                let axes = self
+                    .space
+                    .iter()
+                    .map(|(name, domain)| match domain {
+                        Domain::Values(values) if !values.is_empty() => Ok(values.clone()),
+                        Domain::Values(_) => Err(format!("No value of {} to search", name)),
+                        Domain::Range { .. } => {
+                            Err(format!("{} is a range, which needs random search", name))
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                axes.into_iter().multi_cartesian_product().collect()
+            }
+            Strategy::Random => {
+                let mut rng = StdRng::seed_from_u64(self.seed);
+                (0..self.samples)
+                    .map(|_| self.space.values().map(|d| d.sample(&mut rng)).collect())
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        // The product of no axis is empty, but there is still one candidate.
+        let points = if names.is_empty() {
+            vec![vec![]]
+        } else {
+            points
+        };
+        points
+            .into_iter()
+            .enumerate()
+            .map(|(i, point)| {
+                let mut params = self.params.clone();
+                for (name, value) in names.iter().zip(point) {
+                    params.insert(name.to_string(), value);
+                }
+                let config = ModelConfig {
+                    name: format!("{}-{}", self.name, i),
+                    model: self.model.clone(),
+                    params,
+                };
+                ModelHolder::from_config(&config)?;
+                Ok(config)
+            })
+            .collect()
+    }
+}
+
+/// A trained candidate.
+#[derive(Debug, Clone)]
+pub struct Trial {
+    /// With every hyperparameter of the model, see `Model::config`.
+    pub config: ModelConfig,
+    /// On the cross validation data.
+    pub rmse: f64,
+    pub seconds: f64,
+}
+
+/// Train every candidate of `search`, best first, with `cold_start`
+/// predicting cold customers and movies. Models are parallel themselves.
+pub fn run(
+    search: &Search,
+    data: &Data,
+    cold_start: &FallbackChain,
+) -> Result<Vec<Trial>, Box<dyn Error>> {
+    let candidates = search.candidates()?;
+    info!(
+        "Searching {} with {} candidates",
+        search.name,
+        candidates.len()
+    );
+    let mut trials = candidates
+        .into_iter()
+        .map(|config| -> Result<Trial, Box<dyn Error>> {
+            let mut model = ModelHolder::from_config(&config)?;
+            let (elapsed, _) = measure_time(|| {
+                model.init(data).train();
+            });
+            let model = ColdStart::wrap(model, cold_start, data, &[]);
+            let trial = Trial {
+                config: ModelConfig {
+                    params: model.config(),
+                    ..config
+                },
+                rmse: rmse(model.as_ref(), data.cross_valid()),
+                seconds: elapsed.duration().as_secs_f64(),
+            };
+            info!(
+                "{} cross validation RMSE: {:.4}, elapsed: {}",
+                trial.config.name, trial.rmse, elapsed
+            );
+            Ok(trial)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    trials.sort_by(|a, b| {
+        a.rmse
+            .partial_cmp(&b.rmse)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(trials)
+}
+
+/// Write `<name>.leaderboard.csv`, one row per trial with the searched
+/// hyperparameters, and `<name>.best.json`, an experiment file holding the
+/// best configuration under the name of the search. Returns the files
+/// written.
+pub fn write_results(
+    search: &Search,
+    trials: &[Trial],
+    out: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let path = out.join(format!("{}.leaderboard.csv", search.name));
+    let mut wtr = csv::Writer::from_path(&path)?;
+    let header = ["position", "name", "rmse", "seconds"]
+        .iter()
+        .map(|s| s.to_string())
+        .chain(search.space.keys().cloned());
+    wtr.write_record(header)?;
+    for (position, trial) in trials.iter().enumerate() {
+        let params = search
+            .space
+            .keys()
+            .map(|name| match &trial.config.params[name] {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            });
+        let row = vec![
+            (position + 1).to_string(),
+            trial.config.name.clone(),
+            format!("{:.6}", trial.rmse),
+            format!("{:.3}", trial.seconds),
+        ];
+        wtr.write_record(row.into_iter().chain(params))?;
+    }
+    wtr.flush()?;
+    info!("Leaderboard of {} written to {:?}", search.name, path);
+    let mut written = vec![path];
+
+    if let Some(best) = trials.first() {
+        let experiment = Experiment {
+            models: vec![ModelConfig {
+                name: search.name.clone(),
+                ..best.config.clone()
+            }],
+            searches: vec![],
+        };
+        let path = out.join(format!("{}.best.json", search.name));
+        fs::write(&path, serde_json::to_string_pretty(&experiment)?)?;
+        info!(
+            "Best of {} is {} with RMSE {:.4}, written to {:?}",
+            search.name, best.config.name, best.rmse, path
+        );
+        written.push(path);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_candidates() {
+        let mut search: Search = toml::from_str(
+            "name = \"svd\"\nmodel = \"PureSvd\"\n[params]\nseed = 1\n\
+             [space]\nrank = [10, 20, 30]\npower_iterations = [1, 2]\n",
+        )
+        .unwrap();
+        let grid = search.candidates().unwrap();
+        assert!(grid.len() == 6);
+        assert!(grid[0].name == "svd-0" && grid[0].model == "PureSvd");
+        assert!(grid[0].params["seed"] == 1);
+        assert!(grid.iter().filter(|c| c.params["rank"] == 20).count() == 2);
+
+        search.strategy = Strategy::Random;
+        search.samples = 20;
+        search.space.insert(
+            "oversampling".to_string(),
+            Domain::Range {
+                min: Value::from(5),
+                max: Value::from(8),
+                log: false,
+            },
+        );
+        let random = search.candidates().unwrap();
+        assert!(random.len() == 20 && random == search.candidates().unwrap());
+        for config in &random {
+            let oversampling = config.params["oversampling"].as_u64().unwrap();
+            assert!((5..=8).contains(&oversampling));
+        }
+
+        // Endpoints are drawn as often as the values in between, and empty
+        // ranges give their one value.
+        let mut rng = StdRng::seed_from_u64(1);
+        let range = |min: Value, max: Value, log| Domain::Range { min, max, log };
+        let mut counts = [0; 4];
+        for _ in 0..4000 {
+            let x = range(Value::from(5), Value::from(8), false).sample(&mut rng);
+            counts[x.unwrap().as_u64().unwrap() as usize - 5] += 1;
+        }
+        assert!(counts.iter().all(|&c| (900..1100).contains(&c)));
+        for &log in &[false, true] {
+            let one = range(Value::from(5), Value::from(5), log).sample(&mut rng);
+            assert!(one.unwrap() == 5);
+            let one = range(Value::from(2.5), Value::from(2.5), log).sample(&mut rng);
+            assert!(one.unwrap() == 2.5);
+            let x = range(Value::from(1), Value::from(1000), log).sample(&mut rng);
+            assert!((1..=1000).contains(&x.unwrap().as_u64().unwrap()));
+        }
+        assert!(range(Value::from(3), Value::from(2), false)
+            .sample(&mut rng)
+            .is_err());
+
+        search.strategy = Strategy::Grid;
+        assert!(search.candidates().is_err());
+        search.space.clear();
+        search
+            .space
+            .insert("rnak".to_string(), Domain::Values(vec![Value::from(1)]));
+        assert!(search.candidates().is_err());
+    }
+}
@@ -1,20 +1,317 @@
 use super::*;
 
-use nalgebra::linalg::SymmetricEigen;
+use nalgebra::core::DVector;
+use std::{fmt, str::FromStr};
 
-#[derive(Debug)]
+use crate::algorithm::{KMeans, Lanczos, SymmetricOperator, Which};
+use crate::config;
+use crate::similarity::{self, Similarity, SimilarityMatrix};
+
+/// How a similarity matrix is turned into an affinity graph.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GraphKind {
+    /// Connect every item to its `k` most similar items. $`i \sim j`$ if
+    /// either one is among the neighbours of the other.
+    Knn(usize),
+    /// Connect items whose similarity is at least $`\epsilon`$, all edges weigh 1.
+    Epsilon(f64),
+    /// Connect every pair of items with a gaussian kernel of width $`\sigma`$:
+    /// ```math
+    /// w_{ij} = \exp(-\frac{d_{ij}^2}{2 \sigma^2}), \quad d_{ij}^2 = 2(1 - s_{ij})
+    /// ```
+    /// which is the distance between normalized items for cosine like measures.
+    Gaussian(f64),
+}
+
+impl GraphKind {
+    /// Fails if the graph would have no edge or weights that are not
+    /// finite: `k` must be positive, $`\epsilon`$ in (0, 1] and $`\sigma`$
+    /// positive.
+    pub fn check(self) -> Result<Self, String> {
+        let valid = match self {
+            GraphKind::Knn(k) => k > 0,
+            GraphKind::Epsilon(epsilon) => epsilon > 0f64 && epsilon <= 1f64,
+            GraphKind::Gaussian(sigma) => sigma.is_finite() && sigma > 0f64,
+        };
+        if valid {
+            Ok(self)
+        } else {
+            Err(format!("Invalid graph {}", self))
+        }
+    }
+}
+
+/// Parses `knn:<k>`, `epsilon:<epsilon>` or `gaussian:<sigma>`.
+impl FromStr for GraphKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let param = parts
+            .next()
+            .ok_or_else(|| format!("{} has no parameter", s));
+        let graph = match kind {
+            "knn" => GraphKind::Knn(param?.parse().map_err(|e| format!("{}", e))?),
+            "epsilon" => GraphKind::Epsilon(param?.parse().map_err(|e| format!("{}", e))?),
+            "gaussian" => GraphKind::Gaussian(param?.parse().map_err(|e| format!("{}", e))?),
+            _ => {
+                return Err(format!(
+                    "Unknown graph {}, try knn, epsilon or gaussian",
+                    kind
+                ))
+            }
+        };
+        graph.check()
+    }
+}
+
+impl fmt::Display for GraphKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphKind::Knn(k) => write!(f, "knn:{}", k),
+            GraphKind::Epsilon(epsilon) => write!(f, "epsilon:{}", epsilon),
+            GraphKind::Gaussian(sigma) => write!(f, "gaussian:{}", sigma),
+        }
+    }
+}
+
+/// Which graph Laplacian is decomposed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LaplacianKind {
+    /// $`L = D - W`$
+    Unnormalized,
+    /// $`L_{sym} = I - D^{-1/2} W D^{-1/2}`$
+    Symmetric,
+    /// $`L_{rw} = I - D^{-1} W`$. It is not symmetric, but shares its eigen
+    /// values with $`L_{sym}`$ and its eigen vectors are $`D^{-1/2} v`$.
+    RandomWalk,
+}
+
+impl FromStr for LaplacianKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unnormalized" => Ok(LaplacianKind::Unnormalized),
+            "symmetric" => Ok(LaplacianKind::Symmetric),
+            "random_walk" => Ok(LaplacianKind::RandomWalk),
+            _ => Err(format!(
+                "Unknown laplacian {}, try unnormalized, symmetric or random_walk",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LaplacianKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LaplacianKind::Unnormalized => "unnormalized",
+            LaplacianKind::Symmetric => "symmetric",
+            LaplacianKind::RandomWalk => "random_walk",
+        })
+    }
+}
+
+/// A weighted, undirected graph over the items,
+/// $`W = S + c (\mathbf{1}\mathbf{1}^T - I)`$ with $`S`$ sparse.
+///
+/// The constant $`c`$ lets a fully connected graph be represented without
+/// ever storing its m x m weights, it is 0 for every other kind of graph.
+#[derive(Debug, Clone)]
+pub struct AffinityGraph {
+    sparse: SparseMatrix,
+    constant: f64,
+    degree: DVector<f64>,
+}
+
+impl Default for AffinityGraph {
+    fn default() -> Self {
+        Self {
+            sparse: SparseMatrix::default(),
+            constant: 0f64,
+            degree: DVector::zeros(0),
+        }
+    }
+}
+
+impl AffinityGraph {
+    /// Build the graph over the items (columns) of the n x m `matrix`.
+    pub fn new(matrix: &SparseMatrix, measure: &dyn Similarity, kind: GraphKind) -> Self {
+        let (sparse, constant) = match kind {
+            GraphKind::Knn(k) => {
+                let mut knn = matrix.get_top_k_similarity(measure, k);
+                // Negative weights would make the Laplacian indefinite.
+                knn.values.iter_mut().for_each(|v| *v = f64::max(*v, 0f64));
+                (knn.symmetrize(), 0f64)
+            }
+            GraphKind::Epsilon(epsilon) => {
+                let mut graph = matrix.get_similarity_above(measure, epsilon);
+                graph.values.iter_mut().for_each(|v| *v = 1f64);
+                (graph, 0f64)
+            }
+            GraphKind::Gaussian(sigma) => {
+                let kernel = |s: f64| f64::exp(-(1f64 - s) / sigma.powi(2));
+                // Items that are never rated together have similarity 0.
+                let constant = kernel(0f64);
+                let mut graph = matrix.get_similarity_above(measure, f64::NEG_INFINITY);
+                graph
+                    .values
+                    .iter_mut()
+                    .for_each(|v| *v = kernel(*v) - constant);
+                (graph, constant)
+            }
+        };
+        let n = sparse.nrows;
+        let degree = sparse
+            .mul_vec(&DVector::from_element(n, 1f64))
+            .add_scalar(constant * n.saturating_sub(1) as f64);
+        Self {
+            sparse,
+            constant,
+            degree,
+        }
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.sparse.nrows
+    }
+
+    /// $`W x`$
+    pub fn mul_vec(&self, x: &DVector<f64>) -> DVector<f64> {
+        let mut y = self.sparse.mul_vec(x);
+        if self.constant != 0f64 {
+            y += (DVector::from_element(x.len(), x.sum()) - x) * self.constant;
+        }
+        y
+    }
+}
+
+/// A graph Laplacian as a symmetric operator, so it never has to be formed.
+/// `LaplacianKind::RandomWalk` is applied as `LaplacianKind::Symmetric`,
+/// see `embedding`.
+pub struct Laplacian<'a> {
+    graph: &'a AffinityGraph,
+    kind: LaplacianKind,
+    /// $`D^{-1/2}`$, 0 for isolated nodes.
+    inv_sqrt_degree: DVector<f64>,
+}
+
+impl<'a> Laplacian<'a> {
+    pub fn new(graph: &'a AffinityGraph, kind: LaplacianKind) -> Self {
+        Self {
+            graph,
+            kind,
+            inv_sqrt_degree: graph
+                .degree
+                .map(|d| if d > 0f64 { 1f64 / d.sqrt() } else { 0f64 }),
+        }
+    }
+
+    /// The eigen vectors of the `k` smallest eigen values as columns of an
+    /// m x k matrix, i.e. row `i` is the embedding of item `i`.
+    pub fn embedding(&self, k: usize) -> (DVector<f64>, DMatrix<f64>) {
+        let eigen = Lanczos::new(k, Which::Smallest).solve(self);
+        let mut vectors = eigen.eigenvectors;
+        if self.kind == LaplacianKind::RandomWalk {
+            for mut column in vectors.column_iter_mut() {
+                column.component_mul_assign(&self.inv_sqrt_degree);
+                let norm = column.norm();
+                if norm > 0f64 {
+                    column /= norm;
+                }
+            }
+        }
+        (eigen.eigenvalues, vectors)
+    }
+}
+
+impl<'a> SymmetricOperator for Laplacian<'a> {
+    fn dim(&self) -> usize {
+        self.graph.num_nodes()
+    }
+    fn apply(&self, x: &DVector<f64>) -> DVector<f64> {
+        match self.kind {
+            LaplacianKind::Unnormalized => {
+                self.graph.degree.component_mul(x) - self.graph.mul_vec(x)
+            }
+            LaplacianKind::Symmetric | LaplacianKind::RandomWalk => {
+                let scaled = self.inv_sqrt_degree.component_mul(x);
+                x - self
+                    .inv_sqrt_degree
+                    .component_mul(&self.graph.mul_vec(&scaled))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpectralClusteringConfig {
+    /// See `similarity::from_name`, `$SIMILARITY` by default.
+    pub similarity: String,
+    /// `$SPECTRAL_GRAPH` by default.
+    #[serde(with = "config::by_str")]
+    pub graph: GraphKind,
+    /// `$SPECTRAL_LAPLACIAN` by default.
+    #[serde(with = "config::by_str")]
+    pub laplacian: LaplacianKind,
+    /// Only the first `max_movies` movies are used, if set.
+    pub max_movies: Option<usize>,
+    /// # of eigen pairs computed for the spectral embedding.
+    pub num_eigen: usize,
+    /// # of movie clusters.
+    pub num_clusters: usize,
+    /// Seed of k-means.
+    pub seed: u64,
+}
+
+impl Default for SpectralClusteringConfig {
+    fn default() -> Self {
+        Self {
+            similarity: similarity::from_env().get_name().to_string(),
+            graph: config::from_env(config::SPECTRAL_GRAPH, GraphKind::Knn(50)),
+            laplacian: config::from_env(config::SPECTRAL_LAPLACIAN, LaplacianKind::Symmetric),
+            max_movies: None,
+            num_eigen: 20,
+            num_clusters: 50,
+            seed: KMeans::new(0).seed,
+        }
+    }
+}
+
+/// The affinity graphs are only needed for training, they are not saved.
+#[derive(Debug, Serialize, Deserialize)]
 struct SpectralClustering {
-    movie_similarity: DMatrix<f64>,
-    customer_similarity: DMatrix<f64>,
-    customer_movie: DMatrix<f64>,
+    config: SpectralClusteringConfig,
+    #[serde(skip)]
+    movie_graph: AffinityGraph,
+    /// Same as `movie_graph`, `None` if not computed.
+    #[serde(skip)]
+    customer_graph: Option<AffinityGraph>,
+    customer_movie: SparseMatrix,
+    #[serde(with = "similarity::by_name")]
+    similarity: Box<dyn Similarity>,
+    /// Row `i` is the spectral embedding of movie `i`.
+    movie_embedding: DMatrix<f64>,
+    /// Cluster of every movie.
+    movie_clusters: Vec<usize>,
+    movie_avg: Vec<f64>,
+    global_avg: f64,
+    scale: RatingScale,
 }
 
 impl Default for SpectralClustering {
     fn default() -> Self {
         SpectralClustering {
-            movie_similarity: DMatrix::zeros(1, 1),
-            customer_similarity: DMatrix::zeros(1, 1),
-            customer_movie: DMatrix::zeros(1, 1),
+            config: SpectralClusteringConfig::default(),
+            movie_graph: AffinityGraph::default(),
+            customer_graph: None,
+            customer_movie: SparseMatrix::default(),
+            similarity: Box::new(similarity::Pearson),
+            movie_embedding: DMatrix::zeros(0, 0),
+            movie_clusters: vec![],
+            movie_avg: vec![],
+            global_avg: 0f64,
+            scale: RatingScale::default(),
         }
     }
 }
@@ -25,34 +322,75 @@ impl Model for SpectralClustering {
     fn get_name(&self) -> &'static str {
         "SpectralClustering"
     }
+    fn tags(&self) -> &'static [&'static str] {
+        &["slow", "experimental"]
+    }
+    fn config(&self) -> Map<String, Value> {
+        to_params(&self.config)
+    }
+    fn configure(&mut self, params: &Map<String, Value>) -> Result<(), Box<dyn Error>> {
+        let config: SpectralClusteringConfig = merge_params(&self.config, params)?;
+        similarity::check_name(&config.similarity)?;
+        if config.num_eigen == 0 || config.num_clusters == 0 {
+            return Err("num_eigen and num_clusters must be positive".into());
+        }
+        self.config = config;
+        Ok(())
+    }
     fn init(&mut self, data: &Data) -> &mut dyn Model {
         info!("{}.init(&Data)", self.get_name());
+        self.scale = data.scale;
+        self.similarity = similarity::from_name(&self.config.similarity)
+            .expect("Similarity checked by configure.");
+        info!(
+            "Using {} similarity, {} graph and {} laplacian",
+            self.similarity.get_name(),
+            self.config.graph,
+            self.config.laplacian
+        );
         let (elapsed, _) = measure_time(|| {
             info!("Convert data to matrix");
             let (elapsed, _) = measure_time(|| {
-                self.customer_movie = data.training_data_to_matrix().columns(0, 1000).into();
-
-                //self.customer_movie = data.training_data_to_matrix()
+                self.customer_movie = data.training_data_to_sparse();
+                if let Some(m) = self.config.max_movies {
+                    self.customer_movie = self.customer_movie.first_columns(m);
+                }
             });
             info!("Convert data to matrix finished... elapsed: {}", elapsed);
-            info!("Matrix shape: {:?}", self.customer_movie.shape());
+            info!(
+                "Matrix shape: {:?}, # of non zeros: {}",
+                self.customer_movie.shape(),
+                self.customer_movie.nnz()
+            );
+            let (movie_avg, global_avg) = rating_averages(&self.customer_movie);
+            self.movie_avg = movie_avg;
+            self.global_avg = global_avg;
 
-            info!("Generate movie similarity matrix");
+            info!("Generate movie affinity graph");
             let (elapsed, _) = measure_time(|| {
-                self.movie_similarity = self.customer_movie.get_similarity_matrix()
+                self.movie_graph = AffinityGraph::new(
+                    &self.customer_movie,
+                    self.similarity.as_ref(),
+                    self.config.graph,
+                );
             });
             info!(
-                "Generate movie similarity matrix finished... elapsed: {}",
-                elapsed
+                "Generate movie affinity graph finished... elapsed: {}, # of edges: {}",
+                elapsed,
+                self.movie_graph.sparse.nnz()
             );
 
             /*
-            info!("Generate customer similarity matrix");
+            info!("Generate customer affinity graph");
             let (elapsed, _) = measure_time(|| {
-                self.customer_similarity = self.customer_movie.transpose().get_similarity_matrix();
+                self.customer_graph = Some(AffinityGraph::new(
+                    &self.customer_movie.transpose(),
+                    self.similarity.as_ref(),
+                    self.config.graph,
+                ));
             });
             info!(
-                "Generate customer similarity matrix finished... elapsed: {}",
+                "Generate customer affinity graph finished... elapsed: {}",
                 elapsed
             );
             */
@@ -67,137 +405,114 @@ impl Model for SpectralClustering {
     fn train(&mut self) -> &mut dyn Model {
         info!("{}.train()", self.get_name());
         let (elapsed, (movie_eigen, customer_eigen)) = measure_time(|| {
-            info!("Eigen decompose movie similarity matrix");
-            let (elapsed, movie_eigen) =
-                measure_time(|| SymmetricEigen::new(self.movie_similarity.clone()));
+            info!("Eigen decompose movie laplacian");
+            let (elapsed, movie_eigen) = measure_time(|| {
+                Laplacian::new(&self.movie_graph, self.config.laplacian)
+                    .embedding(self.config.num_eigen)
+            });
             info!(
-                "Eigen decompose movie similarity matrix finished... elapsed: {}",
+                "Eigen decompose movie laplacian finished... elapsed: {}",
                 elapsed
             );
 
-            info!("Eigen decompose customer similarity matrix");
-            let (elapsed, customer_eigen) =
-                measure_time(|| SymmetricEigen::new(self.movie_similarity.clone()));
+            let customer_graph = match &self.customer_graph {
+                Some(graph) => graph,
+                None => return (movie_eigen, None),
+            };
+            info!("Eigen decompose customer laplacian");
+            let (elapsed, customer_eigen) = measure_time(|| {
+                Laplacian::new(customer_graph, self.config.laplacian)
+                    .embedding(self.config.num_eigen)
+            });
             info!(
-                "Eigen decompose customer similarity matrix finished... elapsed: {}",
+                "Eigen decompose customer laplacian finished... elapsed: {}",
                 elapsed
             );
-            (movie_eigen, customer_eigen)
+            (movie_eigen, Some(customer_eigen))
         });
         info!(
             "{}.train() finished... elapsed: {}",
             self.get_name(),
             elapsed
         );
-        let cnt = movie_eigen.eigenvalues.iter().fold(0, |cnt, v| {
-            if (v - 0f64).abs() < 1e-10 {
-                cnt + 1
-            } else {
-                cnt
-            }
-        });
-        info!("# of 0 eigen values in movie: {}", cnt);
-
-        let cnt = customer_eigen.eigenvalues.iter().fold(0, |cnt, v| {
-            if (v - 0f64).abs() < 1e-10 {
-                cnt + 1
-            } else {
-                cnt
-            }
-        });
-        info!("# of 0 eigen values in customer: {}", cnt);
-
-        self
-    }
-    fn predict(&self, _trans: &Transaction) -> Rating {
-        0
-    }
-}
+        info!(
+            "Smallest {} eigen values in movie: {:?}",
+            self.config.num_eigen,
+            movie_eigen.0.as_slice()
+        );
+        if let Some(customer_eigen) = customer_eigen {
+            info!(
+                "Smallest {} eigen values in customer: {:?}",
+                self.config.num_eigen,
+                customer_eigen.0.as_slice()
+            );
+        }
 
-/// Get pearson consine similarity matrix of size m x m from matrix n x m;
-///
-/// Pearson consine similarity is defined by
-/// ```math
-/// cos(x, y) = \frac{
-///     (x - \bar{x})^T \cdot (y - \bar{y})
-/// }{
-///     ||x - \bar{x}|| \cdot ||y - \bar{y}||
-/// }
-/// ```
-trait PearsonCosineSimilarity {
-    /// Get a vector over all rows(items)
-    fn get_avg_and_non_zero_idx(&self) -> (Vec<f64>, Vec<Vec<usize>>);
-
-    /// Get similarity matrix of size m x m from matrix of size m x n
-    fn get_similarity_matrix(&self) -> DMatrix<f64>;
-}
-
-impl PearsonCosineSimilarity for DMatrix<f64> {
-    fn get_avg_and_non_zero_idx(&self) -> (Vec<f64>, Vec<Vec<usize>>) {
-        let (n, m) = self.shape();
-        let mut non_zero_idx = vec![vec![]; m];
-        let mut avg = vec![0f64; m];
-
-        for j in 0..m {
-            let curr = &mut non_zero_idx[j];
-            let col_j = self.column(j);
-            let mut sum = 0f64;
-            for i in 0..n {
-                if col_j[i] != 0f64 {
-                    curr.push(i);
-                    sum += col_j[i];
+        self.movie_embedding = movie_eigen.1;
+        if self.config.laplacian == LaplacianKind::Symmetric {
+            // Ng, Jordan and Weiss: project every movie onto the unit sphere.
+            for mut row in self.movie_embedding.row_iter_mut() {
+                let norm = row.norm();
+                if norm > 0f64 {
+                    row /= norm;
                 }
             }
-            avg[j] = if curr.len() != 0 {
-                sum / curr.len() as f64
-            } else {
-                0f64
-            };
         }
-        (avg, non_zero_idx)
-    }
-    fn get_similarity_matrix(&self) -> DMatrix<f64> {
-        let (_, m) = self.shape();
-        let (avg, non_zero_idx) = self.get_avg_and_non_zero_idx();
+        info!("Cluster movie embedding");
+        let kmeans = KMeans {
+            seed: self.config.seed,
+            ..KMeans::new(self.config.num_clusters)
+        };
+        let (elapsed, clustering) = measure_time(|| kmeans.fit(&self.movie_embedding));
+        info!("Cluster movie embedding finished... elapsed: {}", elapsed);
+        let mut sizes = vec![0; kmeans.k];
+        clustering.labels.iter().for_each(|&c| sizes[c] += 1);
+        info!("Movie cluster sizes: {:?}", sizes);
+        self.movie_clusters = clustering.labels;
 
-        let mut norm = vec![0f64; m];
-        let mut matrix = self.clone();
-        for j in 0..non_zero_idx.len() {
-            non_zero_idx[j].iter().for_each(|&i| {
-                matrix[(i, j)] -= avg[j];
-                norm[j] += matrix[(i, j)].powi(2);
-            });
+        self
+    }
+    /// The customer's average rating over the movies in the same cluster,
+    /// falling back to the movie's average.
+    fn predict(&self, trans: &Transaction) -> Rating {
+        let i = trans.movie_id;
+        let base = self.movie_avg.get(i).copied().unwrap_or(self.global_avg);
+        if trans.customer_id >= self.customer_movie.nrows || i >= self.movie_clusters.len() {
+            return self.scale.clamp(base);
         }
-        norm.iter_mut().for_each(|n| *n = n.sqrt());
-
-        let mut similarility = Self::zeros(m, m);
-        // Divide similarity[i][j] by |item_i| and |item_j|, which is located
-        // in the diag of similarity.
-        for j in 0..m {
-            for i in j + 1..m {
-                if norm[i] == 0f64 || norm[j] == 0f64 {
-                    continue;
-                }
-                let nx = &non_zero_idx[i];
-                let ny = &non_zero_idx[j];
-                let mut p = 0;
-                let mut q = 0;
-                while p < nx.len() && q < ny.len() {
-                    if nx[p] == ny[q] {
-                        similarility[(i, j)] += matrix[(nx[p], i)] * matrix[(ny[q], j)];
-                        p += 1;
-                        q += 1;
-                    } else if nx[p] < ny[q] {
-                        p += 1;
-                    } else {
-                        q += 1;
-                    }
-                }
-                similarility[(i, j)] = similarility[(i, j)] / (norm[i] * norm[j]);
-                similarility[(j, i)] = similarility[(i, j)];
-            }
+        let cluster = self.movie_clusters[i];
+        let (rated, ratings) = self.customer_movie.row(trans.customer_id);
+        let (sum, cnt) = rated
+            .iter()
+            .zip(ratings)
+            .filter(|(&j, _)| self.movie_clusters[j] == cluster)
+            .fold((0f64, 0), |(sum, cnt), (_, &r)| (sum + r, cnt + 1));
+        if cnt == 0 {
+            self.scale.clamp(base)
+        } else {
+            self.scale.clamp(sum / cnt as f64)
         }
-        similarility
+    }
+    fn movie_embedding(&self) -> Option<&DMatrix<f64>> {
+        Some(&self.movie_embedding)
+    }
+    /// New ratings join the customers' rows, clusters are kept as
+    /// trained.
+    fn update(&mut self, new: &[Transaction]) -> Result<(), Box<dyn Error>> {
+        let (triplets, customers) = new_ratings(new, self.customer_movie.ncols);
+        self.customer_movie = self.customer_movie.with_entries(triplets)?;
+        let (movie_avg, global_avg) = rating_averages(&self.customer_movie);
+        self.movie_avg = movie_avg;
+        self.global_avg = global_avg;
+        info!("{} updated {} customers", self.get_name(), customers.len());
+        Ok(())
+    }
+    fn save(&self, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+        persist::save_state(self, writer)
+    }
+    fn load(&mut self, reader: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        *self = persist::load_state(reader)?;
+        Ok(())
     }
 }
 
@@ -205,36 +520,69 @@ impl PearsonCosineSimilarity for DMatrix<f64> {
 mod test {
     use super::*;
     #[test]
-    fn test_pearson_cosine_similarity() {
+    fn test_laplacian() {
+        // Two groups of movies, {0, 1, 2} and {3, 4}, no customer rated both.
         let matrix = DMatrix::<f64>::from_row_slice(
-            3,
-            6,
+            4,
+            5,
             &[
-                1f64, 2f64, 0f64, 0f64, 1f64, 0f64, 0f64, 1f64, 2f64, 0f64, 3f64, 0f64, 2f64, 2f64,
-                4f64, 0f64, 2f64, 0f64,
+                5f64, 4f64, 5f64, 0f64, 0f64, 4f64, 5f64, 4f64, 0f64, 0f64, 0f64, 0f64, 0f64, 1f64,
+                2f64, 0f64, 0f64, 0f64, 2f64, 1f64,
             ],
         );
-        assert!(matrix.shape() == (3, 6));
-        let (avg, non_zero_idx) = matrix.get_avg_and_non_zero_idx();
-        assert!(avg == vec![1.5f64, 5f64 / 3f64, 3f64, 0f64, 2f64, 0f64]);
-        assert!(
-            non_zero_idx
-                == vec![
-                    vec![0, 2],
-                    vec![0, 1, 2],
-                    vec![1, 2],
-                    vec![],
-                    vec![0, 1, 2],
-                    vec![],
-                ]
-        );
-        let similarity = matrix.get_similarity_matrix();
-        assert!(similarity == similarity.transpose());
-        assert!(similarity[(0, 0)] == 0f64);
-        assert!(similarity[(5, 3)] == 0f64);
-        assert!(similarity[(0, 1)] == 0f64);
-        assert!(similarity[(3, 0)] == 0f64);
-        assert!(similarity[(4, 0)] - 0.5f64 < 1e-10);
-        assert!((similarity[(1, 4)] - -f64::sqrt(3f64) / 2f64).abs() < 1e-10);
+        let matrix = SparseMatrix::from_dense(&matrix);
+        for graph in [
+            GraphKind::Knn(2),
+            GraphKind::Epsilon(0.1),
+            GraphKind::Gaussian(1f64),
+        ]
+        .iter()
+        {
+            let graph = AffinityGraph::new(&matrix, &similarity::Cosine, *graph);
+            let degree = graph.mul_vec(&DVector::from_element(5, 1f64));
+            assert!((degree - &graph.degree).norm() < 1e-10);
+            for kind in [
+                LaplacianKind::Unnormalized,
+                LaplacianKind::Symmetric,
+                LaplacianKind::RandomWalk,
+            ]
+            .iter()
+            {
+                let laplacian = Laplacian::new(&graph, *kind);
+                let (values, vectors) = laplacian.embedding(2);
+                assert!(values.iter().all(|v| *v > -1e-8));
+                if *kind != LaplacianKind::RandomWalk {
+                    for i in 0..2 {
+                        let v = vectors.column(i).into_owned();
+                        assert!((laplacian.apply(&v) - &v * values[i]).norm() < 1e-6);
+                    }
+                }
+            }
+        }
+        // Without the gaussian kernel the groups are disconnected, so the
+        // unnormalized laplacian has the two indicator vectors as kernel.
+        let graph = AffinityGraph::new(&matrix, &similarity::Cosine, GraphKind::Knn(2));
+        let (values, vectors) = Laplacian::new(&graph, LaplacianKind::Unnormalized).embedding(2);
+        assert!(values.iter().all(|v| v.abs() < 1e-8));
+        for i in 0..2 {
+            let v = vectors.column(i);
+            assert!((v[0] - v[1]).abs() < 1e-6 && (v[1] - v[2]).abs() < 1e-6);
+            assert!((v[3] - v[4]).abs() < 1e-6);
+        }
+        assert!("knn:10".parse::<GraphKind>() == Ok(GraphKind::Knn(10)));
+        assert!("gaussian".parse::<GraphKind>().is_err());
+        for invalid in &[
+            "knn:0",
+            "epsilon:0",
+            "epsilon:1.5",
+            "gaussian:0",
+            "gaussian:-1",
+            "gaussian:NaN",
+            "gaussian:inf",
+        ] {
+            assert!(invalid.parse::<GraphKind>().is_err());
+        }
+        assert!("epsilon:1".parse::<GraphKind>() == Ok(GraphKind::Epsilon(1f64)));
+        assert!("random_walk".parse::<LaplacianKind>() == Ok(LaplacianKind::RandomWalk));
     }
 }
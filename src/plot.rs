@@ -1,12 +1,37 @@
-use std::error::Error;
+use std::{error::Error, path::Path, str::FromStr};
 
 use log::info;
+use nalgebra::core::DMatrix;
 use plotters::prelude::*;
 
+use crate::algorithm::{pca, Tsne};
+use crate::config;
 use crate::data::{Data, MetaData, Transaction};
 
-#[allow(dead_code)]
-pub fn plot_initial_matrix(data: &Data) -> Result<(), Box<dyn Error>> {
+/// # of most rated movies whose title is written on embedding plots.
+const NUM_ANNOTATED: usize = 30;
+
+/// How an embedding is brought down to 2-D.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Pca,
+    Tsne,
+}
+
+impl FromStr for Projection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pca" => Ok(Projection::Pca),
+            "tsne" => Ok(Projection::Tsne),
+            _ => Err(format!("Unknown projection {}, try pca or tsne", s)),
+        }
+    }
+}
+
+/// Every known and unknown entry of the customer x movie matrix, to
+/// `initial_matrix.png` in `out`.
+pub fn plot_initial_matrix(data: &Data, out: &Path) -> Result<(), Box<dyn Error>> {
     let MetaData {
         num_customers: n,
         num_movies: m,
@@ -14,7 +39,7 @@ pub fn plot_initial_matrix(data: &Data) -> Result<(), Box<dyn Error>> {
         num_cross_valid: _,
         trans_freq: _,
         tests_freq: _,
-    } = data.metadata;
+    } = *data.metadata();
 
     let (x_label_size, y_label_size) = (100, 100);
     let margin = 100;
@@ -23,7 +48,8 @@ pub fn plot_initial_matrix(data: &Data) -> Result<(), Box<dyn Error>> {
         x_label_size + margin * 2 + n as u32 / 5,
     );
 
-    let root = BitMapBackend::new("initial_matrix.png", plot_size).into_drawing_area();
+    let path = out.join("initial_matrix.png");
+    let root = BitMapBackend::new(&path, plot_size).into_drawing_area();
     root.fill(&WHITE)?;
     let root = root.margin(margin, margin, margin, margin);
     // After this point, we should be able to draw construct a chart context
@@ -55,34 +81,33 @@ pub fn plot_initial_matrix(data: &Data) -> Result<(), Box<dyn Error>> {
         .axis_desc_style(("sans-serif", 50).into_font())
         .draw()?;
 
-    let mut plot_points =
-        |data: &Vec<Transaction>, color: &RGBColor| -> Result<(), Box<dyn Error>> {
-            // Similarly, we can draw point series
-            chart.draw_series(PointSeries::of_element(
-                data.iter()
-                    .map(|t| (t.movie_id as f32, t.customer_id as f32)),
-                1,
-                color,
-                &|c, _s, st| {
-                    return EmptyElement::at(c)    // We want to construct a composed element on-the-fly
-                + Pixel::new((0,0),st.filled()); // At this point, the new pixel coordinate is established
-                },
-            ))?;
-            Ok(())
-        };
-
-    plot_points(&data.train, &BLUE)?;
-    plot_points(&data.cross_valid, &YELLOW)?;
+    let mut plot_points = |data: &[Transaction], color: &RGBColor| -> Result<(), Box<dyn Error>> {
+        // Similarly, we can draw point series
+        chart.draw_series(PointSeries::of_element(
+            data.iter()
+                .map(|t| (t.movie_id as f32, t.customer_id as f32)),
+            1,
+            color,
+            &|c, _s, st| {
+                EmptyElement::at(c)    // We want to construct a composed element on-the-fly
+                + Pixel::new((0,0),st.filled()) // At this point, the new pixel coordinate is established
+            },
+        ))?;
+        Ok(())
+    };
+
+    plot_points(data.train(), &BLUE)?;
+    plot_points(data.cross_valid(), &YELLOW)?;
     plot_points(&data.test_data, &RED)?;
     Ok(())
 }
 
 fn plot_freq_histogram(
-    data: &Vec<u32>,
+    data: &[u32],
     max_x: u32,
     max_y: u32,
     title: &'static str,
-    path: &'static str,
+    path: &Path,
 ) -> Result<(), Box<dyn Error>> {
     let root = BitMapBackend::new(path, (1920, 1080)).into_drawing_area();
 
@@ -114,8 +139,9 @@ fn plot_freq_histogram(
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn plot_data_freq(metadata: &MetaData) -> Result<(), Box<dyn Error>> {
+/// Histograms of the # of transactions and tests of every customer, to
+/// `trans_freq.png` and `tests_freq.png` in `out`.
+pub fn plot_data_freq(metadata: &MetaData, out: &Path) -> Result<(), Box<dyn Error>> {
     let MetaData {
         num_customers: n,
         num_movies: _,
@@ -139,7 +165,7 @@ pub fn plot_data_freq(metadata: &MetaData) -> Result<(), Box<dyn Error>> {
         n,
         max_trans + 10,
         "Transaction Frequency",
-        "trans_freq.png",
+        &out.join("trans_freq.png"),
     )?;
     info!("Plotted transaction frequency");
 
@@ -148,9 +174,97 @@ pub fn plot_data_freq(metadata: &MetaData) -> Result<(), Box<dyn Error>> {
         n,
         max_tests + 10,
         "Test Frequency",
-        "tests_freq.png",
+        &out.join("tests_freq.png"),
     )?;
     info!("Plotted test frequency");
 
     Ok(())
 }
+
+/// Scatter the movies of the m x d `embedding` (row `j` is movie `j`) in
+/// 2-D, e.g. factors of a factor model or spectral eigenvectors.
+///
+/// Only the `$EMBEDDING_MOVIES` most rated movies are drawn, and the
+/// `NUM_ANNOTATED` most rated ones are labelled with their titles, so one
+/// can check by eye that similar movies end up together.
+pub fn plot_movie_embedding(
+    data: &Data,
+    embedding: &DMatrix<f64>,
+    title: &str,
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let projection = config::from_env(config::EMBEDDING_PROJECTION, Projection::Pca);
+    let num_movies = config::from_env(config::EMBEDDING_MOVIES, 1000usize);
+
+    let mut num_ratings = vec![0usize; embedding.nrows()];
+    data.train()
+        .iter()
+        .filter(|t| t.movie_id < embedding.nrows())
+        .for_each(|t| num_ratings[t.movie_id] += 1);
+    let mut movies: Vec<usize> = (0..embedding.nrows()).collect();
+    movies.sort_by_key(|&j| std::cmp::Reverse(num_ratings[j]));
+    movies.truncate(num_movies);
+    if movies.is_empty() {
+        return Err("Nothing to plot, the embedding is empty.".into());
+    }
+
+    let points = DMatrix::from_fn(movies.len(), embedding.ncols(), |r, c| {
+        embedding[(movies[r], c)]
+    });
+    info!(
+        "Project {} movies from {} to 2 dimensions by {:?}",
+        movies.len(),
+        embedding.ncols(),
+        projection
+    );
+    let projected = match projection {
+        Projection::Pca => pca(&points, 2),
+        Projection::Tsne => Tsne::default().fit(&points),
+    };
+    let xy = |r: usize| {
+        (
+            projected[(r, 0)],
+            if projected.ncols() > 1 {
+                projected[(r, 1)]
+            } else {
+                0f64
+            },
+        )
+    };
+    let (mut x_range, mut y_range) = ((f64::MAX, f64::MIN), (f64::MAX, f64::MIN));
+    for r in 0..movies.len() {
+        let (x, y) = xy(r);
+        x_range = (x_range.0.min(x), x_range.1.max(x));
+        y_range = (y_range.0.min(y), y_range.1.max(y));
+    }
+    let pad = |(lo, hi): (f64, f64)| {
+        let pad = f64::max((hi - lo) * 0.05, 1e-6);
+        (lo - pad)..(hi + pad)
+    };
+
+    let root = BitMapBackend::new(path, (1920, 1920)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(&root)
+        .x_label_area_size(35)
+        .y_label_area_size(40)
+        .margin(20)
+        .caption(title, ("sans-serif", 50.0).into_font())
+        .build_ranged(pad(x_range), pad(y_range))?;
+    chart.configure_mesh().disable_mesh().draw()?;
+
+    chart.draw_series((0..movies.len()).map(|r| Circle::new(xy(r), 3, BLUE.mix(0.5).filled())))?;
+    chart.draw_series(
+        (0..usize::min(NUM_ANNOTATED, movies.len())).filter_map(|r| {
+            data.movies
+                .iter()
+                .find(|m| m.movie_id == movies[r])
+                .map(|movie| {
+                    EmptyElement::at(xy(r))
+                        + Circle::new((0, 0), 4, RED.filled())
+                        + Text::new(movie.title.clone(), (6, -6), ("sans-serif", 18).into_font())
+                })
+        }),
+    )?;
+    info!("Plotted movie embedding to {:?}", path);
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use log::info;
+use std::{collections::HashMap, error::Error, fs::File, io::Write, path::Path};
+
+use elapsed::measure_time;
+
+use crate::algorithm::{Agglomerative, Linkage};
+use crate::config;
+use crate::data::{Data, TrainingDataToSparse};
+use crate::io::DumpToFile;
+use crate::similarity::{self, SimilarityMatrix};
+
+/// Hierarchically cluster the most rated movies by their similarity.
+///
+/// The distance between two movies is $`1 - s_{ij}`$. The dendrogram is
+/// dumped to `dendrogram.txt` in `out`, one `left,right,distance,size` merge per
+/// line, with the original id of every leaf in `dendrogram_movies.txt`,
+/// and the movies of every flat cluster to `movie_clusters.txt`.
+pub fn movie_clusters(data: &Data, out: &Path) -> Result<(), Box<dyn Error>> {
+    let linkage = config::from_env(config::HIERARCHICAL_LINKAGE, Linkage::Average);
+    let num_clusters = config::from_env(config::HIERARCHICAL_CLUSTERS, 50usize);
+    let num_movies = config::from_env(config::HIERARCHICAL_MOVIES, 2000usize);
+    let measure = similarity::from_env();
+    let customer_movie = data.training_data_to_sparse();
+    let mut num_ratings = vec![0usize; customer_movie.ncols];
+    customer_movie
+        .indices
+        .iter()
+        .for_each(|&j| num_ratings[j] += 1);
+    let mut movies: Vec<usize> = (0..customer_movie.ncols).collect();
+    movies.sort_by_key(|&j| std::cmp::Reverse(num_ratings[j]));
+    movies.truncate(num_movies);
+    movies.sort_unstable();
+    info!(
+        "Hierarchical clustering of {} movies, {:?} linkage, {} similarity",
+        movies.len(),
+        linkage,
+        measure.get_name()
+    );
+
+    let (elapsed, dendrogram) = measure_time(|| {
+        let similarity = customer_movie
+            .select_columns(&movies)
+            .get_similarity_matrix(measure.as_ref());
+        let distances = similarity.map(|s| (1f64 - s).max(0f64));
+        Agglomerative::new(linkage).fit(&distances)
+    });
+    info!("Hierarchical clustering finished... elapsed: {}", elapsed);
+    dendrogram.merges.dump_to_file(&out.join("dendrogram.txt"));
+    movies
+        .iter()
+        .map(|&j| data.movie_ids.to_original(j))
+        .collect::<Vec<_>>()
+        .dump_to_file(&out.join("dendrogram_movies.txt"));
+
+    let titles: HashMap<usize, _> = data.movies.iter().map(|m| (m.movie_id, m)).collect();
+    let labels = dendrogram.cut(num_clusters);
+    // `cut` makes at least one cluster, even if asked for none.
+    let mut clusters = vec![vec![]; labels.iter().max().map_or(0, |&c| c + 1)];
+    labels
+        .iter()
+        .zip(&movies)
+        .for_each(|(&c, &j)| clusters[c].push(j));
+    clusters.sort_by_key(|c| std::cmp::Reverse(c.len()));
+
+    let path = out.join("movie_clusters.txt");
+    let mut file = File::create(&path)?;
+    for (c, cluster) in clusters.iter().enumerate() {
+        writeln!(file, "Cluster {} ({} movies):", c, cluster.len())?;
+        for j in cluster {
+            let id = data.movie_ids.to_original(*j);
+            match titles.get(j) {
+                Some(movie) => {
+                    writeln!(file, "    {} {} ({})", id, movie.title, movie.year_produced)?
+                }
+                None => writeln!(file, "    {} <unknown movie>", id)?,
+            }
+        }
+    }
+    info!("Movie clusters written to {:?}", path);
+    Ok(())
+}
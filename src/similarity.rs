@@ -0,0 +1,534 @@
+use log::warn;
+use nalgebra::core::DMatrix;
+use rayon::prelude::*;
+use std::{env, fmt::Debug};
+
+use crate::config;
+use crate::data::SparseMatrix;
+
+/// Number of items a worker takes at a time when computing similarities.
+/// Each worker owns one dense accumulator of length m which stays hot in
+/// cache for the whole block.
+const SIMILARITY_BLOCK_SIZE: usize = 64;
+
+/// Default $`\lambda`$ of the significance shrunk measures.
+const DEFAULT_SHRINKAGE: f64 = 100f64;
+
+/// What two items `x` and `y` have in common: sums over the customers
+/// who rated both of them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CoRatings {
+    pub count: u32,
+    pub xy: f64,
+    pub xx: f64,
+    pub yy: f64,
+}
+
+/// Sums over all customers who rated one item.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ItemStats {
+    pub count: u32,
+    /// $`||x||`$ over all non zero entries.
+    pub norm: f64,
+}
+
+/// `Similarity` is a measure between two items (columns) of a
+/// customer x item matrix.
+///
+/// A measure may rewrite the matrix first (e.g. centering), after which
+/// the similarity of a pair must only depend on its `CoRatings` and the
+/// `ItemStats` of both items. That is what lets every measure share the
+/// same sparse, parallel accumulation.
+pub trait Similarity: Debug + Sync + Send {
+    fn get_name(&self) -> &str;
+    /// Rewrite the n x m matrix before comparing its columns.
+    fn transform(&self, matrix: &SparseMatrix) -> SparseMatrix {
+        matrix.clone()
+    }
+    /// Similarity of `x` and `y` given what they have in common.
+    fn similarity(&self, co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64;
+}
+
+fn cosine(co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64 {
+    if x.norm == 0f64 || y.norm == 0f64 {
+        0f64
+    } else {
+        co.xy / (x.norm * y.norm)
+    }
+}
+
+/// Pearson consine similarity is defined by
+/// ```math
+/// cos(x, y) = \frac{
+///     (x - \bar{x})^T \cdot (y - \bar{y})
+/// }{
+///     ||x - \bar{x}|| \cdot ||y - \bar{y}||
+/// }
+/// ```
+/// where the mean and the norm are taken over the non zero entries of each item.
+#[derive(Debug, Default)]
+pub struct Pearson;
+
+impl Similarity for Pearson {
+    fn get_name(&self) -> &str {
+        "pearson"
+    }
+    fn transform(&self, matrix: &SparseMatrix) -> SparseMatrix {
+        let (avg, _) = matrix.get_avg_and_non_zero_idx();
+        let mut ret = matrix.clone();
+        ret.indices
+            .iter()
+            .zip(ret.values.iter_mut())
+            .for_each(|(&j, v)| *v -= avg[j]);
+        ret
+    }
+    fn similarity(&self, co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64 {
+        cosine(co, x, y)
+    }
+}
+
+/// Same as `Pearson` but every rating is centered by the mean of the
+/// customer who gave it, which removes customers being generous or harsh.
+#[derive(Debug, Default)]
+pub struct AdjustedCosine;
+
+impl Similarity for AdjustedCosine {
+    fn get_name(&self) -> &str {
+        "adjusted_cosine"
+    }
+    fn transform(&self, matrix: &SparseMatrix) -> SparseMatrix {
+        let mut ret = matrix.clone();
+        for i in 0..ret.nrows {
+            let range = ret.indptr[i]..ret.indptr[i + 1];
+            if range.is_empty() {
+                continue;
+            }
+            let row = &mut ret.values[range];
+            let avg = row.iter().sum::<f64>() / row.len() as f64;
+            row.iter_mut().for_each(|v| *v -= avg);
+        }
+        ret
+    }
+    fn similarity(&self, co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64 {
+        cosine(co, x, y)
+    }
+}
+
+/// Plain cosine of the raw ratings, $`\frac{x^T y}{||x|| \cdot ||y||}`$.
+#[derive(Debug, Default)]
+pub struct Cosine;
+
+impl Similarity for Cosine {
+    fn get_name(&self) -> &str {
+        "cosine"
+    }
+    fn similarity(&self, co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64 {
+        cosine(co, x, y)
+    }
+}
+
+/// Jaccard index of the sets of customers who rated each item,
+/// $`\frac{|X \cap Y|}{|X \cup Y|}`$. The ratings themselves are ignored.
+#[derive(Debug, Default)]
+pub struct Jaccard;
+
+impl Similarity for Jaccard {
+    fn get_name(&self) -> &str {
+        "jaccard"
+    }
+    fn similarity(&self, co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64 {
+        let union = x.count + y.count - co.count;
+        if union == 0 {
+            0f64
+        } else {
+            co.count as f64 / union as f64
+        }
+    }
+}
+
+/// Mean squared difference over the co-rated customers, turned into a
+/// similarity by $`\frac{1}{1 + msd(x, y)}`$.
+#[derive(Debug, Default)]
+pub struct MeanSquaredDifference;
+
+impl Similarity for MeanSquaredDifference {
+    fn get_name(&self) -> &str {
+        "msd"
+    }
+    fn similarity(&self, co: &CoRatings, _x: &ItemStats, _y: &ItemStats) -> f64 {
+        if co.count == 0 {
+            return 0f64;
+        }
+        let msd = (co.xx + co.yy - 2f64 * co.xy) / co.count as f64;
+        1f64 / (1f64 + msd)
+    }
+}
+
+/// Shrinks another measure towards 0 when it is supported by few co-ratings:
+/// ```math
+/// s'(x, y) = \frac{|X \cap Y|}{|X \cap Y| + \lambda} s(x, y)
+/// ```
+#[derive(Debug)]
+pub struct Shrunk {
+    name: String,
+    inner: Box<dyn Similarity>,
+    shrinkage: f64,
+}
+
+impl Shrunk {
+    pub fn new(inner: Box<dyn Similarity>, shrinkage: f64) -> Self {
+        Self {
+            name: format!("shrunk_{}", inner.get_name()),
+            inner,
+            shrinkage,
+        }
+    }
+}
+
+impl Similarity for Shrunk {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+    fn transform(&self, matrix: &SparseMatrix) -> SparseMatrix {
+        self.inner.transform(matrix)
+    }
+    fn similarity(&self, co: &CoRatings, x: &ItemStats, y: &ItemStats) -> f64 {
+        let count = co.count as f64;
+        count / (count + self.shrinkage) * self.inner.similarity(co, x, y)
+    }
+}
+
+/// Names accepted by `from_name`. Any of them can be prefixed by `shrunk_`.
+pub const NAMES: [&str; 5] = ["pearson", "adjusted_cosine", "cosine", "jaccard", "msd"];
+
+/// Get a measure by its name, e.g. `pearson` or `shrunk_adjusted_cosine`.
+pub fn from_name(name: &str) -> Option<Box<dyn Similarity>> {
+    if let Some(inner) = name.strip_prefix("shrunk_") {
+        return from_name(inner).map(|s| Box::new(Shrunk::new(s, DEFAULT_SHRINKAGE)) as _);
+    }
+    match name {
+        "pearson" => Some(Box::new(Pearson)),
+        "adjusted_cosine" => Some(Box::new(AdjustedCosine)),
+        "cosine" => Some(Box::new(Cosine)),
+        "jaccard" => Some(Box::new(Jaccard)),
+        "msd" => Some(Box::new(MeanSquaredDifference)),
+        _ => None,
+    }
+}
+
+/// Fails with the available names if there is no measure `name`.
+pub fn check_name(name: &str) -> Result<(), String> {
+    match from_name(name) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "Unknown similarity {:?}, expecting one of {:?}, optionally prefixed by shrunk_",
+            name, NAMES
+        )),
+    }
+}
+
+/// Serialize a measure as its name, use with `#[serde(with = "similarity::by_name")]`.
+pub mod by_name {
+    use super::{from_name, Similarity};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    // serde hands us a reference to the field, which is a `Box`.
+    #[allow(clippy::borrowed_box)]
+    pub fn serialize<S: Serializer>(
+        measure: &Box<dyn Similarity>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(measure.get_name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Box<dyn Similarity>, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        from_name(&name).ok_or_else(|| D::Error::custom(format!("Unknown similarity {}", name)))
+    }
+}
+
+/// Get the measure named by `$SIMILARITY`, `Pearson` if not set.
+pub fn from_env() -> Box<dyn Similarity> {
+    match env::var(config::SIMILARITY) {
+        Ok(name) => from_name(&name).unwrap_or_else(|| {
+            warn!(
+                "Unknown similarity {}, using pearson. Available: {:?}, optionally prefixed by shrunk_",
+                name, NAMES
+            );
+            Box::new(Pearson)
+        }),
+        Err(_) => Box::new(Pearson),
+    }
+}
+
+/// Get similarity matrix of size m x m from matrix n x m, i.e.
+/// items are columns.
+pub trait SimilarityMatrix {
+    /// Get a vector over all columns(items)
+    fn get_avg_and_non_zero_idx(&self) -> (Vec<f64>, Vec<Vec<usize>>);
+
+    /// Get similarity matrix of size m x m from matrix of size n x m
+    fn get_similarity_matrix(&self, measure: &dyn Similarity) -> DMatrix<f64>;
+
+    /// Same as `get_similarity_matrix` but only the `k` most similar
+    /// neighbours of each item are kept, so the result stays sparse.
+    fn get_top_k_similarity(&self, measure: &dyn Similarity, k: usize) -> SparseMatrix;
+
+    /// Same as `get_similarity_matrix` but only similarities of at least
+    /// `min_similarity` are kept, so the result stays sparse.
+    fn get_similarity_above(&self, measure: &dyn Similarity, min_similarity: f64) -> SparseMatrix;
+}
+
+impl SimilarityMatrix for DMatrix<f64> {
+    fn get_avg_and_non_zero_idx(&self) -> (Vec<f64>, Vec<Vec<usize>>) {
+        let (n, m) = self.shape();
+        let mut non_zero_idx = vec![vec![]; m];
+        let mut avg = vec![0f64; m];
+
+        for j in 0..m {
+            let curr = &mut non_zero_idx[j];
+            let col_j = self.column(j);
+            let mut sum = 0f64;
+            for i in 0..n {
+                if col_j[i] != 0f64 {
+                    curr.push(i);
+                    sum += col_j[i];
+                }
+            }
+            avg[j] = if !curr.is_empty() {
+                sum / curr.len() as f64
+            } else {
+                0f64
+            };
+        }
+        (avg, non_zero_idx)
+    }
+    fn get_similarity_matrix(&self, measure: &dyn Similarity) -> DMatrix<f64> {
+        SparseMatrix::from_dense(self).get_similarity_matrix(measure)
+    }
+    fn get_top_k_similarity(&self, measure: &dyn Similarity, k: usize) -> SparseMatrix {
+        SparseMatrix::from_dense(self).get_top_k_similarity(measure, k)
+    }
+    fn get_similarity_above(&self, measure: &dyn Similarity, min_similarity: f64) -> SparseMatrix {
+        SparseMatrix::from_dense(self).get_similarity_above(measure, min_similarity)
+    }
+}
+
+impl SimilarityMatrix for SparseMatrix {
+    fn get_avg_and_non_zero_idx(&self) -> (Vec<f64>, Vec<Vec<usize>>) {
+        let by_item = self.transpose();
+        (0..by_item.nrows)
+            .map(|j| {
+                let (idx, vals) = by_item.row(j);
+                let avg = if !idx.is_empty() {
+                    vals.iter().sum::<f64>() / idx.len() as f64
+                } else {
+                    0f64
+                };
+                (avg, idx.to_vec())
+            })
+            .unzip()
+    }
+    fn get_similarity_matrix(&self, measure: &dyn Similarity) -> DMatrix<f64> {
+        let m = self.ncols;
+        let mut similarity = DMatrix::zeros(m, m);
+        neighbours(self, measure, None, f64::NEG_INFINITY)
+            .into_iter()
+            .enumerate()
+            .for_each(|(i, row)| row.into_iter().for_each(|(j, s)| similarity[(i, j)] = s));
+        similarity
+    }
+    fn get_top_k_similarity(&self, measure: &dyn Similarity, k: usize) -> SparseMatrix {
+        to_sparse(neighbours(self, measure, Some(k), f64::NEG_INFINITY))
+    }
+    fn get_similarity_above(&self, measure: &dyn Similarity, min_similarity: f64) -> SparseMatrix {
+        to_sparse(neighbours(self, measure, None, min_similarity))
+    }
+}
+
+fn to_sparse(neighbours: Vec<Vec<(usize, f64)>>) -> SparseMatrix {
+    let m = neighbours.len();
+    let triplets = neighbours
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, row)| row.into_iter().map(move |(j, s)| (i, j, s)))
+        .collect();
+    SparseMatrix::from_triplets(m, m, triplets)
+}
+
+/// Compute, for every item (column) of the n x m `matrix`, its similarity
+/// against every other item it shares a customer with.
+///
+/// Instead of merge-joining every pair of items, which is O(m^2) no matter
+/// how sparse the data is, item `i` walks its customers and, through
+/// each customer's row, accumulates into every item `j` rated by the same
+/// customer. Items are processed in blocks in parallel. Only similarities
+/// of at least `min_similarity` are returned, and with `top_k` only the `k`
+/// largest of them.
+fn neighbours(
+    matrix: &SparseMatrix,
+    measure: &dyn Similarity,
+    top_k: Option<usize>,
+    min_similarity: f64,
+) -> Vec<Vec<(usize, f64)>> {
+    let m = matrix.ncols;
+    let by_user = measure.transform(matrix);
+    let by_item = by_user.transpose();
+    let stats: Vec<ItemStats> = (0..m)
+        .map(|j| {
+            let vals = by_item.row(j).1;
+            ItemStats {
+                count: vals.len() as u32,
+                norm: vals.iter().map(|v| v.powi(2)).sum::<f64>().sqrt(),
+            }
+        })
+        .collect();
+
+    (0..m)
+        .into_par_iter()
+        .with_min_len(SIMILARITY_BLOCK_SIZE)
+        .map_init(
+            || (vec![CoRatings::default(); m], vec![false; m], Vec::new()),
+            |(acc, seen, touched), i| {
+                let (users, ratings) = by_item.row(i);
+                for (&u, &x) in users.iter().zip(ratings) {
+                    let (items, ys) = by_user.row(u);
+                    for (&j, &y) in items.iter().zip(ys) {
+                        if !seen[j] {
+                            seen[j] = true;
+                            touched.push(j);
+                        }
+                        let co = &mut acc[j];
+                        co.count += 1;
+                        co.xy += x * y;
+                        co.xx += x * x;
+                        co.yy += y * y;
+                    }
+                }
+                let mut row: Vec<(usize, f64)> = touched
+                    .drain(..)
+                    .filter_map(|j| {
+                        let co = std::mem::take(&mut acc[j]);
+                        seen[j] = false;
+                        if j == i {
+                            return None;
+                        }
+                        let s = measure.similarity(&co, &stats[i], &stats[j]);
+                        if s >= min_similarity {
+                            Some((j, s))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                if let Some(k) = top_k {
+                    if row.len() > k {
+                        row.select_nth_unstable_by(k, |a, b| b.1.partial_cmp(&a.1).unwrap());
+                        row.truncate(k);
+                    }
+                }
+                row.sort_unstable_by_key(|&(j, _)| j);
+                row
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_pearson_cosine_similarity() {
+        let matrix = DMatrix::<f64>::from_row_slice(
+            3,
+            6,
+            &[
+                1f64, 2f64, 0f64, 0f64, 1f64, 0f64, 0f64, 1f64, 2f64, 0f64, 3f64, 0f64, 2f64, 2f64,
+                4f64, 0f64, 2f64, 0f64,
+            ],
+        );
+        assert!(matrix.shape() == (3, 6));
+        let (avg, non_zero_idx) = matrix.get_avg_and_non_zero_idx();
+        assert!(avg == vec![1.5f64, 5f64 / 3f64, 3f64, 0f64, 2f64, 0f64]);
+        assert!(
+            non_zero_idx
+                == vec![
+                    vec![0, 2],
+                    vec![0, 1, 2],
+                    vec![1, 2],
+                    vec![],
+                    vec![0, 1, 2],
+                    vec![],
+                ]
+        );
+        let similarity = matrix.get_similarity_matrix(&Pearson);
+        assert!(similarity == similarity.transpose());
+        assert!(similarity[(0, 0)] == 0f64);
+        assert!(similarity[(5, 3)] == 0f64);
+        assert!(similarity[(0, 1)] == 0f64);
+        assert!(similarity[(3, 0)] == 0f64);
+        assert!(similarity[(4, 0)] - 0.5f64 < 1e-10);
+        assert!((similarity[(1, 4)] - -f64::sqrt(3f64) / 2f64).abs() < 1e-10);
+    }
+
+    #[test]
+    fn test_top_k_similarity() {
+        let matrix = DMatrix::<f64>::from_row_slice(
+            4,
+            4,
+            &[
+                2f64, 2f64, 5f64, 4f64, 3f64, 1f64, 4f64, 0f64, 5f64, 2f64, 1f64, 2f64, 0f64, 4f64,
+                2f64, 4f64,
+            ],
+        );
+        let full = matrix.get_similarity_matrix(&Pearson);
+        assert!(full == SparseMatrix::from_dense(&matrix).get_similarity_matrix(&Pearson));
+        let top_2 = matrix.get_top_k_similarity(&Pearson, 2);
+        assert!(top_2.shape() == (4, 4));
+        for i in 0..4 {
+            let (idx, sim) = top_2.row(i);
+            assert!(idx.len() == 2);
+            assert!(!idx.contains(&i));
+            // The dropped neighbour is never more similar than the kept ones.
+            let dropped = (0..4).find(|j| *j != i && !idx.contains(j)).unwrap();
+            sim.iter().zip(idx).for_each(|(&s, &j)| {
+                assert!((s - full[(i, j)]).abs() < 1e-10);
+                assert!(s >= full[(i, dropped)]);
+            });
+        }
+    }
+
+    #[test]
+    fn test_other_similarities() {
+        // Item 0 and 1 share customer 0 and 1, item 2 only has customer 2.
+        let matrix = DMatrix::<f64>::from_row_slice(
+            3,
+            3,
+            &[1f64, 3f64, 0f64, 2f64, 2f64, 0f64, 4f64, 0f64, 5f64],
+        );
+
+        let jaccard = matrix.get_similarity_matrix(&Jaccard);
+        assert!((jaccard[(0, 1)] - 2f64 / 3f64).abs() < 1e-10);
+        assert!((jaccard[(0, 2)] - 1f64 / 3f64).abs() < 1e-10);
+        assert!(jaccard[(1, 2)] == 0f64);
+
+        let cos = matrix.get_similarity_matrix(&Cosine);
+        assert!((cos[(0, 1)] - 7f64 / (f64::sqrt(21f64) * f64::sqrt(13f64))).abs() < 1e-10);
+
+        // Differences on the co-rated customers are 2 and 0.
+        let msd = matrix.get_similarity_matrix(&MeanSquaredDifference);
+        assert!((msd[(0, 1)] - 1f64 / 3f64).abs() < 1e-10);
+
+        // Customer means are 2, 2 and 4.5, so customer 1 contributes nothing.
+        let adjusted = matrix.get_similarity_matrix(&AdjustedCosine);
+        assert!((adjusted[(0, 1)] - -1f64 / f64::sqrt(1.25f64)).abs() < 1e-10);
+
+        let shrunk = from_name("shrunk_jaccard").unwrap();
+        assert!(shrunk.get_name() == "shrunk_jaccard");
+        let shrunk = matrix.get_similarity_matrix(shrunk.as_ref());
+        assert!((shrunk[(0, 1)] - 2f64 / 102f64 * 2f64 / 3f64).abs() < 1e-10);
+        assert!(from_name("euclidean").is_none());
+    }
+}
@@ -0,0 +1,373 @@
+//! Every tracked run of a command gets a directory `<runs>/<run id>/`,
+//! holding `run.json`, see `RunRecord`, and a copy of every file the run
+//! wrote, so that results are not lost when the next run overwrites them.
+use elapsed::ElapsedDuration;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::data::{format_day, Data};
+use crate::models::{cold_start::FallbackChain, experiment::ModelConfig, Model};
+
+/// Name of the record in every run directory.
+const RUN_RECORD: &str = "run.json";
+
+/// What a run did, as written to `run.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// `YYYY-MM-DDTHH-MM-SS-<pid>`, also the name of the run directory.
+    pub id: String,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`.
+    pub started: String,
+    /// Command line arguments.
+    pub command: Vec<String>,
+    /// `HEAD` of the working directory, if it is a git repository.
+    pub git_commit: Option<String>,
+    /// Whether tracked files differ from `git_commit`.
+    pub git_dirty: bool,
+    pub data_path: Option<PathBuf>,
+    /// `Data::fingerprint`, in hex.
+    pub fingerprint: Option<String>,
+    /// Fallbacks of cold customers and movies, see `--cold-start`.
+    pub cold_start: Option<String>,
+    pub models: Vec<ModelRecord>,
+    /// Files of the run that belong to no model in particular.
+    pub artifacts: Vec<String>,
+    pub seconds: f64,
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+}
+
+/// What a run did with one model configuration.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ModelRecord {
+    pub name: String,
+    pub model: String,
+    /// Every hyperparameter, see `Model::config`.
+    pub params: Map<String, Value>,
+    /// E.g. the cross validation `rmse`.
+    pub metrics: BTreeMap<String, f64>,
+    /// Seconds spent by stage, `init`, `train`, `load`, `update` or `predict`.
+    pub timing: BTreeMap<String, f64>,
+    /// See `Model::training_curve`.
+    pub curve: Vec<f64>,
+    /// Copies in the run directory.
+    pub artifacts: Vec<String>,
+}
+
+/// Records a run, or nothing if tracking is off.
+pub struct Tracker {
+    run: Option<(PathBuf, RunRecord, Instant)>,
+}
+
+pub fn seconds(elapsed: &ElapsedDuration) -> f64 {
+    elapsed.duration().as_secs_f64()
+}
+
+/// `git args...` in the working directory, `None` if git cannot be run.
+fn git(args: &[&str]) -> Option<process::Output> {
+    process::Command::new("git").args(args).output().ok()
+}
+
+impl Tracker {
+    pub fn disabled() -> Self {
+        Self { run: None }
+    }
+
+    /// Start recording a run in a new directory of `root`.
+    pub fn start(root: &Path) -> Result<Self, Box<dyn Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let (day, time) = (now / 86_400, now % 86_400);
+        let (hours, minutes, secs) = (time / 3600, time / 60 % 60, time % 60);
+        let date = format_day(day as u16);
+        let id = format!(
+            "{}T{:02}-{:02}-{:02}-{}",
+            date,
+            hours,
+            minutes,
+            secs,
+            process::id()
+        );
+        let dir = root.join(&id);
+        fs::create_dir_all(&dir)?;
+        let git_commit = git(&["rev-parse", "HEAD"])
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        // `git diff --quiet` exits with 1 if there are differences.
+        let git_dirty = git_commit.is_some()
+            && git(&["diff", "--quiet", "HEAD"])
+                .is_some_and(|output| output.status.code() == Some(1));
+        let record = RunRecord {
+            id,
+            started: format!("{} {:02}:{:02}:{:02}", date, hours, minutes, secs),
+            command: env::args().skip(1).collect(),
+            git_commit,
+            git_dirty,
+            ..Default::default()
+        };
+        info!("Recording run {} in {:?}", record.id, dir);
+        Ok(Self {
+            run: Some((dir, record, Instant::now())),
+        })
+    }
+
+    pub fn data(&mut self, path: &Path, data: &Data) {
+        if let Some((_, record, _)) = &mut self.run {
+            record.data_path = Some(path.to_path_buf());
+            record.fingerprint = Some(format!("{:016x}", data.fingerprint()));
+        }
+    }
+
+    pub fn cold_start(&mut self, chain: &FallbackChain) {
+        if let Some((_, record, _)) = &mut self.run {
+            record.cold_start = Some(chain.to_string());
+        }
+    }
+
+    /// The record of `config`, added on first use.
+    fn model(&mut self, config: &ModelConfig) -> Option<&mut ModelRecord> {
+        let (_, record, _) = self.run.as_mut()?;
+        let i = match record.models.iter().position(|m| m.name == config.name) {
+            Some(i) => i,
+            None => {
+                record.models.push(ModelRecord {
+                    name: config.name.clone(),
+                    model: config.model.clone(),
+                    params: config.params.clone(),
+                    ..Default::default()
+                });
+                record.models.len() - 1
+            }
+        };
+        Some(&mut record.models[i])
+    }
+
+    /// The effective hyperparameters and training curve of `model`.
+    pub fn trained(&mut self, config: &ModelConfig, model: &dyn Model) {
+        if let Some(record) = self.model(config) {
+            record.params = model.config();
+            record.curve = model.training_curve().to_vec();
+        }
+    }
+
+    pub fn timing(&mut self, config: &ModelConfig, stage: &str, seconds: f64) {
+        if let Some(record) = self.model(config) {
+            record.timing.insert(stage.to_string(), seconds);
+        }
+    }
+
+    pub fn metric(&mut self, config: &ModelConfig, metric: &str, value: f64) {
+        if let Some(record) = self.model(config) {
+            record.metrics.insert(metric.to_string(), value);
+        }
+    }
+
+    /// Copy `path` into the run directory, as a result of `config` if any.
+    pub fn artifact(
+        &mut self,
+        config: Option<&ModelConfig>,
+        path: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let dir = match &self.run {
+            Some((dir, _, _)) => dir.clone(),
+            None => return Ok(()),
+        };
+        let name = path
+            .file_name()
+            .ok_or_else(|| format!("{:?} is not a file", path))?
+            .to_string_lossy()
+            .to_string();
+        fs::copy(path, dir.join(&name))?;
+        let artifacts = match config {
+            Some(config) => &mut self.model(config).unwrap().artifacts,
+            None => &mut self.run.as_mut().unwrap().1.artifacts,
+        };
+        if !artifacts.contains(&name) {
+            artifacts.push(name);
+        }
+        Ok(())
+    }
+
+    /// Write `run.json`, with the error of `result` if any.
+    pub fn finish(self, result: &Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+        if let Some((dir, mut record, started)) = self.run {
+            record.seconds = started.elapsed().as_secs_f64();
+            record.error = result.as_ref().err().map(|err| err.to_string());
+            let path = dir.join(RUN_RECORD);
+            fs::write(&path, serde_json::to_string_pretty(&record)?)?;
+            info!("Run {} recorded in {:?}", record.id, path);
+        }
+        Ok(())
+    }
+}
+
+/// Every run recorded in `root`, oldest first.
+pub fn load_runs(root: &Path) -> Result<Vec<RunRecord>, Box<dyn Error>> {
+    let mut runs = vec![];
+    for entry in fs::read_dir(root).map_err(|err| format!("{:?}: {}", root, err))? {
+        let path = entry?.path().join(RUN_RECORD);
+        if !path.is_file() {
+            continue;
+        }
+        match fs::read_to_string(&path)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|text| Ok(serde_json::from_str::<RunRecord>(&text)?))
+        {
+            Ok(run) => runs.push(run),
+            Err(err) => warn!("Skipping {:?}: {}", path, err),
+        }
+    }
+    // Ids start with the time the run started.
+    runs.sort_by(|a, b| a.started.cmp(&b.started).then(a.id.cmp(&b.id)));
+    Ok(runs)
+}
+
+/// One line per model of every run, those named `model` only if any.
+pub fn compare(runs: &[RunRecord], model: Option<&str>) -> Vec<Vec<String>> {
+    let mut rows = vec![vec![
+        "run",
+        "commit",
+        "data",
+        "cold start",
+        "name",
+        "model",
+        "rmse",
+        "train s",
+        "params",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()];
+    for run in runs {
+        let commit = run.git_commit.as_ref().map_or_else(
+            || "-".to_string(),
+            |commit| {
+                let short = commit.get(..7).unwrap_or(commit);
+                format!("{}{}", short, if run.git_dirty { "+" } else { "" })
+            },
+        );
+        let data = run
+            .fingerprint
+            .as_ref()
+            .map_or("-", |f| f.get(..8).unwrap_or(f));
+        let cold_start = run.cold_start.as_deref().unwrap_or("-");
+        if run.models.is_empty() && model.is_none() {
+            let status = run.error.as_ref().map_or("-", |_| "failed");
+            rows.push(vec![
+                run.id.clone(),
+                commit.clone(),
+                data.to_string(),
+                cold_start.to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                status.to_string(),
+                "-".to_string(),
+                "".to_string(),
+            ]);
+        }
+        for record in &run.models {
+            if model.is_some_and(|m| m != record.name && m != record.model) {
+                continue;
+            }
+            let params = record
+                .params
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(" ");
+            rows.push(vec![
+                run.id.clone(),
+                commit.clone(),
+                data.to_string(),
+                cold_start.to_string(),
+                record.name.clone(),
+                record.model.clone(),
+                record
+                    .metrics
+                    .get("rmse")
+                    .map_or_else(|| "-".to_string(), |rmse| format!("{:.4}", rmse)),
+                record
+                    .timing
+                    .get("train")
+                    .map_or_else(|| "-".to_string(), |s| format!("{:.1}", s)),
+                params,
+            ]);
+        }
+    }
+    rows
+}
+
+/// `rows` as left aligned columns.
+pub fn format_table(rows: &[Vec<String>]) -> String {
+    let num_columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..num_columns)
+        .map(|j| {
+            rows.iter()
+                .map(|row| row.get(j).map_or(0, |cell| cell.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    rows.iter()
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            format!("{}\n", line.trim_end())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_track_and_compare() {
+        let root = env::temp_dir().join(format!("tracking_test_{}", process::id()));
+        let mut tracker = Tracker::start(&root).unwrap();
+        tracker.cold_start(&"movie_mean".parse().unwrap());
+        let config = ModelConfig::default_for("PureSvd");
+        tracker.metric(&config, "rmse", 0.95);
+        tracker.timing(&config, "train", 12.5);
+        let artifact = root.join("PureSvd.txt");
+        fs::write(&artifact, "3.5\n").unwrap();
+        tracker.artifact(Some(&config), &artifact).unwrap();
+        tracker.finish(&Ok(())).unwrap();
+        let mut failed = Tracker::start(&root.join("other")).unwrap();
+        failed.timing(&config, "train", 1f64);
+        failed.finish(&Err("boom".into())).unwrap();
+
+        let runs = load_runs(&root).unwrap();
+        assert!(runs.len() == 1);
+        let run = &runs[0];
+        assert!(run.error.is_none() && run.command == env::args().skip(1).collect::<Vec<_>>());
+        let record = &run.models[0];
+        assert!(record.name == "PureSvd" && record.metrics["rmse"] == 0.95);
+        assert!(record.artifacts == vec!["PureSvd.txt"]);
+        assert!(root.join(&run.id).join("PureSvd.txt").is_file());
+
+        let rows = compare(&runs, Some("PureSvd"));
+        assert!(rows.len() == 2);
+        assert!(rows[1][3] == "movie_mean");
+        assert!(rows[1][6] == "0.9500" && rows[1][7] == "12.5");
+        assert!(compare(&runs, Some("ItemKnn")).len() == 1);
+        let table = format_table(&rows);
+        assert!(table.lines().count() == 2 && table.starts_with("run "));
+
+        let failed = &load_runs(&root.join("other")).unwrap()[0];
+        assert!(failed.error.as_deref() == Some("boom"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}